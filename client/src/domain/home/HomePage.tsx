@@ -36,7 +36,7 @@ const HomePage_homePage = graphql`
   )
   @refetchable(queryName: "HomePageRefetchQuery") {
     __id
-    relevantPosts(before: $cursor, last: $count)
+    relevantPosts(first: $count, after: $cursor)
       @connection(key: "HomePage_relevantPosts") {
       edges {
         node {
@@ -50,7 +50,7 @@ const HomePage_homePage = graphql`
 function _HomePage() {
   const { viewer } = usePreloadedRoute<HomePageQuery>(homePageQuery);
 
-  const { data, loadPrevious } = usePaginationFragment<
+  const { data, loadNext } = usePaginationFragment<
     HomePageRefetchQuery,
     HomePage_viewer$key
   >(HomePage_homePage, viewer);
@@ -81,7 +81,7 @@ function _HomePage() {
     <Stack divider={<Divider />} spacing={4}>
       <PostInput fragmentKey={viewer} />
       <PostList fragmentKey={posts} />
-      <Button onClick={() => loadPrevious(5)}>More!</Button>
+      <Button onClick={() => loadNext(5)}>More!</Button>
     </Stack>
   );
 }
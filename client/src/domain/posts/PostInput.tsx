@@ -10,10 +10,14 @@ import { ConnectionHandler, graphql } from "relay-runtime";
 
 const PostInputMutation = graphql`
   mutation PostInputMutation($content: String!, $connections: [ID!]!) {
-    createPost(input: { content: $content })
-      @prependEdge(connections: $connections) {
-      node {
-        ...PostList_post
+    createPost(input: { content: $content }) {
+      edge @prependEdge(connections: $connections) {
+        node {
+          ...PostList_post
+        }
+      }
+      userErrors {
+        message
       }
     }
   }
@@ -6,7 +6,7 @@ import { PostList } from "$domain/posts/PostList";
 import { usePaginationFragment, useSubscription } from "react-relay";
 import { UserPageRefetchQuery } from "$schemas/UserPageRefetchQuery.graphql";
 import { UserPage_user$key } from "$schemas/UserPage_user.graphql";
-import { Button, Divider, Stack } from "@mui/joy";
+import { Button, Divider, Stack, Typography } from "@mui/joy";
 
 export const userPageQuery = graphql`
   query UserPageQuery($id: ID!) {
@@ -34,7 +34,7 @@ const UserPage_user = graphql`
   )
   @refetchable(queryName: "UserPageRefetchQuery") {
     id
-    posts(before: $cursor, last: $count)
+    posts(first: $count, after: $cursor)
       @connection(key: "UserPage_user_posts") {
       edges {
         node {
@@ -48,10 +48,22 @@ const UserPage_user = graphql`
 function _UserPage() {
   const { user } = usePreloadedRoute<UserPageQuery>(userPageQuery);
 
-  const { data, loadPrevious } = usePaginationFragment<
+  if (!user) {
+    return <Typography>This user does not exist.</Typography>;
+  }
+
+  return <UserPosts fragmentKey={user} />;
+}
+
+type UserPostsProps = {
+  fragmentKey: UserPage_user$key;
+};
+
+function UserPosts(props: UserPostsProps) {
+  const { data, loadNext } = usePaginationFragment<
     UserPageRefetchQuery,
     UserPage_user$key
-  >(UserPage_user, user);
+  >(UserPage_user, props.fragmentKey);
 
   useSubscription(
     useMemo(
@@ -76,7 +88,7 @@ function _UserPage() {
   return (
     <Stack divider={<Divider />} spacing={4}>
       <PostList fragmentKey={posts} />
-      <Button onClick={() => loadPrevious(5)}>More!</Button>
+      <Button onClick={() => loadNext(5)}>More!</Button>
     </Stack>
   );
 }
@@ -7,11 +7,31 @@ import {
   SubscribeFunction,
   Observable,
   GraphQLResponse,
+  RequestParameters,
+  Variables,
 } from "relay-runtime";
 import { env } from "./env";
 import { createClient } from "graphql-ws";
 
-const fetchFn: FetchFunction = async (request, variables) => {
+// Relay compiles operations to ids, which are the sha256 hashes of their text
+const persistedQuery = (request: RequestParameters) => ({
+  persistedQuery: { version: 1, sha256Hash: request.id },
+});
+
+type ErrorsWithCode = readonly { extensions?: { code?: unknown } }[];
+
+// The server has not seen the hash yet, or forgot it, and needs the full query once
+const isPersistedQueryNotFound = (errors: unknown) =>
+  Array.isArray(errors) &&
+  (errors as ErrorsWithCode).some(
+    (error) => error.extensions?.code === "PERSISTED_QUERY_NOT_FOUND"
+  );
+
+const post = async (
+  request: RequestParameters,
+  variables: Variables,
+  withText: boolean
+) => {
   const resp = await fetch(env.serverUrl, {
     method: "POST",
     headers: {
@@ -21,7 +41,8 @@ const fetchFn: FetchFunction = async (request, variables) => {
       // <-- Additional headers like 'Authorization' would go here
     },
     body: JSON.stringify({
-      query: request.text, // <-- The GraphQL document composed by Relay
+      query: withText ? request.text : undefined,
+      extensions: persistedQuery(request), // <-- The server knows the document by its hash
       variables,
     }),
   });
@@ -29,25 +50,71 @@ const fetchFn: FetchFunction = async (request, variables) => {
   return (await resp.json()) as GraphQLResponse;
 };
 
+const fetchFn: FetchFunction = async (request, variables) => {
+  const response = await post(request, variables, false);
+
+  if (
+    request.text &&
+    "errors" in response &&
+    isPersistedQueryNotFound(response.errors)
+  ) {
+    return post(request, variables, true);
+  }
+
+  return response;
+};
+
 const subscribeClient = createClient({
   url: env.serverWsUrl,
 });
 
 const subscribeFn: SubscribeFunction = (request, variables) => {
   return Observable.create((sink) => {
-    if (!request.text) {
-      return sink.error(new Error("Operation text cannot be empty"));
+    if (!request.id) {
+      return sink.error(new Error("Operation id cannot be empty"));
     }
 
-    return subscribeClient.subscribe(
-      {
-        operationName: request.name,
-        query: request.text,
-        variables,
-      },
-      // @ts-expect-error "null" and "undefined" will be handled the same. I hope...
-      sink
-    );
+    // The server answers a hash it does not know with a result carrying the error
+    const subscribe = (withText: boolean): (() => void) => {
+      let retried = false;
+
+      const dispose = subscribeClient.subscribe(
+        {
+          operationName: request.name,
+          query: withText ? request.text ?? "" : "",
+          variables,
+          extensions: persistedQuery(request),
+        },
+        {
+          next: (value) => {
+            if (
+              !withText &&
+              request.text &&
+              isPersistedQueryNotFound(value.errors)
+            ) {
+              retried = true;
+              dispose();
+              unsubscribe = subscribe(true);
+              return;
+            }
+
+            sink.next(value as GraphQLResponse);
+          },
+          error: (error) => {
+            if (!retried) sink.error(error as Error);
+          },
+          complete: () => {
+            if (!retried) sink.complete();
+          },
+        }
+      );
+
+      return dispose;
+    };
+
+    let unsubscribe = subscribe(false);
+
+    return () => unsubscribe();
   });
 };
 
@@ -0,0 +1,21 @@
+CREATE TYPE post_state AS ENUM ('draft', 'scheduled', 'published');
+
+ALTER TABLE post
+    ADD COLUMN IF NOT EXISTS state          post_state                  NOT NULL DEFAULT 'published',
+    ADD COLUMN IF NOT EXISTS scheduled_for  TIMESTAMP WITH TIME ZONE,
+    ADD CONSTRAINT scheduled_has_time CHECK (state <> 'scheduled' OR scheduled_for IS NOT NULL);
+
+CREATE INDEX IF NOT EXISTS index_post_scheduled ON post (scheduled_for) WHERE state = 'scheduled';
+
+-- Drafts and scheduled posts stay silent until they get published
+CREATE OR REPLACE FUNCTION post_notification() RETURNS trigger AS $post_notification$
+    DECLARE
+        message TEXT;
+    BEGIN
+        IF NEW.state = 'published' THEN
+            message := format('%s:%s', NEW.post_id, NEW.author);
+            PERFORM pg_notify('post_notification', message);
+        END IF;
+        RETURN NEW;
+    END;
+$post_notification$ LANGUAGE plpgsql;
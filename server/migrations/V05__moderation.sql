@@ -0,0 +1,40 @@
+CREATE TYPE app_role AS ENUM ('user', 'moderator');
+CREATE TYPE node_type AS ENUM ('app_user', 'post', 'comment');
+CREATE TYPE moderation_action AS ENUM ('dismiss', 'hide_content', 'suspend_user');
+
+ALTER TABLE app_user
+    ADD COLUMN IF NOT EXISTS role           app_role                    NOT NULL DEFAULT 'user',
+    ADD COLUMN IF NOT EXISTS suspended      BOOLEAN                     NOT NULL DEFAULT false;
+
+ALTER TABLE post
+    ADD COLUMN IF NOT EXISTS hidden         BOOLEAN                     NOT NULL DEFAULT false;
+
+ALTER TABLE comment
+    ADD COLUMN IF NOT EXISTS hidden         BOOLEAN                     NOT NULL DEFAULT false;
+
+CREATE TABLE IF NOT EXISTS report (
+    report_id       SERIAL                      PRIMARY KEY,
+    reporter        INTEGER                     NOT NULL REFERENCES app_user (user_id),
+    target_type     node_type                   NOT NULL,
+    target_id       INTEGER                     NOT NULL,
+    reason          TEXT                        NOT NULL,
+    created_on      TIMESTAMP WITH TIME ZONE    NOT NULL,
+    resolution      moderation_action,
+    resolved_by     INTEGER                     REFERENCES app_user (user_id),
+    resolved_on     TIMESTAMP WITH TIME ZONE
+);
+
+-- One open report per reporter and target
+CREATE UNIQUE INDEX IF NOT EXISTS index_report_open
+    ON report (reporter, target_type, target_id) WHERE resolution IS NULL;
+CREATE INDEX IF NOT EXISTS index_report_queue ON report (created_on) WHERE resolution IS NULL;
+
+CREATE TABLE IF NOT EXISTS moderation_log (
+    log_id          SERIAL                      PRIMARY KEY,
+    moderator       INTEGER                     NOT NULL REFERENCES app_user (user_id),
+    report_id       INTEGER                     NOT NULL REFERENCES report (report_id),
+    action          moderation_action           NOT NULL,
+    target_type     node_type                   NOT NULL,
+    target_id       INTEGER                     NOT NULL,
+    created_on      TIMESTAMP WITH TIME ZONE    NOT NULL
+);
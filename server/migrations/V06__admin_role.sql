@@ -0,0 +1 @@
+ALTER TYPE app_role ADD VALUE IF NOT EXISTS 'admin';
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS persisted_query (
+    hash            TEXT                        PRIMARY KEY,
+    query           TEXT                        NOT NULL,
+    created_on      TIMESTAMP WITH TIME ZONE    NOT NULL
+);
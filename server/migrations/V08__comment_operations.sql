@@ -0,0 +1,20 @@
+-- Subscribers need to know what happened to a comment, deleted rows only have OLD
+CREATE OR REPLACE FUNCTION comment_notification() RETURNS trigger AS $comment_notification$
+    DECLARE
+        message TEXT;
+        changed comment;
+    BEGIN
+        IF TG_OP = 'DELETE' THEN
+            changed := OLD;
+        ELSE
+            changed := NEW;
+        END IF;
+
+        message := format(
+            '%s:%s:%s:%s',
+            changed.comment_id, changed.referenced_post, changed.author, lower(TG_OP)
+        );
+        PERFORM pg_notify('comment_notification', message);
+        RETURN NULL;
+    END;
+$comment_notification$ LANGUAGE plpgsql;
@@ -0,0 +1,21 @@
+-- Live feeds follow the friend graph, so they need to hear about its changes
+CREATE FUNCTION user_relation_notification() RETURNS trigger AS $user_relation_notification$
+    DECLARE
+        message TEXT;
+        changed user_relation;
+    BEGIN
+        IF TG_OP = 'DELETE' THEN
+            changed := OLD;
+        ELSE
+            changed := NEW;
+        END IF;
+
+        message := format('%s:%s:%s', changed.user_id_a, changed.user_id_b, lower(TG_OP));
+        PERFORM pg_notify('user_relation_notification', message);
+        RETURN NULL;
+    END;
+$user_relation_notification$ LANGUAGE plpgsql;
+
+CREATE TRIGGER user_relation_notification_trigger
+AFTER INSERT OR DELETE ON user_relation
+FOR EACH ROW EXECUTE FUNCTION user_relation_notification();
@@ -0,0 +1,81 @@
+-- Payloads are versioned JSON, so they can grow without breaking listeners.
+-- pg_notify fails above 8000 bytes, so oversized payloads only keep what routing needs.
+CREATE FUNCTION notify_json(channel TEXT, payload JSONB, id_only JSONB) RETURNS void AS $notify_json$
+    BEGIN
+        IF octet_length(payload::text) < 8000 THEN
+            PERFORM pg_notify(channel, payload::text);
+        ELSE
+            PERFORM pg_notify(channel, id_only::text);
+        END IF;
+    END;
+$notify_json$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION post_notification() RETURNS trigger AS $post_notification$
+    DECLARE
+        id_only JSONB;
+    BEGIN
+        IF NEW.state = 'published' THEN
+            id_only := jsonb_build_object(
+                'v', 1,
+                'op', lower(TG_OP),
+                'post_id', NEW.post_id,
+                'author_id', NEW.author
+            );
+            PERFORM notify_json(
+                'post_notification',
+                id_only || jsonb_build_object('hidden', NEW.hidden, 'created_on', NEW.created_on),
+                id_only
+            );
+        END IF;
+        RETURN NULL;
+    END;
+$post_notification$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION comment_notification() RETURNS trigger AS $comment_notification$
+    DECLARE
+        changed comment;
+        id_only JSONB;
+    BEGIN
+        IF TG_OP = 'DELETE' THEN
+            changed := OLD;
+        ELSE
+            changed := NEW;
+        END IF;
+
+        id_only := jsonb_build_object(
+            'v', 1,
+            'op', lower(TG_OP),
+            'comment_id', changed.comment_id,
+            'post_id', changed.referenced_post,
+            'author_id', changed.author
+        );
+        PERFORM notify_json(
+            'comment_notification',
+            id_only || jsonb_build_object('hidden', changed.hidden, 'created_on', changed.created_on),
+            id_only
+        );
+        RETURN NULL;
+    END;
+$comment_notification$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION user_relation_notification() RETURNS trigger AS $user_relation_notification$
+    DECLARE
+        changed user_relation;
+        id_only JSONB;
+    BEGIN
+        IF TG_OP = 'DELETE' THEN
+            changed := OLD;
+        ELSE
+            changed := NEW;
+        END IF;
+
+        id_only := jsonb_build_object(
+            'v', 1,
+            'op', lower(TG_OP),
+            'user_id_a', changed.user_id_a,
+            'user_id_b', changed.user_id_b
+        );
+        PERFORM notify_json('user_relation_notification', id_only, id_only);
+        RETURN NULL;
+    END;
+$user_relation_notification$ LANGUAGE plpgsql;
@@ -0,0 +1,6 @@
+-- Seed data used to be a migration, it has a history of its own now
+DELETE FROM refinery_schema_history WHERE version = 2 AND name = 'debug_data';
+
+-- The old seed restarted the comment sequence behind its own rows
+SELECT setval(pg_get_serial_sequence('comment', 'comment_id'), max(comment_id))
+    FROM comment HAVING max(comment_id) IS NOT NULL;
@@ -0,0 +1,6 @@
+-- Registered queries expire once unused for a while
+ALTER TABLE persisted_query ADD COLUMN IF NOT EXISTS used_on TIMESTAMP WITH TIME ZONE;
+UPDATE persisted_query SET used_on = created_on WHERE used_on IS NULL;
+ALTER TABLE persisted_query ALTER COLUMN used_on SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS index_persisted_query_used_on ON persisted_query (used_on);
@@ -0,0 +1,28 @@
+-- Feeds only show new posts, so publishing a draft or scheduled post is told apart from edits
+CREATE OR REPLACE FUNCTION post_notification() RETURNS trigger AS $post_notification$
+    DECLARE
+        operation TEXT;
+        id_only JSONB;
+    BEGIN
+        IF NEW.state = 'published' THEN
+            IF TG_OP = 'UPDATE' AND OLD.state <> 'published' THEN
+                operation := 'publish';
+            ELSE
+                operation := lower(TG_OP);
+            END IF;
+
+            id_only := jsonb_build_object(
+                'v', 1,
+                'op', operation,
+                'post_id', NEW.post_id,
+                'author_id', NEW.author
+            );
+            PERFORM notify_json(
+                'post_notification',
+                id_only || jsonb_build_object('hidden', NEW.hidden, 'created_on', NEW.created_on),
+                id_only
+            );
+        END IF;
+        RETURN NULL;
+    END;
+$post_notification$ LANGUAGE plpgsql;
@@ -0,0 +1,2 @@
+-- Friendships made while the notification listener was gone get replayed from here
+ALTER TABLE user_relation ADD COLUMN created_on TIMESTAMPTZ NOT NULL DEFAULT now();
@@ -0,0 +1,4 @@
+-- Passwords are hashed by the db, so they never get stored in the clear
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+ALTER TABLE app_user ADD COLUMN password_hash TEXT;
@@ -0,0 +1,60 @@
+-- Content for development and benchmarks, sized by settings of the session (see db::seed).
+-- The random seed is fixed, so the same sizes on an empty db give the same content.
+DO $seed$
+    DECLARE
+        users           INTEGER := current_setting('seed.users')::INTEGER;
+        posts           INTEGER := current_setting('seed.posts')::INTEGER;
+        comments        INTEGER := current_setting('seed.comments')::INTEGER;
+        friendships     INTEGER := current_setting('seed.friendships')::INTEGER;
+        -- Appended to whatever is there, so ids continue after it
+        first_user      INTEGER;
+        first_post      INTEGER;
+        first_comment   INTEGER;
+    BEGIN
+        PERFORM setseed(0.12345);
+
+        SELECT COALESCE(max(user_id), 0) + 1 INTO first_user FROM app_user;
+        SELECT COALESCE(max(post_id), 0) + 1 INTO first_post FROM post;
+        SELECT COALESCE(max(comment_id), 0) + 1 INTO first_comment FROM comment;
+
+        INSERT INTO app_user (user_id, first_name, last_name)
+        SELECT
+            first_user + i,
+            concat('First', first_user + i),
+            concat('Last', first_user + i)
+        FROM generate_series(0, users - 1) AS s(i);
+
+        INSERT INTO post (post_id, author, created_on, content)
+        SELECT
+            first_post + i,
+            first_user + floor(random() * users)::INTEGER,
+            current_timestamp(0) - (random() * (interval '2 years')),
+            concat(md5(random()::text), ' ', md5(random()::text), ' ', md5(random()::text))
+        FROM generate_series(0, posts - 1) AS s(i);
+
+        INSERT INTO comment (comment_id, referenced_post, author, created_on, content)
+        SELECT
+            first_comment + i,
+            first_post + floor(random() * posts)::INTEGER,
+            first_user + floor(random() * users)::INTEGER,
+            current_timestamp(0) - (random() * (interval '2 years')),
+            md5(random()::text)
+        FROM generate_series(0, comments - 1) AS s(i);
+
+        -- Pairs from both halves of the users are ordered already, duplicates are dropped
+        INSERT INTO user_relation (user_id_a, user_id_b)
+        SELECT
+            first_user + floor(random() * (users / 2))::INTEGER,
+            first_user + users / 2 + floor(random() * (users - users / 2))::INTEGER
+        FROM generate_series(1, friendships)
+        ON CONFLICT DO NOTHING;
+
+        -- Explicit ids leave the sequences behind
+        PERFORM setval(pg_get_serial_sequence('app_user', 'user_id'), max(user_id))
+            FROM app_user HAVING max(user_id) IS NOT NULL;
+        PERFORM setval(pg_get_serial_sequence('post', 'post_id'), max(post_id))
+            FROM post HAVING max(post_id) IS NOT NULL;
+        PERFORM setval(pg_get_serial_sequence('comment', 'comment_id'), max(comment_id))
+            FROM comment HAVING max(comment_id) IS NOT NULL;
+    END;
+$seed$ LANGUAGE plpgsql;
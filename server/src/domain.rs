@@ -1,8 +1,12 @@
 pub mod app_user;
+pub mod auth;
 pub mod comment;
 pub mod db_id;
 mod errors;
 pub mod post;
 mod relay_meta;
+pub mod report;
 pub mod schema;
 pub mod viewer;
+
+pub use errors::GqlError;
@@ -1,7 +1,15 @@
 mod db;
 mod domain;
 mod graphql;
+mod loaders;
+#[cfg(test)]
+mod memory;
+mod store;
 
-pub use db::{AppUserLoader, FriendIdLoader};
-pub use domain::AppUser;
-pub use graphql::{AddFriendInput, AppUserInput};
+pub use domain::{AppUser, Role};
+pub use graphql::{
+    AddFriendInput, AddFriendPayload, AppUserInput, CreateUserPayload, SetUserRoleInput,
+    SetUserSuspendedInput,
+};
+pub use loaders::{AppUserLoader, FriendIdLoader};
+pub use store::{RelationStore, UserStore};
@@ -1,144 +1,178 @@
-use std::{collections::HashMap, sync::Arc};
-
-use async_graphql::dataloader::Loader;
+use async_trait::async_trait;
 use tokio_postgres::Row;
 use tracing::{instrument, Level};
 
 use crate::{
-    domain::db_id::DbId,
-    infrastructure::{db::Repo, DbError},
+    domain::{db_id::DbId, relay_meta::NodeId},
+    infrastructure::{
+        db::{Isolation, Repo},
+        DbError,
+    },
 };
 
-use super::domain::AppUser;
-
-pub struct AppUserLoader {
-    repo: Repo,
-}
-
-impl AppUserLoader {
-    pub fn new(repo: Repo) -> Self {
-        Self { repo }
-    }
-}
-
-impl Loader<DbId> for AppUserLoader {
-    type Value = AppUser;
-    type Error = Arc<DbError>;
+use super::{
+    domain::{AppUser, Role},
+    store::{RelationStore, UserStore},
+};
 
+#[async_trait]
+impl UserStore for Repo {
     #[instrument(skip(self), err)]
-    async fn load(&self, ids: &[DbId]) -> Result<HashMap<DbId, Self::Value>, Self::Error> {
-        self.repo
-            .query(
-                "SELECT * FROM app_user WHERE user_id = ANY ($1)",
-                &[&ids],
-                |rows| {
-                    rows.into_iter()
-                        .map(|row| {
-                            let user: AppUser = row.try_into()?;
-                            Ok::<_, DbError>((user.user_id, user))
-                        })
-                        .collect::<Result<HashMap<_, _>, _>>()
-                },
-            )
-            .await
-            .map_err(Arc::new)
+    async fn users(&self, user_ids: &[DbId]) -> Result<Vec<AppUser>, DbError> {
+        self.query(
+            "SELECT * FROM app_user WHERE user_id = ANY ($1)",
+            &[&user_ids],
+            |rows| rows.into_iter().map(|row| row.try_into()).collect(),
+        )
+        .await
     }
-}
 
-pub struct FriendIdLoader {
-    repo: Repo,
-}
+    #[instrument(skip(self), err)]
+    async fn save_user(
+        &self,
+        first_name: &str,
+        last_name: &str,
+        role: Role,
+    ) -> Result<AppUser, DbError> {
+        // A user that lost its role on the way would have more rights than intended
+        self.transaction(Isolation::RepeatableRead, |tx| async move {
+            let user: AppUser = tx
+                .query_one(
+                    r"
+                        INSERT INTO app_user (first_name, last_name)
+                        VALUES ($1, $2)
+                        RETURNING *
+                    ",
+                    &[&first_name, &last_name],
+                    |row| row.try_into(),
+                )
+                .await?;
 
-impl FriendIdLoader {
-    pub fn new(repo: Repo) -> Self {
-        Self { repo }
+            tx.set_role(&user.user_id, role)
+                .await?
+                .ok_or_else(|| DbError::Missing(format!("User {}", *user.user_id)))
+        })
+        .await
     }
-}
 
-impl Loader<DbId> for FriendIdLoader {
-    type Value = Vec<DbId>;
-    type Error = Arc<DbError>;
+    #[instrument(skip(self), err)]
+    async fn set_suspended(
+        &self,
+        user_id: &DbId,
+        suspended: bool,
+    ) -> Result<Option<AppUser>, DbError> {
+        self.query(
+            "UPDATE app_user SET suspended = $2 WHERE user_id = $1 RETURNING *",
+            &[user_id, &suspended],
+            |rows| rows.into_iter().next().map(AppUser::try_from).transpose(),
+        )
+        .await
+    }
 
     #[instrument(skip(self), err)]
-    async fn load(&self, ids: &[DbId]) -> Result<HashMap<DbId, Self::Value>, Self::Error> {
-        let relations: Vec<(DbId, DbId)> = self
-            .repo
-            .query(
-                r#"
-                    SELECT user_id_a, user_id_b
-                    FROM user_relation
-                    WHERE user_id_b = ANY($1)
-                    UNION
-                    SELECT user_id_a, user_id_b
-                    FROM user_relation
-                    WHERE user_id_a = ANY($1)
-                "#,
-                &[&ids],
-                |rows| {
-                    rows.into_iter()
-                        .map(|row| {
-                            let user_id_a = row.try_get(0).map_err(DbError::mapping)?;
-                            let user_id_b = row.try_get(1).map_err(DbError::mapping)?;
-                            Ok::<_, DbError>((user_id_a, user_id_b))
-                        })
-                        .collect()
-                },
-            )
-            .await?;
+    async fn set_role(&self, user_id: &DbId, role: Role) -> Result<Option<AppUser>, DbError> {
+        self.query(
+            "UPDATE app_user SET role = $2 WHERE user_id = $1 RETURNING *",
+            &[user_id, &role],
+            |rows| rows.into_iter().next().map(AppUser::try_from).transpose(),
+        )
+        .await
+    }
 
-        let result_map = ids
-            .iter()
-            .map(|id| {
-                let friends: Vec<DbId> = relations
-                    .iter()
-                    .filter_map(|rel| {
-                        if &rel.0 == id {
-                            Some(rel.1)
-                        } else if &rel.1 == id {
-                            Some(rel.0)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
+    #[instrument(skip(self, password), err)]
+    async fn set_password(&self, user_id: &DbId, password: &str) -> Result<bool, DbError> {
+        self.query(
+            r"
+                UPDATE app_user SET password_hash = crypt($2, gen_salt('bf'))
+                WHERE user_id = $1
+                RETURNING user_id
+            ",
+            &[user_id, &password],
+            |rows| Ok::<_, DbError>(!rows.is_empty()),
+        )
+        .await
+    }
 
-                (*id, friends)
-            })
-            .collect();
+    #[instrument(skip(self), err)]
+    async fn owner_of(&self, node: &NodeId) -> Result<Option<DbId>, DbError> {
+        let statement = match node {
+            NodeId::AppUser(_) => "SELECT user_id AS owner FROM app_user WHERE user_id = $1",
+            NodeId::Comment(_) => "SELECT author AS owner FROM comment WHERE comment_id = $1",
+            NodeId::Post(_) => "SELECT author AS owner FROM post WHERE post_id = $1",
+        };
 
-        Ok(result_map)
+        self.query(statement, &[&node.db_id()], |rows| {
+            rows.into_iter()
+                .next()
+                .map(|row| row.try_get("owner").map_err(DbError::mapping))
+                .transpose()
+        })
+        .await
     }
 }
 
-impl Repo {
+#[async_trait]
+impl RelationStore for Repo {
     #[instrument(skip(self), err)]
-    pub async fn save_user(&self, first_name: &str, last_name: &str) -> Result<AppUser, DbError> {
-        self.query_one(
-            r"
-                INSERT INTO app_user (first_name, last_name)
-                VALUES ($1, $2)
-                RETURNING *
-            ",
-            &[&first_name, &last_name],
-            |row| row.try_into(),
+    async fn relations_of(&self, user_ids: &[DbId]) -> Result<Vec<(DbId, DbId)>, DbError> {
+        self.query(
+            r#"
+                SELECT user_id_a, user_id_b
+                FROM user_relation
+                WHERE user_id_b = ANY($1)
+                UNION
+                SELECT user_id_a, user_id_b
+                FROM user_relation
+                WHERE user_id_a = ANY($1)
+            "#,
+            &[&user_ids],
+            |rows| {
+                rows.into_iter()
+                    .map(|row| {
+                        let user_id_a = row.try_get(0).map_err(DbError::mapping)?;
+                        let user_id_b = row.try_get(1).map_err(DbError::mapping)?;
+                        Ok::<_, DbError>((user_id_a, user_id_b))
+                    })
+                    .collect()
+            },
         )
         .await
     }
 
     #[instrument(skip(self), err)]
-    pub async fn add_friend(&self, user: &DbId, friend: &DbId) -> Result<(), DbError> {
-        let mut users = [user, friend];
+    async fn add_friend(&self, user: &DbId, friend: &DbId) -> Result<(), DbError> {
+        let mut users = [*user, *friend];
         users.sort_unstable();
 
-        self.execute(
-            r"
-                INSERT INTO user_relation (user_id_a, user_id_b)
-                VALUES ($1, $2)
-                ON CONFLICT ON CONSTRAINT user_relation_pkey
-                DO NOTHING
-            ",
-            &[&users[0], &users[1]],
-        )
+        // Keeps both users around until the relation is in
+        self.transaction(Isolation::ReadCommitted, |tx| async move {
+            let found: Vec<DbId> = tx
+                .query(
+                    "SELECT user_id FROM app_user WHERE user_id = ANY($1) FOR KEY SHARE",
+                    &[&users.as_slice()],
+                    |rows| {
+                        rows.into_iter()
+                            .map(|row| row.try_get("user_id").map_err(DbError::mapping))
+                            .collect()
+                    },
+                )
+                .await?;
+
+            if let Some(missing) = users.iter().find(|user_id| !found.contains(user_id)) {
+                return Err(DbError::Missing(format!("User {}", **missing)));
+            }
+
+            tx.execute(
+                r"
+                    INSERT INTO user_relation (user_id_a, user_id_b)
+                    VALUES ($1, $2)
+                    ON CONFLICT ON CONSTRAINT user_relation_pkey
+                    DO NOTHING
+                ",
+                &[&users[0], &users[1]],
+            )
+            .await
+        })
         .await
     }
 }
@@ -152,6 +186,220 @@ impl TryFrom<Row> for AppUser {
             user_id: value.try_get("user_id").map_err(DbError::mapping)?,
             first_name: value.try_get("first_name").map_err(DbError::mapping)?,
             last_name: value.try_get("last_name").map_err(DbError::mapping)?,
+            role: value.try_get("role").map_err(DbError::mapping)?,
+            suspended: value.try_get("suspended").map_err(DbError::mapping)?,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+    use serde_json::{json, Value};
+
+    use crate::{
+        domain::app_user::{
+            domain::SUFFIX,
+            store::{RelationStore, UserStore},
+            Role,
+        },
+        infrastructure::{
+            testing::{TestApp, TestDb},
+            DbError,
+        },
+    };
+
+    fn names(friends: &Value) -> Vec<&str> {
+        friends
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|friend| friend["firstName"].as_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn friendships_go_both_ways() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, alice_id) = app.user("Alice").await;
+        let (bob, bob_id) = app.user("Bob").await;
+        let (_, carol_id) = app.user("Carol").await;
+
+        // Stored as an ordered pair, so befriending back changes nothing
+        app.befriend(bob, &alice_id).await;
+        app.befriend(alice, &bob_id).await;
+        app.befriend(alice, &carol_id).await;
+
+        let query = format!(
+            r#"{{
+                alice: user(id: "{alice_id}") {{ friends {{ firstName }} }}
+                bob: user(id: "{bob_id}") {{ friends {{ firstName }} }}
+                carol: user(id: "{carol_id}") {{ friends(first: 0) {{ firstName }} }}
+            }}"#
+        );
+        let data = app.execute(alice, &query).await;
+
+        assert_eq!(vec!["Bob", "Carol"], names(&data["alice"]["friends"]));
+        assert_eq!(vec!["Alice"], names(&data["bob"]["friends"]));
+        assert_eq!(json!([]), data["carol"]["friends"]);
+
+        let nobody = URL_SAFE.encode(format!("{}{SUFFIX}", *alice + 1000));
+        let query = format!(
+            r#"mutation {{ addFriend(input: {{ friend: "{nobody}" }}) {{ userErrors {{ code }} }} }}"#
+        );
+        let data = app.execute(alice, &query).await;
+        assert_eq!(
+            json!([{ "code": "NOT_FOUND" }]),
+            data["addFriend"]["userErrors"]
+        );
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn friends_come_in_id_order() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, alice_id) = app.user("Alice").await;
+
+        let mut friends = Vec::new();
+        for name in ["A", "B", "C", "D", "E", "F", "G", "H"] {
+            friends.push((name, app.user(name).await.1));
+        }
+        for (_, friend_id) in friends.iter().rev() {
+            app.befriend(alice, friend_id).await;
+        }
+
+        let query =
+            format!(r#"{{ user(id: "{alice_id}") {{ friends(first: 5) {{ firstName }} }} }}"#);
+        let data = app.execute(alice, &query).await;
+
+        assert_eq!(
+            vec!["A", "B", "C", "D", "E"],
+            names(&data["user"]["friends"])
+        );
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn passwords_are_only_stored_hashed() {
+        let db = TestDb::new().await;
+        let user = db.repo.save_user("Ada", "Test", Role::User).await.unwrap();
+
+        assert!(db.repo.set_password(&user.user_id, "secret").await.unwrap());
+
+        let stored_hashed: bool = db
+            .repo
+            .query_one(
+                r"
+                    SELECT password_hash <> $2 AND password_hash = crypt($2, password_hash)
+                    FROM app_user WHERE user_id = $1
+                ",
+                &[&user.user_id, &"secret"],
+                |row| row.try_get(0).map_err(DbError::mapping),
+            )
+            .await
+            .unwrap();
+        assert!(stored_hashed);
+
+        let nobody = (*user.user_id + 1000).into();
+        assert!(!db.repo.set_password(&nobody, "secret").await.unwrap());
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn admins_manage_roles_and_suspensions() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (admin, _) = app.user("Admin").await;
+        let (user, user_id) = app.user("User").await;
+        app.storage.set_role(&admin, Role::Admin).await.unwrap();
+
+        let query = format!(
+            r#"mutation {{ setUserRole(input: {{ user: "{user_id}", role: MODERATOR }}) {{ id }} }}"#
+        );
+        app.execute(admin, &query).await;
+
+        let data = app.execute(user, "{ viewer { role } }").await;
+        assert_eq!(json!({ "viewer": { "role": "MODERATOR" } }), data);
+
+        // Only admins hand out roles
+        let response = app.try_execute(user, &query).await;
+        assert!(!response.errors.is_empty());
+
+        let query = format!(
+            r#"mutation {{ setUserSuspended(input: {{ user: "{user_id}", suspended: true }}) {{ id }} }}"#
+        );
+        app.execute(admin, &query).await;
+
+        let response = app
+            .try_execute(
+                user,
+                r#"mutation { createPost(input: { content: "Hi" }) { node { id } } }"#,
+            )
+            .await;
+        assert!(!response.errors.is_empty());
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn users_are_saved_with_their_role_or_not_at_all() {
+        let db = TestDb::new().await;
+        for statement in [
+            r"
+                CREATE FUNCTION no_admins() RETURNS trigger AS $$
+                BEGIN RAISE EXCEPTION 'No admins'; END
+                $$ LANGUAGE plpgsql
+            ",
+            r"
+                CREATE TRIGGER no_admins BEFORE UPDATE ON app_user
+                FOR EACH ROW WHEN (NEW.role = 'admin') EXECUTE FUNCTION no_admins()
+            ",
+        ] {
+            db.repo.execute(statement, &[]).await.unwrap();
+        }
+
+        let moderator = db
+            .repo
+            .save_user("Mod", "Test", Role::Moderator)
+            .await
+            .unwrap();
+        assert_eq!(Role::Moderator, moderator.role);
+
+        // Setting the role fails after the insert, which goes along with it
+        let failed = db.repo.save_user("Admin", "Test", Role::Admin).await;
+        assert!(matches!(failed, Err(DbError::Statement(_))));
+
+        let names = db
+            .repo
+            .query("SELECT first_name FROM app_user", &[], |rows| {
+                rows.iter()
+                    .map(|row| row.try_get(0).map_err(DbError::mapping))
+                    .collect::<Result<Vec<String>, _>>()
+            })
+            .await
+            .unwrap();
+        assert_eq!(vec!["Mod"], names);
+
+        let nobody = (*moderator.user_id + 1000).into();
+        let failed = db.repo.add_friend(&moderator.user_id, &nobody).await;
+        assert!(matches!(failed, Err(DbError::Missing(_))));
+        assert!(db
+            .repo
+            .relations_of(&[moderator.user_id])
+            .await
+            .unwrap()
+            .is_empty());
+
+        db.drop().await;
+    }
+}
@@ -1,4 +1,5 @@
-use async_graphql::ID;
+use async_graphql::{Enum, ID};
+use postgres_types::{FromSql, ToSql};
 
 use crate::domain::{
     db_id::{CanDecodeId, DbId, HasDbId},
@@ -9,9 +10,11 @@ pub const SUFFIX: &str = "AppUser";
 
 #[derive(Clone)]
 pub struct AppUser {
-    pub(super) user_id: DbId,
+    pub(in crate::domain) user_id: DbId,
     pub(in crate::domain) first_name: String,
     pub(in crate::domain) last_name: String,
+    pub(in crate::domain) role: Role,
+    pub(in crate::domain) suspended: bool,
 }
 
 impl HasDbId for AppUser {
@@ -25,3 +28,15 @@ impl CanDecodeId for AppUser {
         Self::decode_with_suffix(relay_id, SUFFIX)
     }
 }
+
+// Ordered by privilege, every role includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Enum, ToSql, FromSql)]
+#[postgres(name = "app_role")]
+pub enum Role {
+    #[postgres(name = "user")]
+    User,
+    #[postgres(name = "moderator")]
+    Moderator,
+    #[postgres(name = "admin")]
+    Admin,
+}
@@ -1,24 +1,29 @@
-use async_graphql::{Context, InputObject, Object, ID};
+use async_graphql::{
+    connection::{Edge, EmptyFields},
+    Context, InputObject, Object, ID,
+};
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use time::OffsetDateTime;
 use tracing::instrument;
 
 use crate::{
     domain::{
         errors::GqlError,
         post::Post,
-        relay_meta::{paginate, AppConnection},
+        relay_meta::{
+            into_payload, paginate, selects_rows, AppConnection, AppCursor, ConnectionOrder,
+            CreatedRange, TotalCount, UserError,
+        },
     },
-    infrastructure::db::Loaders,
+    infrastructure::{query_limits::QueryLimits, storage::Loaders},
 };
 
-use super::domain::{AppUser, SUFFIX};
+use super::domain::{AppUser, Role, SUFFIX};
 
 #[Object]
 impl AppUser {
     pub async fn id(&self) -> ID {
-        let combined = self.user_id.to_string() + SUFFIX;
-
-        ID(URL_SAFE.encode(combined))
+        relay_id(self)
     }
 
     pub async fn first_name(&self) -> &str {
@@ -29,30 +34,44 @@ impl AppUser {
         &self.last_name
     }
 
+    /// Ordered by id, so the same friends come first every time
     #[instrument(skip_all, err)]
-    #[graphql(complexity = "10 * child_complexity")]
-    // TODO: Limit number of loaded friends?
-    pub async fn friends(&self, ctx: &Context<'_>) -> Result<Vec<AppUser>, GqlError> {
+    #[graphql(complexity = "first.max(0) as usize * child_complexity")]
+    pub async fn friends(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 10)] first: i32,
+    ) -> Result<Vec<AppUser>, GqlError> {
         let loaders = ctx.data::<Loaders>()?;
+        let limits = ctx.data::<QueryLimits>()?;
+
+        let first = usize::try_from(first)
+            .map_err(|_| GqlError::invalid_field("first", "must not be negative"))?
+            .min(limits.friends);
 
-        let friend_ids = loaders
+        let mut friend_ids = loaders
             .friend_id
             .load_one(self.user_id)
             .await
-            .map_err(|_| GqlError::DbLoad)?
+            .map_err(GqlError::db_load)?
             .ok_or_else(|| GqlError::InvalidState("Expected empty vec, got None".to_string()))?;
 
-        let users = loaders
+        friend_ids.sort();
+        friend_ids.truncate(first);
+
+        let mut users = loaders
             .app_user
-            .load_many(friend_ids)
+            .load_many(friend_ids.iter().copied())
             .await
-            .map_err(|_| GqlError::DbLoad)?
-            .into_values()
-            .collect();
+            .map_err(GqlError::db_load)?;
 
-        Ok(users)
+        Ok(friend_ids
+            .iter()
+            .filter_map(|friend_id| users.remove(friend_id))
+            .collect())
     }
 
+    #[allow(clippy::too_many_arguments)]
     #[instrument(skip_all, err)]
     #[graphql(
         complexity = "first.unwrap_or(0).try_into().unwrap_or(usize::MAX) * child_complexity 
@@ -65,31 +84,182 @@ impl AppUser {
         before: Option<String>,
         first: Option<i32>,
         last: Option<i32>,
+        #[graphql(default_with = "ConnectionOrder::CreatedOnDesc")] order_by: ConnectionOrder,
+        since: Option<OffsetDateTime>,
+        until: Option<OffsetDateTime>,
     ) -> Result<AppConnection<Post>, GqlError> {
         let loaders = ctx.data::<Loaders>()?;
 
-        let posts = loaders
-            .posts_of_author
-            .load_one(self.user_id)
-            .await
-            .map_err(|_| GqlError::DbLoad)?
-            .ok_or_else(|| GqlError::InvalidState("Expected empty vec, got None".to_string()))?;
+        let range = CreatedRange::new(since, until);
 
-        let connection = paginate(after, before, first, last, posts)
-            .await
-            .map_err(|_| GqlError::DbLoad)?;
+        let posts = if selects_rows(ctx) {
+            loaders
+                .posts_of_author
+                .load_one(self.user_id)
+                .await
+                .map_err(GqlError::db_load)?
+                .ok_or_else(|| GqlError::InvalidState("Expected empty vec, got None".to_string()))?
+        } else {
+            Vec::new()
+        };
+
+        let posts = range.arrange(posts, order_by, |p| p.created_on);
+        let total_count = TotalCount::PostsOfAuthors(vec![self.user_id], range);
+
+        let connection = paginate(after, before, first, last, posts, total_count).await?;
 
         Ok(connection)
     }
 }
 
+fn relay_id(user: &AppUser) -> ID {
+    let combined = user.user_id.to_string() + SUFFIX;
+
+    ID(URL_SAFE.encode(combined))
+}
+
 #[derive(InputObject, Debug)]
 pub struct AppUserInput {
+    pub(in crate::domain) client_mutation_id: Option<String>,
     pub(in crate::domain) first_name: String,
     pub(in crate::domain) last_name: String,
 }
 
 #[derive(InputObject, Debug)]
 pub struct AddFriendInput {
+    pub(in crate::domain) client_mutation_id: Option<String>,
     pub(in crate::domain) friend: ID,
 }
+
+#[derive(InputObject, Debug)]
+pub struct SetUserRoleInput {
+    pub(in crate::domain) user: ID,
+    pub(in crate::domain) role: Role,
+}
+
+#[derive(InputObject, Debug)]
+pub struct SetUserSuspendedInput {
+    pub(in crate::domain) user: ID,
+    pub(in crate::domain) suspended: bool,
+}
+
+pub struct CreateUserPayload {
+    client_mutation_id: Option<String>,
+    user: Option<AppUser>,
+    user_errors: Vec<UserError>,
+}
+
+impl CreateUserPayload {
+    pub fn new(
+        client_mutation_id: Option<String>,
+        result: Result<AppUser, GqlError>,
+    ) -> Result<Self, GqlError> {
+        let (user, user_errors) = into_payload(result)?;
+
+        Ok(Self {
+            client_mutation_id,
+            user,
+            user_errors,
+        })
+    }
+}
+
+// The deprecated fields keep selections of the former `AppUser` result working
+#[Object]
+impl CreateUserPayload {
+    async fn client_mutation_id(&self) -> Option<&str> {
+        self.client_mutation_id.as_deref()
+    }
+
+    async fn node(&self) -> Option<&AppUser> {
+        self.user.as_ref()
+    }
+
+    async fn edge(&self) -> Option<Edge<AppCursor, AppUser, EmptyFields>> {
+        self.user
+            .clone()
+            .map(|user| Edge::new(AppCursor(user.user_id), user))
+    }
+
+    async fn user_errors(&self) -> &Vec<UserError> {
+        &self.user_errors
+    }
+
+    #[graphql(deprecation = "Use `node.id` instead")]
+    async fn id(&self) -> Option<ID> {
+        self.user.as_ref().map(relay_id)
+    }
+
+    #[graphql(deprecation = "Use `node.firstName` instead")]
+    async fn first_name(&self) -> Option<&str> {
+        self.user.as_ref().map(|user| user.first_name.as_str())
+    }
+
+    #[graphql(deprecation = "Use `node.lastName` instead")]
+    async fn last_name(&self) -> Option<&str> {
+        self.user.as_ref().map(|user| user.last_name.as_str())
+    }
+}
+
+/// The node is the viewer, whose friends changed, and the edge points to the new friend.
+pub struct AddFriendPayload {
+    client_mutation_id: Option<String>,
+    users: Option<(AppUser, AppUser)>,
+    user_errors: Vec<UserError>,
+}
+
+impl AddFriendPayload {
+    pub fn new(
+        client_mutation_id: Option<String>,
+        result: Result<(AppUser, AppUser), GqlError>,
+    ) -> Result<Self, GqlError> {
+        let (users, user_errors) = into_payload(result)?;
+
+        Ok(Self {
+            client_mutation_id,
+            users,
+            user_errors,
+        })
+    }
+
+    fn user(&self) -> Option<&AppUser> {
+        self.users.as_ref().map(|(user, _)| user)
+    }
+}
+
+// The deprecated fields keep selections of the former `AppUser` result working
+#[Object]
+impl AddFriendPayload {
+    async fn client_mutation_id(&self) -> Option<&str> {
+        self.client_mutation_id.as_deref()
+    }
+
+    async fn node(&self) -> Option<&AppUser> {
+        self.user()
+    }
+
+    async fn edge(&self) -> Option<Edge<AppCursor, AppUser, EmptyFields>> {
+        self.users
+            .clone()
+            .map(|(_, friend)| Edge::new(AppCursor(friend.user_id), friend))
+    }
+
+    async fn user_errors(&self) -> &Vec<UserError> {
+        &self.user_errors
+    }
+
+    #[graphql(deprecation = "Use `node.id` instead")]
+    async fn id(&self) -> Option<ID> {
+        self.user().map(relay_id)
+    }
+
+    #[graphql(deprecation = "Use `node.firstName` instead")]
+    async fn first_name(&self) -> Option<&str> {
+        self.user().map(|user| user.first_name.as_str())
+    }
+
+    #[graphql(deprecation = "Use `node.lastName` instead")]
+    async fn last_name(&self) -> Option<&str> {
+        self.user().map(|user| user.last_name.as_str())
+    }
+}
@@ -0,0 +1,75 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_graphql::dataloader::Loader;
+use tracing::instrument;
+
+use crate::{
+    domain::db_id::DbId,
+    infrastructure::{storage::Storage, DbError},
+};
+
+use super::domain::AppUser;
+
+pub struct AppUserLoader {
+    storage: Storage,
+}
+
+impl AppUserLoader {
+    pub fn new(storage: Storage) -> Self {
+        Self { storage }
+    }
+}
+
+impl Loader<DbId> for AppUserLoader {
+    type Value = AppUser;
+    type Error = Arc<DbError>;
+
+    #[instrument(skip(self), err)]
+    async fn load(&self, ids: &[DbId]) -> Result<HashMap<DbId, Self::Value>, Self::Error> {
+        let users = self.storage.users(ids).await?;
+
+        Ok(users.into_iter().map(|user| (user.user_id, user)).collect())
+    }
+}
+
+pub struct FriendIdLoader {
+    storage: Storage,
+}
+
+impl FriendIdLoader {
+    pub fn new(storage: Storage) -> Self {
+        Self { storage }
+    }
+}
+
+impl Loader<DbId> for FriendIdLoader {
+    type Value = Vec<DbId>;
+    type Error = Arc<DbError>;
+
+    #[instrument(skip(self), err)]
+    async fn load(&self, ids: &[DbId]) -> Result<HashMap<DbId, Self::Value>, Self::Error> {
+        let relations = self.storage.relations_of(ids).await?;
+
+        let result_map = ids
+            .iter()
+            .map(|id| {
+                let friends: Vec<DbId> = relations
+                    .iter()
+                    .filter_map(|rel| {
+                        if &rel.0 == id {
+                            Some(rel.1)
+                        } else if &rel.1 == id {
+                            Some(rel.0)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+
+                (*id, friends)
+            })
+            .collect();
+
+        Ok(result_map)
+    }
+}
@@ -0,0 +1,122 @@
+use async_trait::async_trait;
+
+use crate::{
+    domain::{db_id::DbId, relay_meta::NodeId},
+    infrastructure::{
+        memory::MemoryStore,
+        notification_center::{Notification, Operation, RelationNotification},
+        DbError,
+    },
+};
+
+use super::{
+    domain::{AppUser, Role},
+    store::{RelationStore, UserStore},
+};
+
+#[async_trait]
+impl UserStore for MemoryStore {
+    async fn users(&self, user_ids: &[DbId]) -> Result<Vec<AppUser>, DbError> {
+        let tables = self.tables();
+
+        Ok(user_ids
+            .iter()
+            .filter_map(|user_id| tables.users.get(user_id).cloned())
+            .collect())
+    }
+
+    async fn save_user(
+        &self,
+        first_name: &str,
+        last_name: &str,
+        role: Role,
+    ) -> Result<AppUser, DbError> {
+        let mut tables = self.tables();
+
+        let user = AppUser {
+            user_id: tables.next_id(),
+            first_name: first_name.to_string(),
+            last_name: last_name.to_string(),
+            role,
+            suspended: false,
+        };
+        tables.users.insert(user.user_id, user.clone());
+
+        Ok(user)
+    }
+
+    async fn set_suspended(
+        &self,
+        user_id: &DbId,
+        suspended: bool,
+    ) -> Result<Option<AppUser>, DbError> {
+        Ok(self.tables().users.get_mut(user_id).map(|user| {
+            user.suspended = suspended;
+            user.clone()
+        }))
+    }
+
+    async fn set_role(&self, user_id: &DbId, role: Role) -> Result<Option<AppUser>, DbError> {
+        Ok(self.tables().users.get_mut(user_id).map(|user| {
+            user.role = role;
+            user.clone()
+        }))
+    }
+
+    // Nothing signs in against memory, so only whether the user exists matters
+    async fn set_password(&self, user_id: &DbId, _password: &str) -> Result<bool, DbError> {
+        Ok(self.tables().users.contains_key(user_id))
+    }
+
+    async fn owner_of(&self, node: &NodeId) -> Result<Option<DbId>, DbError> {
+        let tables = self.tables();
+
+        Ok(match node {
+            NodeId::AppUser(user_id) => tables.users.get(user_id).map(|user| user.user_id),
+            NodeId::Comment(comment_id) => tables
+                .comments
+                .get(comment_id)
+                .map(|comment| comment.row.author),
+            NodeId::Post(post_id) => tables.posts.get(post_id).map(|post| post.row.author),
+        })
+    }
+}
+
+#[async_trait]
+impl RelationStore for MemoryStore {
+    async fn relations_of(&self, user_ids: &[DbId]) -> Result<Vec<(DbId, DbId)>, DbError> {
+        Ok(self
+            .tables()
+            .relations
+            .iter()
+            .filter(|(a, b)| user_ids.contains(a) || user_ids.contains(b))
+            .copied()
+            .collect())
+    }
+
+    async fn add_friend(&self, user: &DbId, friend: &DbId) -> Result<(), DbError> {
+        let (user_id_a, user_id_b) = (*user.min(friend), *user.max(friend));
+
+        let inserted = {
+            let mut tables = self.tables();
+
+            for user_id in [user_id_a, user_id_b] {
+                if !tables.users.contains_key(&user_id) {
+                    return Err(DbError::Missing(format!("User {}", *user_id)));
+                }
+            }
+
+            tables.relations.insert((user_id_a, user_id_b))
+        };
+
+        if inserted {
+            self.notify([Notification::Relation(RelationNotification {
+                user_id_a,
+                user_id_b,
+                operation: Operation::Insert,
+            })]);
+        }
+
+        Ok(())
+    }
+}
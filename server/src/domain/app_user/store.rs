@@ -0,0 +1,45 @@
+use async_trait::async_trait;
+
+use crate::{
+    domain::{db_id::DbId, relay_meta::NodeId},
+    infrastructure::DbError,
+};
+
+use super::domain::{AppUser, Role};
+
+/// Where users are kept.
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn users(&self, user_ids: &[DbId]) -> Result<Vec<AppUser>, DbError>;
+
+    async fn save_user(
+        &self,
+        first_name: &str,
+        last_name: &str,
+        role: Role,
+    ) -> Result<AppUser, DbError>;
+
+    async fn set_suspended(
+        &self,
+        user_id: &DbId,
+        suspended: bool,
+    ) -> Result<Option<AppUser>, DbError>;
+
+    async fn set_role(&self, user_id: &DbId, role: Role) -> Result<Option<AppUser>, DbError>;
+
+    /// Replaces the password of a user, false if there is no such user.
+    async fn set_password(&self, user_id: &DbId, password: &str) -> Result<bool, DbError>;
+
+    /// Users own themselves, posts and comments belong to their author.
+    async fn owner_of(&self, node: &NodeId) -> Result<Option<DbId>, DbError>;
+}
+
+/// Friendships, which always go both ways.
+#[async_trait]
+pub trait RelationStore: Send + Sync {
+    /// Every relation one of the users is part of.
+    async fn relations_of(&self, user_ids: &[DbId]) -> Result<Vec<(DbId, DbId)>, DbError>;
+
+    /// Fails with `DbError::Missing` unless both users exist.
+    async fn add_friend(&self, user: &DbId, friend: &DbId) -> Result<(), DbError>;
+}
@@ -0,0 +1,104 @@
+use async_graphql::{Context, ErrorExtensions, Guard, Result, ID};
+
+use crate::infrastructure::storage::{Loaders, Storage};
+
+use super::{
+    app_user::{AppUser, Role},
+    db_id::DbId,
+    errors::GqlError,
+    relay_meta::NodeId,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Session {
+    pub user_id: DbId,
+}
+
+impl Session {
+    pub fn new(user_id: DbId) -> Self {
+        Self { user_id }
+    }
+
+    pub fn of<'a>(ctx: &Context<'a>) -> Result<&'a Session, GqlError> {
+        ctx.data_opt::<Session>().ok_or(GqlError::Unauthenticated)
+    }
+
+    pub async fn viewer(&self, ctx: &Context<'_>) -> Result<AppUser, GqlError> {
+        let loaders = ctx.data::<Loaders>()?;
+
+        loaders
+            .app_user
+            .load_one(self.user_id)
+            .await
+            .map_err(GqlError::db_load)?
+            .ok_or_else(|| GqlError::InvalidState("Expected viewer, got None".to_string()))
+    }
+}
+
+pub struct RoleGuard {
+    role: Role,
+}
+
+impl RoleGuard {
+    pub fn new(role: Role) -> Self {
+        Self { role }
+    }
+}
+
+impl Guard for RoleGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        let viewer = Session::of(ctx)?.viewer(ctx).await?;
+
+        if viewer.role < self.role {
+            return Err(GqlError::Forbidden(format!("Requires role {:?}", self.role)).extend());
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ActiveUserGuard;
+
+impl Guard for ActiveUserGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        let viewer = Session::of(ctx)?.viewer(ctx).await?;
+
+        if viewer.suspended {
+            return Err(GqlError::Forbidden("Viewer is suspended".to_string()).extend());
+        }
+
+        Ok(())
+    }
+}
+
+pub struct OwnerGuard<'a> {
+    target: &'a ID,
+}
+
+impl<'a> OwnerGuard<'a> {
+    pub fn new(target: &'a ID) -> Self {
+        Self { target }
+    }
+}
+
+impl Guard for OwnerGuard<'_> {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        let session = Session::of(ctx)?;
+        let storage = ctx.data::<Storage>()?;
+
+        let target =
+            NodeId::decode(self.target).map_err(|e| GqlError::InvalidRequest(e.to_string()))?;
+
+        let owner = storage
+            .owner_of(&target)
+            .await
+            .map_err(GqlError::db_load)?
+            .ok_or_else(|| GqlError::NotFound("Given id does not exist".to_string()))?;
+
+        if owner != session.user_id {
+            return Err(GqlError::Forbidden("Viewer does not own this".to_string()).extend());
+        }
+
+        Ok(())
+    }
+}
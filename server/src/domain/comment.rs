@@ -1,7 +1,14 @@
 mod db;
 mod domain;
 mod graphql;
+mod loaders;
+#[cfg(test)]
+mod memory;
+mod store;
 
-pub use db::{CommentLoader, CommentsOfPostLoader};
 pub use domain::Comment;
-pub use graphql::CommentInput;
+pub use graphql::{
+    CommentChange, CommentInput, CommentOperation, CreateCommentPayload, EditCommentInput,
+};
+pub use loaders::{CommentCountOfPostLoader, CommentLoader, CommentsOfPostLoader};
+pub use store::CommentStore;
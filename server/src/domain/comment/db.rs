@@ -1,106 +1,106 @@
-use std::{collections::HashMap, sync::Arc};
-
-use async_graphql::dataloader::Loader;
+use async_trait::async_trait;
 use time::OffsetDateTime;
 use tokio_postgres::Row;
 use tracing::{instrument, Level};
 
 use crate::{
-    domain::db_id::DbId,
+    domain::{db_id::DbId, relay_meta::CreatedRange},
     infrastructure::{db::Repo, DbError},
 };
 
-use super::Comment;
-
-pub struct CommentLoader {
-    repo: Repo,
-}
-
-impl CommentLoader {
-    pub fn new(repo: Repo) -> Self {
-        Self { repo }
-    }
-}
-
-impl Loader<DbId> for CommentLoader {
-    type Value = Comment;
-    type Error = Arc<DbError>;
+use super::{store::CommentStore, Comment};
 
+#[async_trait]
+impl CommentStore for Repo {
     #[instrument(skip(self), err)]
-    async fn load(&self, ids: &[DbId]) -> Result<HashMap<DbId, Self::Value>, Self::Error> {
-        self.repo
-            .query(
-                "SELECT * FROM comment WHERE comment_id = ANY($1)",
-                &[&ids],
-                |rows| {
-                    rows.into_iter()
-                        .map(|row| {
-                            let comment: Comment = row.try_into()?;
-                            Ok::<_, DbError>((comment.comment_id, comment))
-                        })
-                        .collect::<Result<HashMap<_, _>, _>>()
-                },
-            )
-            .await
-            .map_err(|e| e.into())
+    async fn visible_comments(&self, comment_ids: &[DbId]) -> Result<Vec<Comment>, DbError> {
+        self.query(
+            "SELECT * FROM comment WHERE comment_id = ANY($1) AND NOT hidden",
+            &[&comment_ids],
+            |rows| rows.into_iter().map(|row| row.try_into()).collect(),
+        )
+        .await
     }
-}
-
-pub struct CommentsOfPostLoader {
-    repo: Repo,
-}
 
-impl CommentsOfPostLoader {
-    pub fn new(repo: Repo) -> Self {
-        Self { repo }
+    #[instrument(skip(self), err)]
+    async fn comments_of_posts(&self, post_ids: &[DbId]) -> Result<Vec<Comment>, DbError> {
+        self.query(
+            "SELECT * FROM comment WHERE referenced_post = ANY($1) AND NOT hidden",
+            &[&post_ids],
+            |rows| rows.into_iter().map(|row| row.try_into()).collect(),
+        )
+        .await
     }
-}
-
-impl Loader<DbId> for CommentsOfPostLoader {
-    type Value = Vec<Comment>;
-    type Error = Arc<DbError>;
 
     #[instrument(skip(self), err)]
-    async fn load(&self, ids: &[DbId]) -> Result<HashMap<DbId, Self::Value>, Self::Error> {
-        let comments: Vec<Comment> = self
-            .repo
-            .query(
-                "SELECT * FROM comment WHERE referenced_post = ANY($1)",
-                &[&ids],
-                |rows| rows.into_iter().map(|row| row.try_into()).collect(),
-            )
-            .await?;
-
-        let mut result = HashMap::from_iter(ids.iter().map(|id| (*id, Vec::new())));
-
-        for comment in comments {
-            result
-                .entry(comment.referenced_post)
-                .and_modify(|old| old.push(comment));
-        }
-
-        Ok(result)
+    async fn count_comments_of_posts(
+        &self,
+        post_ids: &[DbId],
+        range: CreatedRange,
+    ) -> Result<Vec<(DbId, usize)>, DbError> {
+        self.query(
+            r"
+                SELECT referenced_post, COUNT(*) FROM comment
+                WHERE referenced_post = ANY($1) AND NOT hidden
+                    AND ($2::timestamptz IS NULL OR created_on >= $2)
+                    AND ($3::timestamptz IS NULL OR created_on < $3)
+                GROUP BY referenced_post
+            ",
+            &[&post_ids, &range.since, &range.until],
+            |rows| {
+                rows.into_iter()
+                    .map(|row| Ok((row.try_get(0)?, row.try_get::<_, i64>(1)? as usize)))
+                    .collect::<Result<_, tokio_postgres::Error>>()
+                    .map_err(DbError::mapping)
+            },
+        )
+        .await
     }
-}
 
-impl Repo {
     #[instrument(skip(self), err)]
-    pub async fn save_comment(
+    async fn save_comment(
         &self,
         author_id: &DbId,
         referenced_post_id: &DbId,
         content: &str,
-    ) -> Result<Comment, DbError> {
+    ) -> Result<Option<Comment>, DbError> {
         let now = OffsetDateTime::now_utc();
 
-        self.query_one(
+        self.query(
             r"
                 INSERT INTO comment (author, created_on, content, referenced_post)
-                VALUES ($1, $2, $3, $4)
+                SELECT $1, $2, $3, $4
+                WHERE EXISTS (
+                    SELECT 1 FROM post
+                    WHERE post_id = $4 AND state = 'published' AND NOT hidden
+                )
                 RETURNING *
             ",
             &[&author_id, &now, &content, &referenced_post_id],
-            |row| row.try_into(),
+            |rows| rows.into_iter().next().map(Comment::try_from).transpose(),
+        )
+        .await
+    }
+
+    #[instrument(skip(self), err)]
+    async fn update_comment_content(
+        &self,
+        comment_id: &DbId,
+        content: &str,
+    ) -> Result<Option<Comment>, DbError> {
+        self.query(
+            "UPDATE comment SET content = $2 WHERE comment_id = $1 AND NOT hidden RETURNING *",
+            &[comment_id, &content],
+            |rows| rows.into_iter().next().map(Comment::try_from).transpose(),
+        )
+        .await
+    }
+
+    #[instrument(skip(self), err)]
+    async fn hide_comment(&self, comment_id: &DbId) -> Result<(), DbError> {
+        self.execute(
+            "UPDATE comment SET hidden = true WHERE comment_id = $1",
+            &[comment_id],
         )
         .await
     }
@@ -120,3 +120,112 @@ impl TryFrom<Row> for Comment {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::ID;
+    use serde_json::json;
+
+    use crate::{
+        domain::{db_id::CanDecodeId, post::Post},
+        infrastructure::testing::{TestApp, TestDb},
+    };
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn comments_page_and_get_edited_by_their_authors() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, _) = app.user("Alice").await;
+        let (bob, _) = app.user("Bob").await;
+        let post_id = app.post(alice, "Post").await;
+
+        let first_id = app.comment(bob, &post_id, "First").await;
+        for content in ["Second", "Third"] {
+            app.comment(alice, &post_id, content).await;
+        }
+
+        let query = format!(
+            r#"{{
+                node(id: "{post_id}") {{
+                    ... on Post {{
+                        comments(first: 2) {{
+                            totalCount
+                            edges {{ node {{ content author {{ firstName }} }} }}
+                            pageInfo {{ hasNextPage }}
+                        }}
+                        newest: comments(last: 1) {{ edges {{ node {{ content }} }} }}
+                    }}
+                }}
+            }}"#
+        );
+        let data = app.execute(bob, &query).await;
+        assert_eq!(
+            json!({
+                "comments": {
+                    "totalCount": 3,
+                    "edges": [
+                        { "node": { "content": "First", "author": { "firstName": "Bob" } } },
+                        { "node": { "content": "Second", "author": { "firstName": "Alice" } } },
+                    ],
+                    "pageInfo": { "hasNextPage": true },
+                },
+                "newest": { "edges": [{ "node": { "content": "Third" } }] },
+            }),
+            data["node"]
+        );
+
+        let query = format!(
+            r#"mutation {{ editComment(input: {{ comment: "{first_id}", content: "Edited" }}) {{ content }} }}"#
+        );
+        // The post belongs to Alice, the comment does not
+        assert!(!app.try_execute(alice, &query).await.errors.is_empty());
+        let data = app.execute(bob, &query).await;
+        assert_eq!(json!({ "editComment": { "content": "Edited" } }), data);
+
+        let query = format!(r#"{{ node(id: "{first_id}") {{ ... on Comment {{ content }} }} }}"#);
+        let data = app.execute(alice, &query).await;
+        assert_eq!(json!({ "node": { "content": "Edited" } }), data);
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn comments_need_a_published_visible_post() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, _) = app.user("Alice").await;
+        let (bob, _) = app.user("Bob").await;
+
+        let data = app
+            .execute(
+                alice,
+                r#"mutation { createPost(input: { content: "Draft", draft: true }) { node { id } } }"#,
+            )
+            .await;
+        let draft_id = data["createPost"]["node"]["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let hidden_id = app.post(alice, "Hidden").await;
+        app.storage
+            .hide_post(&Post::decode(&ID(hidden_id.clone())).unwrap())
+            .await
+            .unwrap();
+
+        for post_id in [draft_id, hidden_id] {
+            let query = format!(
+                r#"mutation {{ createComment(input: {{ referencedPost: "{post_id}", content: "Hi" }}) {{ node {{ id }} userErrors {{ code }} }} }}"#
+            );
+            let data = app.execute(bob, &query).await;
+
+            assert_eq!(
+                json!({ "node": null, "userErrors": [{ "code": "NOT_FOUND" }] }),
+                data["createComment"]
+            );
+        }
+
+        db.drop().await;
+    }
+}
@@ -12,8 +12,8 @@ pub const SUFFIX: &str = "Comment";
 pub struct Comment {
     pub comment_id: DbId,
     pub(super) referenced_post: DbId,
-    pub(super) author: DbId,
-    pub(super) created_on: OffsetDateTime,
+    pub(in crate::domain) author: DbId,
+    pub(in crate::domain) created_on: OffsetDateTime,
     pub(super) content: String,
 }
 
@@ -1,11 +1,20 @@
-use async_graphql::{Context, InputObject, Object, ID};
+use async_graphql::{
+    connection::{CursorType, Edge, EmptyFields},
+    Context, Enum, InputObject, Object, SimpleObject, ID,
+};
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
 use time::OffsetDateTime;
 use tracing::instrument;
 
 use crate::{
-    domain::{app_user::AppUser, errors::GqlError, post::Post},
-    infrastructure::db::Loaders,
+    domain::{
+        app_user::AppUser,
+        db_id::DbId,
+        errors::GqlError,
+        post::Post,
+        relay_meta::{into_payload, AppCursor, UserError},
+    },
+    infrastructure::storage::Loaders,
 };
 
 use super::{domain::SUFFIX, Comment};
@@ -13,9 +22,7 @@ use super::{domain::SUFFIX, Comment};
 #[Object]
 impl Comment {
     pub async fn id(&self) -> ID {
-        let combined = self.comment_id.to_string() + SUFFIX;
-
-        ID(URL_SAFE.encode(combined))
+        relay_id(self.comment_id)
     }
 
     #[instrument(skip_all, err)]
@@ -27,7 +34,7 @@ impl Comment {
             .post
             .load_one(self.referenced_post)
             .await
-            .map_err(|_| GqlError::DbLoad)?
+            .map_err(GqlError::db_load)?
             .ok_or_else(|| GqlError::InvalidState("Expected referenced post, got None".to_string()))
     }
 
@@ -40,7 +47,7 @@ impl Comment {
             .app_user
             .load_one(self.author)
             .await
-            .map_err(|_| GqlError::DbLoad)?
+            .map_err(GqlError::db_load)?
             .ok_or_else(|| GqlError::InvalidState("Expected author, got None".to_string()))
     }
 
@@ -53,8 +60,105 @@ impl Comment {
     }
 }
 
+fn relay_id(comment_id: DbId) -> ID {
+    let combined = comment_id.to_string() + SUFFIX;
+
+    ID(URL_SAFE.encode(combined))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum CommentOperation {
+    Created,
+    Edited,
+    Deleted,
+}
+
+/// Something that happened to a comment of a post. Hidden comments count as deleted.
+#[derive(SimpleObject)]
+pub struct CommentChange {
+    operation: CommentOperation,
+    /// Also given for deleted comments, so clients can remove them
+    comment_id: ID,
+    /// Missing for deleted comments
+    edge: Option<Edge<AppCursor, Comment, EmptyFields>>,
+}
+
+impl CommentChange {
+    pub fn new(operation: CommentOperation, comment_id: DbId, comment: Option<Comment>) -> Self {
+        let (operation, edge) = match comment {
+            Some(comment) if operation != CommentOperation::Deleted => (
+                operation,
+                Some(Edge::new(AppCursor(comment.comment_id), comment)),
+            ),
+            _ => (CommentOperation::Deleted, None),
+        };
+
+        Self {
+            operation,
+            comment_id: relay_id(comment_id),
+            edge,
+        }
+    }
+}
+
 #[derive(Debug, InputObject)]
 pub struct CommentInput {
+    pub(in crate::domain) client_mutation_id: Option<String>,
     pub(in crate::domain) content: String,
     pub(in crate::domain) referenced_post: ID,
 }
+
+#[derive(Debug, InputObject)]
+pub struct EditCommentInput {
+    pub(in crate::domain) comment: ID,
+    pub(in crate::domain) content: String,
+}
+
+pub struct CreateCommentPayload {
+    client_mutation_id: Option<String>,
+    comment: Option<Comment>,
+    user_errors: Vec<UserError>,
+}
+
+impl CreateCommentPayload {
+    pub fn new(
+        client_mutation_id: Option<String>,
+        result: Result<Comment, GqlError>,
+    ) -> Result<Self, GqlError> {
+        let (comment, user_errors) = into_payload(result)?;
+
+        Ok(Self {
+            client_mutation_id,
+            comment,
+            user_errors,
+        })
+    }
+}
+
+#[Object]
+impl CreateCommentPayload {
+    async fn client_mutation_id(&self) -> Option<&str> {
+        self.client_mutation_id.as_deref()
+    }
+
+    async fn node(&self) -> Option<&Comment> {
+        self.comment.as_ref()
+    }
+
+    async fn edge(&self) -> Option<Edge<AppCursor, Comment, EmptyFields>> {
+        self.comment
+            .clone()
+            .map(|comment| Edge::new(AppCursor(comment.comment_id), comment))
+    }
+
+    async fn user_errors(&self) -> &Vec<UserError> {
+        &self.user_errors
+    }
+
+    #[graphql(deprecation = "Use `edge.cursor` instead")]
+    async fn cursor(&self) -> Option<String> {
+        self.comment
+            .as_ref()
+            .map(|comment| AppCursor(comment.comment_id).encode_cursor())
+    }
+}
@@ -0,0 +1,102 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_graphql::dataloader::Loader;
+use tracing::instrument;
+
+use crate::{
+    domain::{
+        db_id::DbId,
+        relay_meta::{by_range, CountKey},
+    },
+    infrastructure::{storage::Storage, DbError},
+};
+
+use super::Comment;
+
+pub struct CommentLoader {
+    storage: Storage,
+}
+
+impl CommentLoader {
+    pub fn new(storage: Storage) -> Self {
+        Self { storage }
+    }
+}
+
+impl Loader<DbId> for CommentLoader {
+    type Value = Comment;
+    type Error = Arc<DbError>;
+
+    #[instrument(skip(self), err)]
+    async fn load(&self, ids: &[DbId]) -> Result<HashMap<DbId, Self::Value>, Self::Error> {
+        let comments = self.storage.visible_comments(ids).await?;
+
+        Ok(comments
+            .into_iter()
+            .map(|comment| (comment.comment_id, comment))
+            .collect())
+    }
+}
+
+pub struct CommentsOfPostLoader {
+    storage: Storage,
+}
+
+impl CommentsOfPostLoader {
+    pub fn new(storage: Storage) -> Self {
+        Self { storage }
+    }
+}
+
+impl Loader<DbId> for CommentsOfPostLoader {
+    type Value = Vec<Comment>;
+    type Error = Arc<DbError>;
+
+    #[instrument(skip(self), err)]
+    async fn load(&self, ids: &[DbId]) -> Result<HashMap<DbId, Self::Value>, Self::Error> {
+        let comments = self.storage.comments_of_posts(ids).await?;
+
+        let mut result = HashMap::from_iter(ids.iter().map(|id| (*id, Vec::new())));
+
+        for comment in comments {
+            result
+                .entry(comment.referenced_post)
+                .and_modify(|old| old.push(comment));
+        }
+
+        Ok(result)
+    }
+}
+
+pub struct CommentCountOfPostLoader {
+    storage: Storage,
+}
+
+impl CommentCountOfPostLoader {
+    pub fn new(storage: Storage) -> Self {
+        Self { storage }
+    }
+}
+
+impl Loader<CountKey> for CommentCountOfPostLoader {
+    type Value = usize;
+    type Error = Arc<DbError>;
+
+    #[instrument(skip(self), err)]
+    async fn load(&self, keys: &[CountKey]) -> Result<HashMap<CountKey, Self::Value>, Self::Error> {
+        let mut result = HashMap::new();
+
+        for (range, posts) in by_range(keys) {
+            let counts = self.storage.count_comments_of_posts(&posts, range).await?;
+
+            result.extend(posts.iter().map(|post| ((*post, range), 0)));
+            result.extend(
+                counts
+                    .into_iter()
+                    .map(|(post, count)| ((post, range), count)),
+            );
+        }
+
+        Ok(result)
+    }
+}
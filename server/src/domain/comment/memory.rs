@@ -0,0 +1,154 @@
+use async_trait::async_trait;
+use time::OffsetDateTime;
+
+use crate::{
+    domain::{db_id::DbId, post::PostState, relay_meta::CreatedRange},
+    infrastructure::{
+        memory::{Hideable, MemoryStore},
+        notification_center::{CommentNotification, Notification, Operation},
+        DbError,
+    },
+};
+
+use super::{store::CommentStore, Comment};
+
+fn comment_notification(comment: &Hideable<Comment>, operation: Operation) -> Notification {
+    Notification::Comment(CommentNotification {
+        author_id: comment.row.author,
+        post_id: comment.row.referenced_post,
+        comment_id: comment.row.comment_id,
+        operation,
+        hidden: comment.hidden,
+    })
+}
+
+#[async_trait]
+impl CommentStore for MemoryStore {
+    async fn visible_comments(&self, comment_ids: &[DbId]) -> Result<Vec<Comment>, DbError> {
+        Ok(self
+            .tables()
+            .comments
+            .values()
+            .filter_map(Hideable::visible)
+            .filter(|comment| comment_ids.contains(&comment.comment_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn comments_of_posts(&self, post_ids: &[DbId]) -> Result<Vec<Comment>, DbError> {
+        Ok(self
+            .tables()
+            .comments
+            .values()
+            .filter_map(Hideable::visible)
+            .filter(|comment| post_ids.contains(&comment.referenced_post))
+            .cloned()
+            .collect())
+    }
+
+    async fn count_comments_of_posts(
+        &self,
+        post_ids: &[DbId],
+        range: CreatedRange,
+    ) -> Result<Vec<(DbId, usize)>, DbError> {
+        let tables = self.tables();
+
+        Ok(post_ids
+            .iter()
+            .map(|post_id| {
+                let count = tables
+                    .comments
+                    .values()
+                    .filter_map(Hideable::visible)
+                    .filter(|comment| {
+                        comment.referenced_post == *post_id && range.contains(comment.created_on)
+                    })
+                    .count();
+
+                (*post_id, count)
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect())
+    }
+
+    async fn save_comment(
+        &self,
+        author_id: &DbId,
+        referenced_post_id: &DbId,
+        content: &str,
+    ) -> Result<Option<Comment>, DbError> {
+        let (comment, notification) = {
+            let mut tables = self.tables();
+
+            if !tables.users.contains_key(author_id) {
+                return Err(DbError::Missing(format!("Author {}", **author_id)));
+            }
+            if !tables
+                .posts
+                .get(referenced_post_id)
+                .and_then(Hideable::visible)
+                .is_some_and(|post| post.state == PostState::Published)
+            {
+                return Ok(None);
+            }
+
+            let comment = Hideable::new(Comment {
+                comment_id: tables.next_id(),
+                referenced_post: *referenced_post_id,
+                author: *author_id,
+                created_on: OffsetDateTime::now_utc(),
+                content: content.to_string(),
+            });
+            let saved = (
+                comment.row.clone(),
+                comment_notification(&comment, Operation::Insert),
+            );
+            tables.comments.insert(comment.row.comment_id, comment);
+
+            saved
+        };
+
+        self.notify([notification]);
+
+        Ok(Some(comment))
+    }
+
+    async fn update_comment_content(
+        &self,
+        comment_id: &DbId,
+        content: &str,
+    ) -> Result<Option<Comment>, DbError> {
+        let (comment, notification) = {
+            let mut tables = self.tables();
+
+            let Some(comment) = tables
+                .comments
+                .get_mut(comment_id)
+                .filter(|comment| !comment.hidden)
+            else {
+                return Ok(None);
+            };
+            comment.row.content = content.to_string();
+
+            (
+                comment.row.clone(),
+                comment_notification(comment, Operation::Update),
+            )
+        };
+
+        self.notify([notification]);
+
+        Ok(Some(comment))
+    }
+
+    async fn hide_comment(&self, comment_id: &DbId) -> Result<(), DbError> {
+        let notification = self.tables().comments.get_mut(comment_id).map(|comment| {
+            comment.hidden = true;
+            comment_notification(comment, Operation::Update)
+        });
+
+        self.notify(notification);
+
+        Ok(())
+    }
+}
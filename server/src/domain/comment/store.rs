@@ -0,0 +1,39 @@
+use async_trait::async_trait;
+
+use crate::{
+    domain::{db_id::DbId, relay_meta::CreatedRange},
+    infrastructure::DbError,
+};
+
+use super::Comment;
+
+/// Where comments are kept. Hidden comments are never returned.
+#[async_trait]
+pub trait CommentStore: Send + Sync {
+    async fn visible_comments(&self, comment_ids: &[DbId]) -> Result<Vec<Comment>, DbError>;
+
+    async fn comments_of_posts(&self, post_ids: &[DbId]) -> Result<Vec<Comment>, DbError>;
+
+    /// Posts without comments in the range are left out.
+    async fn count_comments_of_posts(
+        &self,
+        post_ids: &[DbId],
+        range: CreatedRange,
+    ) -> Result<Vec<(DbId, usize)>, DbError>;
+
+    /// `None` if the post is not published or hidden.
+    async fn save_comment(
+        &self,
+        author_id: &DbId,
+        referenced_post_id: &DbId,
+        content: &str,
+    ) -> Result<Option<Comment>, DbError>;
+
+    async fn update_comment_content(
+        &self,
+        comment_id: &DbId,
+        content: &str,
+    ) -> Result<Option<Comment>, DbError>;
+
+    async fn hide_comment(&self, comment_id: &DbId) -> Result<(), DbError>;
+}
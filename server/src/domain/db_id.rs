@@ -1,6 +1,7 @@
 use async_graphql::{NewType, ID};
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
 use postgres_types::{FromSql, ToSql};
+use serde::Deserialize;
 use std::{
     ops::Deref,
     str::{from_utf8, FromStr},
@@ -8,8 +9,11 @@ use std::{
 
 use super::errors::MappingError;
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Hash, ToSql, FromSql, NewType)]
+#[derive(
+    Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Hash, ToSql, FromSql, NewType, Deserialize,
+)]
 #[postgres(transparent)]
+#[serde(transparent)]
 pub struct DbId(i32);
 
 impl Deref for DbId {
@@ -1,5 +1,10 @@
+use std::sync::Arc;
+
+use async_graphql::{indexmap::IndexMap, ErrorExtensions, Name, Pos, ServerError, Value};
 use thiserror::Error;
 
+use crate::infrastructure::DbError;
+
 #[derive(Debug, Error)]
 pub enum MappingError {
     #[error("Could not decode relay id: {0}")]
@@ -12,20 +17,135 @@ impl MappingError {
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum GqlError {
     #[error("Could not load from db")]
-    DbLoad,
+    DbLoad(#[source] Arc<DbError>),
     #[error("Could not save to db")]
-    DbSave,
+    DbSave(#[source] Arc<DbError>),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
     #[error("Could not access internal tooling")]
     InternalData(String),
     #[error("Invalid internal state: {0}")]
     InvalidState(String),
     #[error("Invalid request: {0}")]
     InvalidRequest(String),
+    #[error("Invalid input: {}", .0.iter().map(|v| format!("{} {}", v.field, v.message)).collect::<Vec<_>>().join(", "))]
+    InvalidInput(Vec<FieldViolation>),
+    #[error("Not found: {0}")]
+    NotFound(String),
     #[error("Other server returned error: {0}")]
     OtherServer(String),
+    // Apollo clients match on this message to resend the full query
+    #[error("PersistedQueryNotFound")]
+    PersistedQueryNotFound,
+    #[error("Rate limited: {0}")]
+    RateLimited(String),
+    #[error("Subscription overflowed: {0}")]
+    SubscriptionOverflow(String),
+    #[error("Not authenticated")]
+    Unauthenticated,
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldViolation {
+    pub field: String,
+    pub message: String,
+}
+
+impl GqlError {
+    pub fn db_load(e: impl Into<Arc<DbError>>) -> Self {
+        Self::DbLoad(e.into())
+    }
+
+    pub fn db_save(e: impl Into<Arc<DbError>>) -> Self {
+        Self::DbSave(e.into())
+    }
+
+    pub fn invalid_field(field: &str, message: impl ToString) -> Self {
+        Self::InvalidInput(vec![FieldViolation {
+            field: field.to_string(),
+            message: message.to_string(),
+        }])
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::DbLoad(_) | Self::DbSave(_) | Self::InternalData(_) | Self::InvalidState(_) => {
+                "INTERNAL"
+            }
+            Self::Forbidden(_) => "FORBIDDEN",
+            Self::InvalidRequest(_) | Self::InvalidInput(_) => "VALIDATION",
+            Self::NotFound(_) => "NOT_FOUND",
+            Self::OtherServer(_) => "UPSTREAM",
+            Self::PersistedQueryNotFound => "PERSISTED_QUERY_NOT_FOUND",
+            Self::RateLimited(_) => "RATE_LIMITED",
+            Self::SubscriptionOverflow(_) => "SUBSCRIPTION_OVERFLOW",
+            Self::Unauthenticated => "UNAUTHENTICATED",
+        }
+    }
+
+    // Details of these stay in the logs
+    pub fn is_internal(&self) -> bool {
+        matches!(self.code(), "INTERNAL" | "UPSTREAM")
+    }
+
+    pub fn public_message(&self) -> String {
+        match self.code() {
+            "INTERNAL" => "Internal server error".to_string(),
+            "UPSTREAM" => "Another service failed to respond".to_string(),
+            _ => self.to_string(),
+        }
+    }
+
+    // Display of the whole source chain, for logging
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = std::error::Error::source(self);
+
+        while let Some(e) = source {
+            report.push_str(": ");
+            report.push_str(&e.to_string());
+            source = e.source();
+        }
+
+        report
+    }
+}
+
+impl GqlError {
+    // For errors raised around execution, before there is a location to point at
+    pub fn into_server_error(self) -> ServerError {
+        let mut error = self.extend().into_server_error(Pos::default());
+        error.locations.clear();
+        error
+    }
+}
+
+impl ErrorExtensions for GqlError {
+    fn extend(&self) -> async_graphql::Error {
+        let mut error = async_graphql::Error::new(self.public_message());
+        error.source = Some(Arc::new(self.clone()));
+
+        error.extend_with(|_, e| {
+            e.set("code", self.code());
+
+            if let Self::InvalidInput(violations) = self {
+                let fields = violations
+                    .iter()
+                    .map(|v| {
+                        Value::Object(IndexMap::from([
+                            (Name::new("field"), Value::from(v.field.as_str())),
+                            (Name::new("message"), Value::from(v.message.as_str())),
+                        ]))
+                    })
+                    .collect::<Vec<_>>();
+
+                e.set("fields", fields);
+            }
+        })
+    }
 }
 
 impl From<reqwest::Error> for GqlError {
@@ -39,3 +159,31 @@ impl From<async_graphql::Error> for GqlError {
         Self::InternalData(value.message)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::ErrorExtensions;
+
+    use super::GqlError;
+
+    #[test]
+    fn internal_details_stay_private() {
+        let error = GqlError::InvalidState("secret detail".to_string());
+        let extended = error.extend();
+
+        assert_eq!("INTERNAL", error.code());
+        assert_eq!("Internal server error", extended.message);
+        assert!(error.report().contains("secret detail"));
+    }
+
+    #[test]
+    fn validation_keeps_field() {
+        let error = GqlError::invalid_field("reason", "must not be empty");
+
+        assert_eq!("VALIDATION", error.code());
+        assert_eq!(
+            "Invalid input: reason must not be empty",
+            error.extend().message
+        );
+    }
+}
@@ -1,7 +1,14 @@
 mod db;
 mod domain;
 mod graphql;
+mod loaders;
+#[cfg(test)]
+mod memory;
+mod store;
 
-pub use db::{PostLoader, PostsOfAuthorLoader};
 pub use domain::Post;
-pub use graphql::PostInput;
+#[cfg(test)]
+pub use domain::PostState;
+pub use graphql::{CreatePostPayload, EditPostInput, PostInput, PublishPostInput};
+pub use loaders::{PostCountOfAuthorLoader, PostLoader, PostsOfAuthorLoader};
+pub use store::PostStore;
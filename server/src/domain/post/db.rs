@@ -1,101 +1,220 @@
-use std::{collections::HashMap, sync::Arc};
-
-use async_graphql::dataloader::Loader;
+use async_trait::async_trait;
 use time::OffsetDateTime;
 use tokio_postgres::Row;
 use tracing::{instrument, Level};
 
 use crate::{
-    domain::db_id::DbId,
+    domain::{db_id::DbId, relay_meta::CreatedRange},
     infrastructure::{db::Repo, DbError},
 };
 
-use super::domain::Post;
-
-pub struct PostLoader {
-    repo: Repo,
-}
+use super::{
+    domain::{Post, PostState},
+    store::PostStore,
+};
 
-impl PostLoader {
-    pub fn new(repo: Repo) -> Self {
-        Self { repo }
+#[async_trait]
+impl PostStore for Repo {
+    #[instrument(skip(self), err)]
+    async fn published_posts(&self, post_ids: &[DbId]) -> Result<Vec<Post>, DbError> {
+        self.query(
+            r"
+                SELECT * FROM post
+                WHERE post_id = ANY($1) AND state = 'published' AND NOT hidden
+                ORDER BY created_on, post_id
+            ",
+            &[&post_ids],
+            |rows| rows.into_iter().map(|row| row.try_into()).collect(),
+        )
+        .await
     }
-}
 
-impl Loader<DbId> for PostLoader {
-    type Value = Post;
-    type Error = Arc<DbError>;
+    #[instrument(skip(self), err)]
+    async fn published_posts_of_authors(&self, author_ids: &[DbId]) -> Result<Vec<Post>, DbError> {
+        self.query(
+            "SELECT * FROM post WHERE author = ANY($1) AND state = 'published' AND NOT hidden",
+            &[&author_ids],
+            |rows| rows.into_iter().map(|row| row.try_into()).collect(),
+        )
+        .await
+    }
 
     #[instrument(skip(self), err)]
-    async fn load(&self, ids: &[DbId]) -> Result<HashMap<DbId, Self::Value>, Self::Error> {
-        self.repo
-            .query(
-                "SELECT * FROM post WHERE post_id = ANY($1)",
-                &[&ids],
-                |rows| {
-                    rows.into_iter()
-                        .map(|row| {
-                            let post: Post = row.try_into()?;
-                            Ok::<_, DbError>((post.post_id, post))
-                        })
-                        .collect::<Result<HashMap<_, _>, _>>()
-                },
-            )
-            .await
-            .map_err(|e| e.into())
+    async fn count_published_posts_of_authors(
+        &self,
+        author_ids: &[DbId],
+        range: CreatedRange,
+    ) -> Result<Vec<(DbId, usize)>, DbError> {
+        self.query(
+            r"
+                SELECT author, COUNT(*) FROM post
+                WHERE author = ANY($1) AND state = 'published' AND NOT hidden
+                    AND ($2::timestamptz IS NULL OR created_on >= $2)
+                    AND ($3::timestamptz IS NULL OR created_on < $3)
+                GROUP BY author
+            ",
+            &[&author_ids, &range.since, &range.until],
+            |rows| {
+                rows.into_iter()
+                    .map(|row| Ok((row.try_get(0)?, row.try_get::<_, i64>(1)? as usize)))
+                    .collect::<Result<_, tokio_postgres::Error>>()
+                    .map_err(DbError::mapping)
+            },
+        )
+        .await
     }
-}
 
-pub struct PostsOfAuthorLoader {
-    repo: Repo,
-}
+    #[instrument(skip(self), err)]
+    async fn save_post(
+        &self,
+        author_id: &DbId,
+        content: &str,
+        state: PostState,
+        scheduled_for: Option<OffsetDateTime>,
+    ) -> Result<Post, DbError> {
+        let now = OffsetDateTime::now_utc();
 
-impl PostsOfAuthorLoader {
-    pub fn new(repo: Repo) -> Self {
-        Self { repo }
+        self.query_one(
+            r"
+                INSERT INTO post (author, created_on, content, state, scheduled_for)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING *
+            ",
+            &[author_id, &now, &content, &state, &scheduled_for],
+            |row| row.try_into(),
+        )
+        .await
     }
-}
-
-impl Loader<DbId> for PostsOfAuthorLoader {
-    type Value = Vec<Post>;
-    type Error = Arc<DbError>;
 
     #[instrument(skip(self), err)]
-    async fn load(&self, ids: &[DbId]) -> Result<HashMap<DbId, Self::Value>, Self::Error> {
-        let posts: Vec<Post> = self
-            .repo
-            .query(
-                "SELECT * FROM post WHERE author = ANY($1)",
-                &[&ids],
-                |rows| rows.into_iter().map(|row| row.try_into()).collect(),
-            )
-            .await?;
+    async fn update_post_content(
+        &self,
+        post_id: &DbId,
+        content: &str,
+    ) -> Result<Option<Post>, DbError> {
+        self.query(
+            "UPDATE post SET content = $2 WHERE post_id = $1 AND NOT hidden RETURNING *",
+            &[post_id, &content],
+            |rows| rows.into_iter().next().map(Post::try_from).transpose(),
+        )
+        .await
+    }
 
-        let mut result = HashMap::from_iter(ids.iter().map(|id| (*id, Vec::new())));
+    #[instrument(skip(self), err)]
+    async fn feed_position(
+        &self,
+        post_id: &DbId,
+    ) -> Result<Option<(OffsetDateTime, DbId)>, DbError> {
+        self.query(
+            "SELECT created_on, post_id FROM post WHERE post_id = $1",
+            &[post_id],
+            |rows| {
+                rows.into_iter()
+                    .next()
+                    .map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+                    .transpose()
+                    .map_err(DbError::mapping)
+            },
+        )
+        .await
+    }
 
-        for post in posts {
-            result
-                .entry(post.author)
-                .and_modify(|e: &mut Vec<Post>| e.push(post));
-        }
+    #[instrument(skip(self), err)]
+    async fn published_posts_after(
+        &self,
+        author_ids: &[DbId],
+        after: (OffsetDateTime, DbId),
+        limit: i64,
+    ) -> Result<Vec<Post>, DbError> {
+        self.query(
+            r"
+                SELECT * FROM post
+                WHERE author = ANY($1) AND state = 'published' AND NOT hidden
+                    AND (created_on, post_id) > ($2, $3)
+                ORDER BY created_on, post_id
+                LIMIT $4
+            ",
+            &[&author_ids, &after.0, &after.1, &limit],
+            |rows| rows.into_iter().map(|row| row.try_into()).collect(),
+        )
+        .await
+    }
 
-        Ok(result)
+    #[instrument(skip(self), err)]
+    async fn unpublished_posts(&self, author_id: &DbId) -> Result<Vec<Post>, DbError> {
+        self.query(
+            r"
+                SELECT * FROM post
+                WHERE author = $1 AND state <> 'published' AND NOT hidden
+                ORDER BY created_on DESC
+            ",
+            &[author_id],
+            |rows| rows.into_iter().map(|row| row.try_into()).collect(),
+        )
+        .await
     }
-}
 
-impl Repo {
     #[instrument(skip(self), err)]
-    pub async fn save_post(&self, author_id: &DbId, content: &str) -> Result<Post, DbError> {
+    async fn publish_post(
+        &self,
+        author_id: &DbId,
+        post_id: &DbId,
+        scheduled_for: Option<OffsetDateTime>,
+    ) -> Result<Option<Post>, DbError> {
+        let state = match scheduled_for {
+            Some(_) => PostState::Scheduled,
+            None => PostState::Published,
+        };
         let now = OffsetDateTime::now_utc();
 
-        self.query_one(
+        self.query(
             r"
-                INSERT INTO post (author, created_on, content)
-                VALUES ($1, $2, $3)
+                UPDATE post
+                SET state = $3::post_state,
+                    scheduled_for = $4,
+                    created_on = CASE WHEN $3::post_state = 'published' THEN $5 ELSE created_on END
+                WHERE post_id = $1 AND author = $2 AND state <> 'published'
                 RETURNING *
             ",
-            &[author_id, &now, &content],
-            |row| row.try_into(),
+            &[post_id, author_id, &state, &scheduled_for, &now],
+            |rows| rows.into_iter().next().map(Post::try_from).transpose(),
+        )
+        .await
+    }
+
+    #[instrument(skip(self), err)]
+    async fn hide_post(&self, post_id: &DbId) -> Result<(), DbError> {
+        self.execute(
+            "UPDATE post SET hidden = true WHERE post_id = $1",
+            &[post_id],
+        )
+        .await
+    }
+
+    // Locked rows get skipped by other instances, so every post is published exactly once
+    #[instrument(skip(self), err)]
+    async fn publish_due_posts(&self, limit: i64) -> Result<Vec<DbId>, DbError> {
+        self.query(
+            r"
+                UPDATE post
+                SET state = 'published', created_on = now()
+                WHERE post_id IN (
+                    SELECT post_id FROM post
+                    JOIN app_user ON app_user.user_id = post.author
+                    WHERE state = 'scheduled' AND scheduled_for <= now()
+                        AND NOT app_user.suspended
+                    ORDER BY scheduled_for
+                    LIMIT $1
+                    FOR UPDATE OF post SKIP LOCKED
+                )
+                RETURNING post_id
+            ",
+            &[&limit],
+            |rows| {
+                rows.into_iter()
+                    .map(|row| row.try_get("post_id").map_err(DbError::mapping))
+                    .collect()
+            },
         )
         .await
     }
@@ -111,6 +230,245 @@ impl TryFrom<Row> for Post {
             author: value.try_get("author").map_err(DbError::mapping)?,
             created_on: value.try_get("created_on").map_err(DbError::mapping)?,
             content: value.try_get("content").map_err(DbError::mapping)?,
+            state: value.try_get("state").map_err(DbError::mapping)?,
+            scheduled_for: value.try_get("scheduled_for").map_err(DbError::mapping)?,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use time::{Duration, OffsetDateTime};
+
+    use crate::{
+        domain::post::domain::PostState,
+        infrastructure::testing::{next_data, TestApp, TestDb},
+    };
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn drafts_stay_private_until_published() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, alice_id) = app.user("Alice").await;
+
+        let data = app
+            .execute(
+                alice,
+                r#"mutation { createPost(input: { content: "Draft", draft: true }) { node { id state } } }"#,
+            )
+            .await;
+        assert_eq!("DRAFT", data["createPost"]["node"]["state"]);
+        let post_id = data["createPost"]["node"]["id"].as_str().unwrap();
+
+        let query = format!(
+            r#"{{
+                viewer {{ drafts {{ edges {{ node {{ content state }} }} }} }}
+                user(id: "{alice_id}") {{ posts {{ totalCount }} }}
+            }}"#
+        );
+        let data = app.execute(alice, &query).await;
+        assert_eq!(
+            json!({
+                "viewer": { "drafts": { "edges": [{ "node": { "content": "Draft", "state": "DRAFT" } }] } },
+                "user": { "posts": { "totalCount": 0 } },
+            }),
+            data
+        );
+
+        let query = format!(
+            r#"mutation {{ editPost(input: {{ post: "{post_id}", content: "Edited" }}) {{ content }} }}"#
+        );
+        app.execute(alice, &query).await;
+
+        let query = format!(
+            r#"mutation {{ publishPost(input: {{ post: "{post_id}" }}) {{ node {{ content state }} }} }}"#
+        );
+        let data = app.execute(alice, &query).await;
+        assert_eq!(
+            json!({ "content": "Edited", "state": "PUBLISHED" }),
+            data["publishPost"]["node"]
+        );
+
+        // Only unpublished posts get published
+        let response = app.try_execute(alice, &query).await;
+        assert!(!response.errors.is_empty());
+
+        let query = format!(
+            r#"{{
+                viewer {{ drafts {{ edges {{ node {{ id }} }} }} }}
+                user(id: "{alice_id}") {{ posts {{ totalCount edges {{ node {{ content }} }} }} }}
+            }}"#
+        );
+        let data = app.execute(alice, &query).await;
+        assert_eq!(
+            json!({
+                "viewer": { "drafts": { "edges": [] } },
+                "user": { "posts": { "totalCount": 1, "edges": [{ "node": { "content": "Edited" } }] } },
+            }),
+            data
+        );
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn due_posts_get_published() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, alice_id) = app.user("Alice").await;
+
+        let later = OffsetDateTime::now_utc() + Duration::hours(1);
+        let due = app
+            .storage
+            .save_post(
+                &alice,
+                "Due",
+                PostState::Scheduled,
+                Some(OffsetDateTime::now_utc() - Duration::seconds(1)),
+            )
+            .await
+            .unwrap();
+        app.storage
+            .save_post(&alice, "Later", PostState::Scheduled, Some(later))
+            .await
+            .unwrap();
+        let (suspended, _) = app.user("Suspended").await;
+        app.storage
+            .save_post(
+                &suspended,
+                "Withheld",
+                PostState::Scheduled,
+                Some(OffsetDateTime::now_utc() - Duration::seconds(1)),
+            )
+            .await
+            .unwrap();
+        app.storage.set_suspended(&suspended, true).await.unwrap();
+
+        let published = app.storage.publish_due_posts(10).await.unwrap();
+        assert_eq!(vec![due.post_id], published);
+        assert!(app.storage.publish_due_posts(10).await.unwrap().is_empty());
+
+        let query = format!(
+            r#"{{
+                viewer {{ drafts {{ edges {{ node {{ content state }} }} }} }}
+                user(id: "{alice_id}") {{ posts {{ edges {{ node {{ content state scheduledFor }} }} }} }}
+            }}"#
+        );
+        let data = app.execute(alice, &query).await;
+        assert_eq!(
+            json!({ "content": "Later", "state": "SCHEDULED" }),
+            data["viewer"]["drafts"]["edges"][0]["node"]
+        );
+        assert_eq!("Due", data["user"]["posts"]["edges"][0]["node"]["content"]);
+        assert_eq!(
+            "PUBLISHED",
+            data["user"]["posts"]["edges"][0]["node"]["state"]
+        );
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn posts_page_and_count_in_ranges() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, alice_id) = app.user("Alice").await;
+
+        for content in ["First", "Second", "Third"] {
+            app.post(alice, content).await;
+        }
+
+        let query = format!(
+            r#"{{
+                user(id: "{alice_id}") {{
+                    posts(first: 2) {{
+                        totalCount
+                        edges {{ node {{ content }} }}
+                        pageInfo {{ hasNextPage endCursor }}
+                    }}
+                }}
+            }}"#
+        );
+        let data = app.execute(alice, &query).await;
+        let posts = &data["user"]["posts"];
+        assert_eq!(3, posts["totalCount"]);
+        assert_eq!(
+            json!([{ "node": { "content": "Third" } }, { "node": { "content": "Second" } }]),
+            posts["edges"]
+        );
+        assert_eq!(true, posts["pageInfo"]["hasNextPage"]);
+
+        let cursor = posts["pageInfo"]["endCursor"].as_str().unwrap();
+        let query = format!(
+            r#"{{
+                user(id: "{alice_id}") {{
+                    posts(first: 2, after: "{cursor}") {{
+                        edges {{ node {{ content }} }}
+                        pageInfo {{ hasNextPage }}
+                    }}
+                }}
+            }}"#
+        );
+        let data = app.execute(alice, &query).await;
+        assert_eq!(
+            json!({ "edges": [{ "node": { "content": "First" } }], "pageInfo": { "hasNextPage": false } }),
+            data["user"]["posts"]
+        );
+
+        let query = format!(
+            r#"{{
+                user(id: "{alice_id}") {{
+                    past: posts(until: "2000-01-01T00:00:00Z") {{ totalCount }}
+                    recent: posts(since: "2000-01-01T00:00:00Z") {{ totalCount }}
+                }}
+            }}"#
+        );
+        let data = app.execute(alice, &query).await;
+        assert_eq!(
+            json!({ "past": { "totalCount": 0 }, "recent": { "totalCount": 3 } }),
+            data["user"]
+        );
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn feeds_get_published_posts_but_not_edits() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, alice_id) = app.user("Alice").await;
+        let post_id = app.post(alice, "Old").await;
+        app.storage
+            .save_post(
+                &alice,
+                "Scheduled",
+                PostState::Scheduled,
+                Some(OffsetDateTime::now_utc() - Duration::seconds(1)),
+            )
+            .await
+            .unwrap();
+
+        let query = format!(
+            r#"subscription {{ userFeed(userId: "{alice_id}") {{ node {{ content }} }} }}"#
+        );
+        let mut feed = app.subscribe(alice, &query).await;
+
+        let query = format!(
+            r#"mutation {{ editPost(input: {{ post: "{post_id}", content: "Edited" }}) {{ content }} }}"#
+        );
+        app.execute(alice, &query).await;
+        app.storage.publish_due_posts(10).await.unwrap();
+
+        assert_eq!(
+            json!({ "userFeed": [{ "node": { "content": "Scheduled" } }] }),
+            next_data(&mut feed).await
+        );
+
+        db.drop().await;
+    }
+}
@@ -1,4 +1,5 @@
-use async_graphql::ID;
+use async_graphql::{Enum, ID};
+use postgres_types::{FromSql, ToSql};
 use time::OffsetDateTime;
 
 use crate::domain::{
@@ -11,9 +12,11 @@ pub const SUFFIX: &str = "Post";
 #[derive(Clone)]
 pub struct Post {
     pub post_id: DbId,
-    pub(super) author: DbId,
+    pub(in crate::domain) author: DbId,
     pub(in crate::domain) created_on: OffsetDateTime,
     pub(super) content: String,
+    pub(in crate::domain) state: PostState,
+    pub(super) scheduled_for: Option<OffsetDateTime>,
 }
 
 impl HasDbId for Post {
@@ -27,3 +30,14 @@ impl CanDecodeId for Post {
         Self::decode_with_suffix(relay_id, SUFFIX)
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum, ToSql, FromSql)]
+#[postgres(name = "post_state")]
+pub enum PostState {
+    #[postgres(name = "draft")]
+    Draft,
+    #[postgres(name = "scheduled")]
+    Scheduled,
+    #[postgres(name = "published")]
+    Published,
+}
@@ -1,4 +1,7 @@
-use async_graphql::{Context, InputObject, Object, ID};
+use async_graphql::{
+    connection::{CursorType, Edge, EmptyFields},
+    Context, InputObject, Object, ID,
+};
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
 use time::OffsetDateTime;
 use tracing::instrument;
@@ -8,12 +11,15 @@ use crate::{
         app_user::AppUser,
         comment::Comment,
         errors::GqlError,
-        relay_meta::{paginate, AppConnection},
+        relay_meta::{
+            into_payload, paginate, selects_rows, AppConnection, AppCursor, ConnectionOrder,
+            CreatedRange, TotalCount, UserError,
+        },
     },
-    infrastructure::db::Loaders,
+    infrastructure::storage::Loaders,
 };
 
-use super::domain::{Post, SUFFIX};
+use super::domain::{Post, PostState, SUFFIX};
 
 #[Object]
 impl Post {
@@ -32,7 +38,7 @@ impl Post {
             .app_user
             .load_one(self.author)
             .await
-            .map_err(|_| GqlError::DbLoad)?
+            .map_err(GqlError::db_load)?
             .ok_or_else(|| GqlError::InvalidState("Expected author, got None".to_string()))
     }
 
@@ -44,6 +50,15 @@ impl Post {
         &self.content
     }
 
+    async fn state(&self) -> PostState {
+        self.state
+    }
+
+    async fn scheduled_for(&self) -> Option<OffsetDateTime> {
+        self.scheduled_for
+    }
+
+    #[allow(clippy::too_many_arguments)]
     #[instrument(skip_all, err)]
     #[graphql(
         complexity = "first.unwrap_or(0).try_into().unwrap_or(usize::MAX) * child_complexity 
@@ -56,17 +71,29 @@ impl Post {
         before: Option<String>,
         first: Option<i32>,
         last: Option<i32>,
+        #[graphql(default_with = "ConnectionOrder::CreatedOnAsc")] order_by: ConnectionOrder,
+        since: Option<OffsetDateTime>,
+        until: Option<OffsetDateTime>,
     ) -> Result<AppConnection<Comment>, GqlError> {
         let loaders = ctx.data::<Loaders>()?;
 
-        let comments = loaders
-            .comments_of_post
-            .load_one(self.post_id)
-            .await
-            .map_err(|_| GqlError::DbLoad)?
-            .ok_or_else(|| GqlError::InvalidState("Expected empty vec, got None".to_string()))?;
+        let range = CreatedRange::new(since, until);
+
+        let comments = if selects_rows(ctx) {
+            loaders
+                .comments_of_post
+                .load_one(self.post_id)
+                .await
+                .map_err(GqlError::db_load)?
+                .ok_or_else(|| GqlError::InvalidState("Expected empty vec, got None".to_string()))?
+        } else {
+            Vec::new()
+        };
 
-        let connection = paginate(after, before, first, last, comments).await?;
+        let comments = range.arrange(comments, order_by, |c| c.created_on);
+        let total_count = TotalCount::CommentsOfPost(self.post_id, range);
+
+        let connection = paginate(after, before, first, last, comments, total_count).await?;
 
         Ok(connection)
     }
@@ -74,5 +101,80 @@ impl Post {
 
 #[derive(Debug, InputObject)]
 pub struct PostInput {
+    pub(in crate::domain) client_mutation_id: Option<String>,
+    pub(in crate::domain) content: String,
+    #[graphql(default)]
+    pub(in crate::domain) draft: bool,
+    pub(in crate::domain) scheduled_for: Option<OffsetDateTime>,
+}
+
+impl PostInput {
+    pub(in crate::domain) fn state(&self) -> PostState {
+        match (self.scheduled_for, self.draft) {
+            (Some(_), _) => PostState::Scheduled,
+            (None, true) => PostState::Draft,
+            (None, false) => PostState::Published,
+        }
+    }
+}
+
+#[derive(Debug, InputObject)]
+pub struct EditPostInput {
+    pub(in crate::domain) post: ID,
     pub(in crate::domain) content: String,
 }
+
+#[derive(Debug, InputObject)]
+pub struct PublishPostInput {
+    pub(in crate::domain) post: ID,
+    pub(in crate::domain) scheduled_for: Option<OffsetDateTime>,
+}
+
+pub struct CreatePostPayload {
+    client_mutation_id: Option<String>,
+    post: Option<Post>,
+    user_errors: Vec<UserError>,
+}
+
+impl CreatePostPayload {
+    pub fn new(
+        client_mutation_id: Option<String>,
+        result: Result<Post, GqlError>,
+    ) -> Result<Self, GqlError> {
+        let (post, user_errors) = into_payload(result)?;
+
+        Ok(Self {
+            client_mutation_id,
+            post,
+            user_errors,
+        })
+    }
+}
+
+#[Object]
+impl CreatePostPayload {
+    async fn client_mutation_id(&self) -> Option<&str> {
+        self.client_mutation_id.as_deref()
+    }
+
+    async fn node(&self) -> Option<&Post> {
+        self.post.as_ref()
+    }
+
+    async fn edge(&self) -> Option<Edge<AppCursor, Post, EmptyFields>> {
+        self.post
+            .clone()
+            .map(|post| Edge::new(AppCursor(post.post_id), post))
+    }
+
+    async fn user_errors(&self) -> &Vec<UserError> {
+        &self.user_errors
+    }
+
+    #[graphql(deprecation = "Use `edge.cursor` instead")]
+    async fn cursor(&self) -> Option<String> {
+        self.post
+            .as_ref()
+            .map(|post| AppCursor(post.post_id).encode_cursor())
+    }
+}
@@ -0,0 +1,103 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_graphql::dataloader::Loader;
+use tracing::instrument;
+
+use crate::{
+    domain::{
+        db_id::DbId,
+        relay_meta::{by_range, CountKey},
+    },
+    infrastructure::{storage::Storage, DbError},
+};
+
+use super::domain::Post;
+
+pub struct PostLoader {
+    storage: Storage,
+}
+
+impl PostLoader {
+    pub fn new(storage: Storage) -> Self {
+        Self { storage }
+    }
+}
+
+impl Loader<DbId> for PostLoader {
+    type Value = Post;
+    type Error = Arc<DbError>;
+
+    #[instrument(skip(self), err)]
+    async fn load(&self, ids: &[DbId]) -> Result<HashMap<DbId, Self::Value>, Self::Error> {
+        let posts = self.storage.published_posts(ids).await?;
+
+        Ok(posts.into_iter().map(|post| (post.post_id, post)).collect())
+    }
+}
+
+pub struct PostsOfAuthorLoader {
+    storage: Storage,
+}
+
+impl PostsOfAuthorLoader {
+    pub fn new(storage: Storage) -> Self {
+        Self { storage }
+    }
+}
+
+impl Loader<DbId> for PostsOfAuthorLoader {
+    type Value = Vec<Post>;
+    type Error = Arc<DbError>;
+
+    #[instrument(skip(self), err)]
+    async fn load(&self, ids: &[DbId]) -> Result<HashMap<DbId, Self::Value>, Self::Error> {
+        let posts = self.storage.published_posts_of_authors(ids).await?;
+
+        let mut result = HashMap::from_iter(ids.iter().map(|id| (*id, Vec::new())));
+
+        for post in posts {
+            result
+                .entry(post.author)
+                .and_modify(|e: &mut Vec<Post>| e.push(post));
+        }
+
+        Ok(result)
+    }
+}
+
+pub struct PostCountOfAuthorLoader {
+    storage: Storage,
+}
+
+impl PostCountOfAuthorLoader {
+    pub fn new(storage: Storage) -> Self {
+        Self { storage }
+    }
+}
+
+impl Loader<CountKey> for PostCountOfAuthorLoader {
+    type Value = usize;
+    type Error = Arc<DbError>;
+
+    #[instrument(skip(self), err)]
+    async fn load(&self, keys: &[CountKey]) -> Result<HashMap<CountKey, Self::Value>, Self::Error> {
+        let mut result = HashMap::new();
+
+        // Usually every key of a batch shares the same range, so this is a single query
+        for (range, authors) in by_range(keys) {
+            let counts = self
+                .storage
+                .count_published_posts_of_authors(&authors, range)
+                .await?;
+
+            result.extend(authors.iter().map(|author| ((*author, range), 0)));
+            result.extend(
+                counts
+                    .into_iter()
+                    .map(|(author, count)| ((author, range), count)),
+            );
+        }
+
+        Ok(result)
+    }
+}
@@ -0,0 +1,277 @@
+use async_trait::async_trait;
+use time::OffsetDateTime;
+
+use crate::{
+    domain::{db_id::DbId, relay_meta::CreatedRange},
+    infrastructure::{
+        memory::{Hideable, MemoryStore},
+        notification_center::{Notification, Operation, PostNotification},
+        DbError,
+    },
+};
+
+use super::{
+    domain::{Post, PostState},
+    store::PostStore,
+};
+
+// Like the trigger, which only tells about published posts
+fn post_notification(post: &Hideable<Post>, operation: Operation) -> Option<Notification> {
+    (post.row.state == PostState::Published).then_some(Notification::Post(PostNotification {
+        author_id: post.row.author,
+        post_id: post.row.post_id,
+        operation,
+        hidden: post.hidden,
+    }))
+}
+
+fn published(post: &Hideable<Post>) -> Option<&Post> {
+    post.visible()
+        .filter(|post| post.state == PostState::Published)
+}
+
+#[async_trait]
+impl PostStore for MemoryStore {
+    async fn published_posts(&self, post_ids: &[DbId]) -> Result<Vec<Post>, DbError> {
+        let mut posts: Vec<Post> = self
+            .tables()
+            .posts
+            .values()
+            .filter_map(published)
+            .filter(|post| post_ids.contains(&post.post_id))
+            .cloned()
+            .collect();
+        posts.sort_by_key(|post| (post.created_on, post.post_id));
+
+        Ok(posts)
+    }
+
+    async fn published_posts_of_authors(&self, author_ids: &[DbId]) -> Result<Vec<Post>, DbError> {
+        Ok(self
+            .tables()
+            .posts
+            .values()
+            .filter_map(published)
+            .filter(|post| author_ids.contains(&post.author))
+            .cloned()
+            .collect())
+    }
+
+    async fn count_published_posts_of_authors(
+        &self,
+        author_ids: &[DbId],
+        range: CreatedRange,
+    ) -> Result<Vec<(DbId, usize)>, DbError> {
+        let tables = self.tables();
+
+        Ok(author_ids
+            .iter()
+            .map(|author_id| {
+                let count = tables
+                    .posts
+                    .values()
+                    .filter_map(published)
+                    .filter(|post| post.author == *author_id && range.contains(post.created_on))
+                    .count();
+
+                (*author_id, count)
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect())
+    }
+
+    async fn save_post(
+        &self,
+        author_id: &DbId,
+        content: &str,
+        state: PostState,
+        scheduled_for: Option<OffsetDateTime>,
+    ) -> Result<Post, DbError> {
+        let (post, notification) = {
+            let mut tables = self.tables();
+
+            if !tables.users.contains_key(author_id) {
+                return Err(DbError::Missing(format!("Author {}", **author_id)));
+            }
+
+            let post = Hideable::new(Post {
+                post_id: tables.next_id(),
+                author: *author_id,
+                created_on: OffsetDateTime::now_utc(),
+                content: content.to_string(),
+                state,
+                scheduled_for,
+            });
+            let saved = (
+                post.row.clone(),
+                post_notification(&post, Operation::Insert),
+            );
+            tables.posts.insert(post.row.post_id, post);
+
+            saved
+        };
+
+        self.notify(notification);
+
+        Ok(post)
+    }
+
+    async fn update_post_content(
+        &self,
+        post_id: &DbId,
+        content: &str,
+    ) -> Result<Option<Post>, DbError> {
+        let (post, notification) = {
+            let mut tables = self.tables();
+
+            let Some(post) = tables.posts.get_mut(post_id).filter(|post| !post.hidden) else {
+                return Ok(None);
+            };
+            post.row.content = content.to_string();
+
+            (post.row.clone(), post_notification(post, Operation::Update))
+        };
+
+        self.notify(notification);
+
+        Ok(Some(post))
+    }
+
+    async fn feed_position(
+        &self,
+        post_id: &DbId,
+    ) -> Result<Option<(OffsetDateTime, DbId)>, DbError> {
+        Ok(self
+            .tables()
+            .posts
+            .get(post_id)
+            .map(|post| (post.row.created_on, post.row.post_id)))
+    }
+
+    async fn published_posts_after(
+        &self,
+        author_ids: &[DbId],
+        after: (OffsetDateTime, DbId),
+        limit: i64,
+    ) -> Result<Vec<Post>, DbError> {
+        let mut posts: Vec<Post> = self
+            .tables()
+            .posts
+            .values()
+            .filter_map(published)
+            .filter(|post| {
+                author_ids.contains(&post.author) && (post.created_on, post.post_id) > after
+            })
+            .cloned()
+            .collect();
+        posts.sort_by_key(|post| (post.created_on, post.post_id));
+        posts.truncate(limit as usize);
+
+        Ok(posts)
+    }
+
+    async fn unpublished_posts(&self, author_id: &DbId) -> Result<Vec<Post>, DbError> {
+        let mut posts: Vec<Post> = self
+            .tables()
+            .posts
+            .values()
+            .filter_map(Hideable::visible)
+            .filter(|post| post.author == *author_id && post.state != PostState::Published)
+            .cloned()
+            .collect();
+        posts.sort_by_key(|post| post.created_on);
+        posts.reverse();
+
+        Ok(posts)
+    }
+
+    async fn publish_post(
+        &self,
+        author_id: &DbId,
+        post_id: &DbId,
+        scheduled_for: Option<OffsetDateTime>,
+    ) -> Result<Option<Post>, DbError> {
+        let (post, notification) = {
+            let mut tables = self.tables();
+
+            let Some(post) = tables.posts.get_mut(post_id).filter(|post| {
+                post.row.author == *author_id && post.row.state != PostState::Published
+            }) else {
+                return Ok(None);
+            };
+
+            post.row.scheduled_for = scheduled_for;
+            post.row.state = match scheduled_for {
+                Some(_) => PostState::Scheduled,
+                None => {
+                    post.row.created_on = OffsetDateTime::now_utc();
+                    PostState::Published
+                }
+            };
+
+            (
+                post.row.clone(),
+                post_notification(post, Operation::Publish),
+            )
+        };
+
+        self.notify(notification);
+
+        Ok(Some(post))
+    }
+
+    async fn hide_post(&self, post_id: &DbId) -> Result<(), DbError> {
+        let notification = self.tables().posts.get_mut(post_id).and_then(|post| {
+            post.hidden = true;
+            post_notification(post, Operation::Update)
+        });
+
+        self.notify(notification);
+
+        Ok(())
+    }
+
+    async fn publish_due_posts(&self, limit: i64) -> Result<Vec<DbId>, DbError> {
+        let now = OffsetDateTime::now_utc();
+
+        let notifications: Vec<Notification> = {
+            let mut tables = self.tables();
+            let tables = &mut *tables;
+
+            let mut due: Vec<&mut Hideable<Post>> = tables
+                .posts
+                .values_mut()
+                .filter(|post| {
+                    post.row.state == PostState::Scheduled
+                        && post.row.scheduled_for.is_some_and(|at| at <= now)
+                        && tables
+                            .users
+                            .get(&post.row.author)
+                            .is_some_and(|author| !author.suspended)
+                })
+                .collect();
+            due.sort_by_key(|post| post.row.scheduled_for);
+            due.truncate(limit as usize);
+
+            due.into_iter()
+                .filter_map(|post| {
+                    post.row.state = PostState::Published;
+                    post.row.created_on = now;
+                    post_notification(post, Operation::Publish)
+                })
+                .collect()
+        };
+
+        let published = notifications
+            .iter()
+            .filter_map(|notification| match notification {
+                Notification::Post(post) => Some(post.post_id),
+                _ => None,
+            })
+            .collect();
+
+        self.notify(notifications);
+
+        Ok(published)
+    }
+}
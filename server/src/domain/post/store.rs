@@ -0,0 +1,70 @@
+use async_trait::async_trait;
+use time::OffsetDateTime;
+
+use crate::{
+    domain::{db_id::DbId, relay_meta::CreatedRange},
+    infrastructure::DbError,
+};
+
+use super::domain::{Post, PostState};
+
+/// Where posts are kept. Hidden posts are only ever returned to be published.
+#[async_trait]
+pub trait PostStore: Send + Sync {
+    /// Oldest first, the order feeds deliver them in.
+    async fn published_posts(&self, post_ids: &[DbId]) -> Result<Vec<Post>, DbError>;
+
+    async fn published_posts_of_authors(&self, author_ids: &[DbId]) -> Result<Vec<Post>, DbError>;
+
+    /// Authors without posts in the range are left out.
+    async fn count_published_posts_of_authors(
+        &self,
+        author_ids: &[DbId],
+        range: CreatedRange,
+    ) -> Result<Vec<(DbId, usize)>, DbError>;
+
+    async fn save_post(
+        &self,
+        author_id: &DbId,
+        content: &str,
+        state: PostState,
+        scheduled_for: Option<OffsetDateTime>,
+    ) -> Result<Post, DbError>;
+
+    async fn update_post_content(
+        &self,
+        post_id: &DbId,
+        content: &str,
+    ) -> Result<Option<Post>, DbError>;
+
+    /// Where a post sits in a feed, `None` if it does not exist.
+    async fn feed_position(
+        &self,
+        post_id: &DbId,
+    ) -> Result<Option<(OffsetDateTime, DbId)>, DbError>;
+
+    /// Published posts of the authors that come after `after` in a feed, oldest first.
+    async fn published_posts_after(
+        &self,
+        author_ids: &[DbId],
+        after: (OffsetDateTime, DbId),
+        limit: i64,
+    ) -> Result<Vec<Post>, DbError>;
+
+    /// Drafts and scheduled posts, newest first.
+    async fn unpublished_posts(&self, author_id: &DbId) -> Result<Vec<Post>, DbError>;
+
+    /// Publishes now or schedules, `None` if the author has no such unpublished post.
+    async fn publish_post(
+        &self,
+        author_id: &DbId,
+        post_id: &DbId,
+        scheduled_for: Option<OffsetDateTime>,
+    ) -> Result<Option<Post>, DbError>;
+
+    async fn hide_post(&self, post_id: &DbId) -> Result<(), DbError>;
+
+    /// Publishes up to `limit` posts whose time has come, returning their ids. Posts of
+    /// suspended authors wait until they are reinstated.
+    async fn publish_due_posts(&self, limit: i64) -> Result<Vec<DbId>, DbError>;
+}
@@ -1,19 +1,25 @@
-use std::fmt::Display;
+use std::{collections::HashMap, fmt::Display};
 
 use async_graphql::{
     connection::{
         query, Connection, CursorType, DefaultConnectionName, DefaultEdgeName, DisableNodesField,
         Edge, EmptyFields,
     },
-    Error, Interface, OutputType, ID,
+    Context, Enum, Error, Interface, Object, OutputType, SimpleObject, ID,
 };
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use futures::try_join;
+use postgres_types::{FromSql, ToSql};
+use time::OffsetDateTime;
 use tracing::instrument;
 
+use crate::infrastructure::storage::Loaders;
+
 use super::{
     app_user::AppUser,
     comment::Comment,
-    db_id::{DbId, HasDbId},
+    db_id::{CanDecodeId, DbId, HasDbId},
+    errors::{GqlError, MappingError},
     post::Post,
 };
 
@@ -25,6 +31,164 @@ pub enum Node {
     Post(Post),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ToSql, FromSql)]
+#[postgres(name = "node_type")]
+pub enum NodeType {
+    #[postgres(name = "app_user")]
+    AppUser,
+    #[postgres(name = "comment")]
+    Comment,
+    #[postgres(name = "post")]
+    Post,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeId {
+    AppUser(DbId),
+    Comment(DbId),
+    Post(DbId),
+}
+
+impl NodeId {
+    pub fn new(node_type: NodeType, id: DbId) -> Self {
+        match node_type {
+            NodeType::AppUser => Self::AppUser(id),
+            NodeType::Comment => Self::Comment(id),
+            NodeType::Post => Self::Post(id),
+        }
+    }
+
+    pub fn decode(relay_id: &ID) -> Result<Self, MappingError> {
+        if let Ok(id) = AppUser::decode(relay_id) {
+            return Ok(Self::AppUser(id));
+        }
+
+        if let Ok(id) = Comment::decode(relay_id) {
+            return Ok(Self::Comment(id));
+        }
+
+        if let Ok(id) = Post::decode(relay_id) {
+            return Ok(Self::Post(id));
+        }
+
+        Err(MappingError::DecodeRelayId(
+            "Given id did not match any available types".to_string(),
+        ))
+    }
+
+    pub fn node_type(&self) -> NodeType {
+        match self {
+            Self::AppUser(_) => NodeType::AppUser,
+            Self::Comment(_) => NodeType::Comment,
+            Self::Post(_) => NodeType::Post,
+        }
+    }
+
+    pub fn db_id(&self) -> DbId {
+        match self {
+            Self::AppUser(id) | Self::Comment(id) | Self::Post(id) => *id,
+        }
+    }
+
+    pub async fn load(&self, loaders: &Loaders) -> Result<Option<Node>, GqlError> {
+        let node = match *self {
+            Self::AppUser(id) => loaders
+                .app_user
+                .load_one(id)
+                .await
+                .map_err(GqlError::db_load)?
+                .map(Node::AppUser),
+            Self::Comment(id) => loaders
+                .comment
+                .load_one(id)
+                .await
+                .map_err(GqlError::db_load)?
+                .map(Node::Comment),
+            Self::Post(id) => loaders
+                .post
+                .load_one(id)
+                .await
+                .map_err(GqlError::db_load)?
+                .map(Node::Post),
+        };
+
+        Ok(node)
+    }
+
+    /// Loads several nodes at once, batching each type through its loader.
+    /// The result keeps the order of `ids`, missing nodes are `None`.
+    pub async fn load_many(ids: &[Self], loaders: &Loaders) -> Result<Vec<Option<Node>>, GqlError> {
+        let ids_of = |node_type| {
+            ids.iter()
+                .filter(move |id| id.node_type() == node_type)
+                .map(|id| id.db_id())
+        };
+
+        let (users, comments, posts) = try_join!(
+            loaders.app_user.load_many(ids_of(NodeType::AppUser)),
+            loaders.comment.load_many(ids_of(NodeType::Comment)),
+            loaders.post.load_many(ids_of(NodeType::Post)),
+        )
+        .map_err(GqlError::db_load)?;
+
+        let nodes = ids
+            .iter()
+            .map(|id| match id {
+                Self::AppUser(id) => users.get(id).cloned().map(Node::AppUser),
+                Self::Comment(id) => comments.get(id).cloned().map(Node::Comment),
+                Self::Post(id) => posts.get(id).cloned().map(Node::Post),
+            })
+            .collect();
+
+        Ok(nodes)
+    }
+}
+
+/// Mistakes in the input of a mutation, which the user is able to fix.
+#[derive(Debug, SimpleObject)]
+pub struct UserError {
+    field: Option<String>,
+    message: String,
+    code: UserErrorCode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum UserErrorCode {
+    NotFound,
+    Validation,
+}
+
+/// Splits the result of a mutation into what its payload carries.
+/// Errors the user can not fix fail the whole mutation instead.
+pub fn into_payload<T>(
+    result: Result<T, GqlError>,
+) -> Result<(Option<T>, Vec<UserError>), GqlError> {
+    match result {
+        Ok(node) => Ok((Some(node), Vec::new())),
+        Err(error) => Ok((None, user_errors(error)?)),
+    }
+}
+
+fn user_errors(error: GqlError) -> Result<Vec<UserError>, GqlError> {
+    let user_error = |field: Option<String>, message: String, code| UserError {
+        field,
+        message,
+        code,
+    };
+
+    match error {
+        GqlError::InvalidInput(violations) => Ok(violations
+            .into_iter()
+            .map(|v| user_error(Some(v.field), v.message, UserErrorCode::Validation))
+            .collect()),
+        GqlError::InvalidRequest(message) => {
+            Ok(vec![user_error(None, message, UserErrorCode::Validation)])
+        }
+        GqlError::NotFound(message) => Ok(vec![user_error(None, message, UserErrorCode::NotFound)]),
+        other => Err(other),
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct AppCursor(pub DbId);
 
@@ -62,13 +226,115 @@ impl CursorType for AppCursor {
 pub type AppConnection<T> = Connection<
     AppCursor,
     T,
-    EmptyFields,
+    ConnectionFields,
     EmptyFields,
     DefaultConnectionName,
     DefaultEdgeName,
     DisableNodesField,
 >;
 
+/// Order of connections over content, newest first is what feeds want.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum ConnectionOrder {
+    CreatedOnAsc,
+    CreatedOnDesc,
+}
+
+/// Restricts a connection to what was created from `since` (inclusive) to `until` (exclusive).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CreatedRange {
+    pub since: Option<OffsetDateTime>,
+    pub until: Option<OffsetDateTime>,
+}
+
+impl CreatedRange {
+    pub fn new(since: Option<OffsetDateTime>, until: Option<OffsetDateTime>) -> Self {
+        Self { since, until }
+    }
+
+    pub fn contains(&self, created_on: OffsetDateTime) -> bool {
+        self.since.iter().all(|since| *since <= created_on)
+            && self.until.iter().all(|until| created_on < *until)
+    }
+
+    /// Filters and sorts loaded content. Ties are broken by id, so cursors stay stable.
+    pub fn arrange<T: HasDbId>(
+        &self,
+        mut results: Vec<T>,
+        order: ConnectionOrder,
+        created_on: impl Fn(&T) -> OffsetDateTime,
+    ) -> Vec<T> {
+        results.retain(|x| self.contains(created_on(x)));
+        results.sort_unstable_by_key(|x| (created_on(x), x.db_id()));
+
+        if order == ConnectionOrder::CreatedOnDesc {
+            results.reverse();
+        }
+
+        results
+    }
+}
+
+/// Counts of connections are cached per owner and filter.
+pub type CountKey = (DbId, CreatedRange);
+
+/// Groups the keys of a count loader, so every range can be counted in one query.
+pub fn by_range(keys: &[CountKey]) -> HashMap<CreatedRange, Vec<DbId>> {
+    let mut grouped: HashMap<CreatedRange, Vec<DbId>> = HashMap::new();
+
+    for (id, range) in keys {
+        grouped.entry(*range).or_default().push(*id);
+    }
+
+    grouped
+}
+
+/// How many items a connection has in total, counted by the database only if asked for.
+#[derive(Debug)]
+pub enum TotalCount {
+    Known(usize),
+    PostsOfAuthors(Vec<DbId>, CreatedRange),
+    CommentsOfPost(DbId, CreatedRange),
+}
+
+pub struct ConnectionFields {
+    total_count: TotalCount,
+}
+
+#[Object]
+impl ConnectionFields {
+    #[instrument(skip_all, err)]
+    async fn total_count(&self, ctx: &Context<'_>) -> Result<usize, GqlError> {
+        let loaders = ctx.data::<Loaders>()?;
+
+        match &self.total_count {
+            TotalCount::Known(count) => Ok(*count),
+            TotalCount::PostsOfAuthors(authors, range) => {
+                let counts = loaders
+                    .post_count_of_author
+                    .load_many(authors.iter().map(|author| (*author, *range)))
+                    .await
+                    .map_err(GqlError::db_load)?;
+
+                Ok(counts.into_values().sum())
+            }
+            TotalCount::CommentsOfPost(post, range) => loaders
+                .comment_count_of_post
+                .load_one((*post, *range))
+                .await
+                .map_err(GqlError::db_load)
+                .map(Option::unwrap_or_default),
+        }
+    }
+}
+
+/// Connections only need their rows if more than the total count is selected.
+pub fn selects_rows(ctx: &Context<'_>) -> bool {
+    let connection = ctx.look_ahead();
+
+    connection.field("edges").exists() || connection.field("pageInfo").exists()
+}
+
 /* This is needlessly async ("query") and forces the user to load everything.
 Maybe benchmark if this is more/less performant than two roundtrips (one to get count). */
 #[instrument(skip(results), err(Debug))]
@@ -78,7 +344,10 @@ pub async fn paginate<T: OutputType + HasDbId>(
     first: Option<i32>,
     last: Option<i32>,
     mut results: Vec<T>,
-) -> Result<AppConnection<T>, Error> {
+    total_count: TotalCount,
+) -> Result<AppConnection<T>, GqlError> {
+    let fields = ConnectionFields { total_count };
+
     query(
         after,
         before,
@@ -95,7 +364,7 @@ pub async fn paginate<T: OutputType + HasDbId>(
 
             let (start, end) = match determine_range(after, before, first, last, results_len) {
                 Some(val) => val,
-                None => return Ok(Connection::new(false, false)),
+                None => return Ok(Connection::with_additional_fields(false, false, fields)),
             };
 
             if start > end {
@@ -104,7 +373,8 @@ pub async fn paginate<T: OutputType + HasDbId>(
 
             let slice: Vec<_> = results.drain(start..=end).collect();
 
-            let mut connection: AppConnection<T> = Connection::new(start > 0, end < results.len());
+            let mut connection: AppConnection<T> =
+                Connection::with_additional_fields(start > 0, end + 1 < results_len, fields);
 
             connection.edges.extend(
                 slice
@@ -116,6 +386,7 @@ pub async fn paginate<T: OutputType + HasDbId>(
         },
     )
     .await
+    .map_err(|e| GqlError::InvalidRequest(e.message))
 }
 
 fn determine_range(
@@ -169,11 +440,62 @@ fn determine_range(
 
 #[cfg(test)]
 mod tests {
-    use async_graphql::connection::CursorType;
+    use async_graphql::{connection::CursorType, SimpleObject};
+
+    use time::OffsetDateTime;
 
-    use crate::domain::{db_id::DbId, relay_meta::AppCursor};
+    use crate::domain::{
+        db_id::{DbId, HasDbId},
+        relay_meta::AppCursor,
+    };
 
-    use super::determine_range;
+    use super::{determine_range, paginate, ConnectionOrder, CreatedRange, TotalCount};
+
+    #[derive(SimpleObject)]
+    struct Item {
+        id: DbId,
+    }
+
+    impl HasDbId for Item {
+        fn db_id(&self) -> DbId {
+            self.id
+        }
+    }
+
+    fn items(count: i32) -> Vec<Item> {
+        (0..count).map(|id| Item { id: DbId::from(id) }).collect()
+    }
+
+    // More rows after the page than in it
+    #[tokio::test]
+    async fn has_next_page_with_more_left_than_taken() {
+        let connection = paginate(None, None, Some(6), None, items(10), TotalCount::Known(10))
+            .await
+            .unwrap();
+
+        assert_eq!(6, connection.edges.len());
+        assert!(connection.has_next_page);
+        assert!(!connection.has_previous_page);
+    }
+
+    #[tokio::test]
+    async fn has_no_next_page_on_the_last_page() {
+        let after = AppCursor(DbId::from(5)).encode_cursor();
+        let connection = paginate(
+            Some(after),
+            None,
+            Some(6),
+            None,
+            items(10),
+            TotalCount::Known(10),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(4, connection.edges.len());
+        assert!(!connection.has_next_page);
+        assert!(connection.has_previous_page);
+    }
 
     #[test]
     fn encode() {
@@ -281,4 +603,53 @@ mod tests {
         let result = determine_range(None, None, None, None, array.len());
         assert!(result.is_none());
     }
+
+    struct Created(i32, i64);
+
+    impl HasDbId for Created {
+        fn db_id(&self) -> DbId {
+            DbId::from(self.0)
+        }
+    }
+
+    fn arranged(range: CreatedRange, order: ConnectionOrder) -> Vec<i32> {
+        let results = vec![
+            Created(3, 20),
+            Created(1, 10),
+            Created(4, 30),
+            Created(2, 20),
+        ];
+
+        range
+            .arrange(results, order, |x| {
+                OffsetDateTime::from_unix_timestamp(x.1).unwrap()
+            })
+            .into_iter()
+            .map(|x| x.0)
+            .collect()
+    }
+
+    #[test]
+    fn arrange_orders_with_ties_by_id() {
+        let range = CreatedRange::default();
+
+        assert_eq!(
+            vec![1, 2, 3, 4],
+            arranged(range, ConnectionOrder::CreatedOnAsc)
+        );
+        assert_eq!(
+            vec![4, 3, 2, 1],
+            arranged(range, ConnectionOrder::CreatedOnDesc)
+        );
+    }
+
+    #[test]
+    fn arrange_filters_since_inclusive_until_exclusive() {
+        let range = CreatedRange::new(
+            Some(OffsetDateTime::from_unix_timestamp(20).unwrap()),
+            Some(OffsetDateTime::from_unix_timestamp(30).unwrap()),
+        );
+
+        assert_eq!(vec![2, 3], arranged(range, ConnectionOrder::CreatedOnAsc));
+    }
 }
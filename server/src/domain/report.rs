@@ -0,0 +1,10 @@
+mod db;
+mod domain;
+mod graphql;
+#[cfg(test)]
+mod memory;
+mod store;
+
+pub use domain::Report;
+pub use graphql::{ReportContentInput, ResolveReportInput};
+pub use store::ReportStore;
@@ -0,0 +1,338 @@
+use async_trait::async_trait;
+use time::OffsetDateTime;
+use tokio_postgres::Row;
+use tracing::{info, instrument, Level};
+
+use crate::{
+    domain::{
+        app_user::UserStore,
+        comment::CommentStore,
+        db_id::DbId,
+        post::PostStore,
+        relay_meta::{NodeId, NodeType},
+    },
+    infrastructure::{
+        db::{Isolation, Repo},
+        DbError,
+    },
+};
+
+use super::{
+    domain::{ModerationAction, Report},
+    store::ReportStore,
+};
+
+#[async_trait]
+impl ReportStore for Repo {
+    #[instrument(skip(self), err)]
+    async fn save_report(
+        &self,
+        reporter: &DbId,
+        target: &NodeId,
+        reason: &str,
+    ) -> Result<Report, DbError> {
+        let now = OffsetDateTime::now_utc();
+
+        self.query_one(
+            r"
+                INSERT INTO report (reporter, target_type, target_id, reason, created_on)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (reporter, target_type, target_id) WHERE resolution IS NULL
+                DO UPDATE SET reason = EXCLUDED.reason
+                RETURNING *
+            ",
+            &[
+                reporter,
+                &target.node_type(),
+                &target.db_id(),
+                &reason,
+                &now,
+            ],
+            |row| row.try_into(),
+        )
+        .await
+    }
+
+    #[instrument(skip(self), err)]
+    async fn report(&self, report_id: &DbId) -> Result<Option<Report>, DbError> {
+        self.query(
+            "SELECT * FROM report WHERE report_id = $1",
+            &[report_id],
+            |rows| rows.into_iter().next().map(Report::try_from).transpose(),
+        )
+        .await
+    }
+
+    #[instrument(skip(self), err)]
+    async fn open_reports(&self) -> Result<Vec<Report>, DbError> {
+        self.query(
+            "SELECT * FROM report WHERE resolution IS NULL ORDER BY created_on",
+            &[],
+            |rows| rows.into_iter().map(|row| row.try_into()).collect(),
+        )
+        .await
+    }
+
+    // Everything a resolution does lands together, or the report stays open
+    #[instrument(skip(self), err)]
+    async fn resolve_report(
+        &self,
+        report_id: &DbId,
+        moderator: &DbId,
+        action: ModerationAction,
+    ) -> Result<Option<Report>, DbError> {
+        let now = OffsetDateTime::now_utc();
+
+        let resolved = self
+            .transaction(Isolation::Serializable, |tx| async move {
+                let report: Option<Report> = tx
+                    .query(
+                        r"
+                            UPDATE report
+                            SET resolution = $2, resolved_by = $3, resolved_on = $4
+                            WHERE report_id = $1 AND resolution IS NULL
+                            RETURNING *
+                        ",
+                        &[report_id, &action, moderator, &now],
+                        |rows| rows.into_iter().next().map(Report::try_from).transpose(),
+                    )
+                    .await?;
+
+                let Some(report) = report else {
+                    return Ok(None);
+                };
+
+                let affected = match (action, report.target) {
+                    (ModerationAction::Dismiss, target) => target,
+                    (ModerationAction::HideContent, NodeId::Post(post_id)) => {
+                        tx.hide_post(&post_id).await?;
+                        report.target
+                    }
+                    (ModerationAction::HideContent, NodeId::Comment(comment_id)) => {
+                        tx.hide_comment(&comment_id).await?;
+                        report.target
+                    }
+                    // Rolls the resolution back, callers check the action applies first
+                    (ModerationAction::HideContent, NodeId::AppUser(_)) => {
+                        return Err(DbError::Missing(format!(
+                            "Content to hide of {:?}",
+                            report.target
+                        )));
+                    }
+                    (ModerationAction::SuspendUser, target) => {
+                        let user_id = tx
+                            .owner_of(&target)
+                            .await?
+                            .ok_or_else(|| DbError::Missing(format!("Owner of {target:?}")))?;
+                        tx.set_suspended(&user_id, true).await?;
+                        NodeId::AppUser(user_id)
+                    }
+                };
+
+                tx.execute(
+                    r"
+                        INSERT INTO moderation_log (moderator, report_id, action, target_type, target_id, created_on)
+                        VALUES ($1, $2, $3, $4, $5, $6)
+                    ",
+                    &[
+                        moderator,
+                        report_id,
+                        &action,
+                        &affected.node_type(),
+                        &affected.db_id(),
+                        &now,
+                    ],
+                )
+                .await?;
+
+                Ok(Some((report, affected)))
+            })
+            .await?;
+
+        let Some((report, affected)) = resolved else {
+            return Ok(None);
+        };
+
+        info!(
+            moderator = **moderator,
+            report = **report_id,
+            ?action,
+            target = ?affected,
+            "Resolved report"
+        );
+
+        Ok(Some(report))
+    }
+}
+
+impl TryFrom<Row> for Report {
+    type Error = DbError;
+
+    #[instrument(level = Level::TRACE, err)]
+    fn try_from(value: Row) -> Result<Self, Self::Error> {
+        let target_type: NodeType = value.try_get("target_type").map_err(DbError::mapping)?;
+        let target_id: DbId = value.try_get("target_id").map_err(DbError::mapping)?;
+
+        Ok(Report {
+            report_id: value.try_get("report_id").map_err(DbError::mapping)?,
+            reporter: value.try_get("reporter").map_err(DbError::mapping)?,
+            target: NodeId::new(target_type, target_id),
+            reason: value.try_get("reason").map_err(DbError::mapping)?,
+            created_on: value.try_get("created_on").map_err(DbError::mapping)?,
+            resolution: value.try_get("resolution").map_err(DbError::mapping)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::ID;
+    use serde_json::json;
+
+    use crate::{
+        domain::{
+            app_user::Role,
+            comment::Comment,
+            db_id::CanDecodeId,
+            report::{domain::ModerationAction, Report},
+        },
+        infrastructure::{
+            testing::{TestApp, TestDb},
+            DbError,
+        },
+    };
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn resolutions_hide_and_suspend_and_get_logged() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, _) = app.user("Alice").await;
+        let (bob, _) = app.user("Bob").await;
+        let (moderator, _) = app.user("Mod").await;
+        app.storage
+            .set_role(&moderator, Role::Moderator)
+            .await
+            .unwrap();
+
+        let post_id = app.post(alice, "Post").await;
+        let rude_id = app.comment(bob, &post_id, "Rude").await;
+        let spam_id = app.comment(bob, &post_id, "Spam").await;
+
+        let mut resolutions = Vec::new();
+        for (target, action) in [(&rude_id, "HIDE_CONTENT"), (&spam_id, "SUSPEND_USER")] {
+            let query = format!(
+                r#"mutation {{ reportContent(input: {{ targetId: "{target}", reason: "Bad" }}) {{ id }} }}"#
+            );
+            let data = app.execute(alice, &query).await;
+            let report_id = data["reportContent"]["id"].as_str().unwrap().to_string();
+
+            let query = format!(
+                r#"mutation {{ resolveReport(input: {{ report: "{report_id}", action: {action} }}) {{ resolution }} }}"#
+            );
+            app.execute(moderator, &query).await;
+            resolutions.push(query);
+        }
+
+        // Resolved once and for all
+        let response = app.try_execute(moderator, &resolutions[0]).await;
+        assert!(!response.errors.is_empty());
+
+        let query = format!(
+            r#"{{
+                node(id: "{post_id}") {{ ... on Post {{ comments {{ totalCount edges {{ node {{ content }} }} }} }} }}
+                moderationQueue {{ totalCount }}
+            }}"#
+        );
+        let data = app.execute(moderator, &query).await;
+        assert_eq!(
+            json!({
+                "node": { "comments": { "totalCount": 1, "edges": [{ "node": { "content": "Spam" } }] } },
+                "moderationQueue": { "totalCount": 0 },
+            }),
+            data
+        );
+
+        let response = app
+            .try_execute(
+                bob,
+                r#"mutation { createPost(input: { content: "Again" }) { node { id } } }"#,
+            )
+            .await;
+        assert!(!response.errors.is_empty());
+
+        let logged: Vec<(String, i32)> = db
+            .repo
+            .query(
+                "SELECT action::text, target_id FROM moderation_log ORDER BY log_id",
+                &[],
+                |rows| {
+                    rows.into_iter()
+                        .map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+                        .collect::<Result<_, tokio_postgres::Error>>()
+                        .map_err(DbError::mapping)
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![
+                (
+                    "hide_content".to_string(),
+                    *Comment::decode(&ID(rude_id)).unwrap()
+                ),
+                ("suspend_user".to_string(), *bob)
+            ],
+            logged
+        );
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn reported_users_are_not_hidden() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, _) = app.user("Alice").await;
+        let (_, bob_id) = app.user("Bob").await;
+        let (moderator, _) = app.user("Mod").await;
+        app.storage
+            .set_role(&moderator, Role::Moderator)
+            .await
+            .unwrap();
+
+        let query = format!(
+            r#"mutation {{ reportContent(input: {{ targetId: "{bob_id}", reason: "Bad" }}) {{ id }} }}"#
+        );
+        let data = app.execute(alice, &query).await;
+        let report_id = data["reportContent"]["id"].as_str().unwrap().to_string();
+
+        let query = format!(
+            r#"mutation {{ resolveReport(input: {{ report: "{report_id}", action: HIDE_CONTENT }}) {{ resolution }} }}"#
+        );
+        let response = serde_json::to_value(app.try_execute(moderator, &query).await).unwrap();
+        assert_eq!(
+            json!("VALIDATION"),
+            response["errors"][0]["extensions"]["code"]
+        );
+
+        // The store refuses as well, leaving the report open
+        let resolved = app
+            .storage
+            .resolve_report(
+                &Report::decode(&ID(report_id)).unwrap(),
+                &moderator,
+                ModerationAction::HideContent,
+            )
+            .await;
+        assert!(matches!(resolved, Err(DbError::Missing(_))));
+
+        let data = app
+            .execute(moderator, "{ moderationQueue { totalCount } }")
+            .await;
+        assert_eq!(json!({ "moderationQueue": { "totalCount": 1 } }), data);
+
+        db.drop().await;
+    }
+}
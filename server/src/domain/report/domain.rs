@@ -0,0 +1,55 @@
+use async_graphql::{Enum, ID};
+use postgres_types::{FromSql, ToSql};
+use time::OffsetDateTime;
+
+use crate::domain::{
+    db_id::{CanDecodeId, DbId, HasDbId},
+    errors::MappingError,
+    relay_meta::NodeId,
+};
+
+pub const SUFFIX: &str = "Report";
+
+#[derive(Clone)]
+pub struct Report {
+    pub report_id: DbId,
+    pub(super) reporter: DbId,
+    pub(in crate::domain) target: NodeId,
+    pub(super) reason: String,
+    pub(super) created_on: OffsetDateTime,
+    pub(super) resolution: Option<ModerationAction>,
+}
+
+impl HasDbId for Report {
+    fn db_id(&self) -> DbId {
+        self.report_id
+    }
+}
+
+impl CanDecodeId for Report {
+    fn decode(relay_id: &ID) -> Result<DbId, MappingError> {
+        Self::decode_with_suffix(relay_id, SUFFIX)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum, ToSql, FromSql)]
+#[postgres(name = "moderation_action")]
+pub enum ModerationAction {
+    #[postgres(name = "dismiss")]
+    Dismiss,
+    /// Reported posts and comments only, a reported user can only be suspended
+    #[postgres(name = "hide_content")]
+    HideContent,
+    #[postgres(name = "suspend_user")]
+    SuspendUser,
+}
+
+impl ModerationAction {
+    /// Users have no content of their own to hide.
+    pub fn applies_to(&self, target: &NodeId) -> bool {
+        !matches!(
+            (self, target),
+            (ModerationAction::HideContent, NodeId::AppUser(_))
+        )
+    }
+}
@@ -0,0 +1,65 @@
+use async_graphql::{Context, InputObject, Object, ID};
+use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use time::OffsetDateTime;
+use tracing::instrument;
+
+use crate::{
+    domain::{app_user::AppUser, errors::GqlError, relay_meta::Node},
+    infrastructure::storage::Loaders,
+};
+
+use super::domain::{ModerationAction, Report, SUFFIX};
+
+#[Object]
+impl Report {
+    pub async fn id(&self) -> ID {
+        let combined = self.report_id.to_string() + SUFFIX;
+
+        ID(URL_SAFE.encode(combined))
+    }
+
+    #[instrument(skip_all, err)]
+    #[graphql(complexity = 3)]
+    async fn reporter(&self, ctx: &Context<'_>) -> Result<AppUser, GqlError> {
+        let loaders = ctx.data::<Loaders>()?;
+
+        loaders
+            .app_user
+            .load_one(self.reporter)
+            .await
+            .map_err(GqlError::db_load)?
+            .ok_or_else(|| GqlError::InvalidState("Expected reporter, got None".to_string()))
+    }
+
+    #[instrument(skip_all, err)]
+    #[graphql(complexity = 3)]
+    async fn target(&self, ctx: &Context<'_>) -> Result<Option<Node>, GqlError> {
+        let loaders = ctx.data::<Loaders>()?;
+
+        self.target.load(loaders).await
+    }
+
+    async fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    async fn created_on(&self) -> OffsetDateTime {
+        self.created_on
+    }
+
+    async fn resolution(&self) -> Option<ModerationAction> {
+        self.resolution
+    }
+}
+
+#[derive(Debug, InputObject)]
+pub struct ReportContentInput {
+    pub(in crate::domain) target_id: ID,
+    pub(in crate::domain) reason: String,
+}
+
+#[derive(Debug, InputObject)]
+pub struct ResolveReportInput {
+    pub(in crate::domain) report: ID,
+    pub(in crate::domain) action: ModerationAction,
+}
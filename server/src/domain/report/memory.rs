@@ -0,0 +1,116 @@
+use async_trait::async_trait;
+use time::OffsetDateTime;
+
+use crate::{
+    domain::{
+        app_user::UserStore, comment::CommentStore, db_id::DbId, post::PostStore,
+        relay_meta::NodeId,
+    },
+    infrastructure::{memory::MemoryStore, DbError},
+};
+
+use super::{
+    domain::{ModerationAction, Report},
+    store::ReportStore,
+};
+
+#[async_trait]
+impl ReportStore for MemoryStore {
+    async fn save_report(
+        &self,
+        reporter: &DbId,
+        target: &NodeId,
+        reason: &str,
+    ) -> Result<Report, DbError> {
+        let mut tables = self.tables();
+
+        let open = tables.reports.values_mut().find(|report| {
+            report.reporter == *reporter && report.target == *target && report.resolution.is_none()
+        });
+
+        if let Some(report) = open {
+            report.reason = reason.to_string();
+            return Ok(report.clone());
+        }
+
+        let report = Report {
+            report_id: tables.next_id(),
+            reporter: *reporter,
+            target: *target,
+            reason: reason.to_string(),
+            created_on: OffsetDateTime::now_utc(),
+            resolution: None,
+        };
+        tables.reports.insert(report.report_id, report.clone());
+
+        Ok(report)
+    }
+
+    async fn report(&self, report_id: &DbId) -> Result<Option<Report>, DbError> {
+        Ok(self.tables().reports.get(report_id).cloned())
+    }
+
+    async fn open_reports(&self) -> Result<Vec<Report>, DbError> {
+        let mut reports: Vec<Report> = self
+            .tables()
+            .reports
+            .values()
+            .filter(|report| report.resolution.is_none())
+            .cloned()
+            .collect();
+        reports.sort_by_key(|report| report.created_on);
+
+        Ok(reports)
+    }
+
+    // Only closing the report is atomic, which is all a single process needs
+    async fn resolve_report(
+        &self,
+        report_id: &DbId,
+        _moderator: &DbId,
+        action: ModerationAction,
+    ) -> Result<Option<Report>, DbError> {
+        let report = {
+            let mut tables = self.tables();
+
+            let Some(report) = tables
+                .reports
+                .get_mut(report_id)
+                .filter(|report| report.resolution.is_none())
+            else {
+                return Ok(None);
+            };
+
+            if !action.applies_to(&report.target) {
+                return Err(DbError::Missing(format!(
+                    "Content to hide of {:?}",
+                    report.target
+                )));
+            }
+            report.resolution = Some(action);
+
+            report.clone()
+        };
+
+        match (action, report.target) {
+            (ModerationAction::Dismiss, _) => {}
+            (ModerationAction::HideContent, NodeId::Post(post_id)) => {
+                self.hide_post(&post_id).await?;
+            }
+            (ModerationAction::HideContent, NodeId::Comment(comment_id)) => {
+                self.hide_comment(&comment_id).await?;
+            }
+            // Rejected before anything changed
+            (ModerationAction::HideContent, NodeId::AppUser(_)) => {}
+            (ModerationAction::SuspendUser, target) => {
+                let user_id = self
+                    .owner_of(&target)
+                    .await?
+                    .ok_or_else(|| DbError::Missing(format!("Owner of {target:?}")))?;
+                self.set_suspended(&user_id, true).await?;
+            }
+        }
+
+        Ok(Some(report))
+    }
+}
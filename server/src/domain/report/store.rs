@@ -0,0 +1,33 @@
+use async_trait::async_trait;
+
+use crate::{
+    domain::{db_id::DbId, relay_meta::NodeId},
+    infrastructure::DbError,
+};
+
+use super::domain::{ModerationAction, Report};
+
+/// Where reports and what moderators did about them are kept.
+#[async_trait]
+pub trait ReportStore: Send + Sync {
+    /// Reporting the same content again while it is open only updates the reason.
+    async fn save_report(
+        &self,
+        reporter: &DbId,
+        target: &NodeId,
+        reason: &str,
+    ) -> Result<Report, DbError>;
+
+    async fn report(&self, report_id: &DbId) -> Result<Option<Report>, DbError>;
+
+    /// Oldest first.
+    async fn open_reports(&self) -> Result<Vec<Report>, DbError>;
+
+    /// Applies the action, `None` if the report was already resolved.
+    async fn resolve_report(
+        &self,
+        report_id: &DbId,
+        moderator: &DbId,
+        action: ModerationAction,
+    ) -> Result<Option<Report>, DbError>;
+}
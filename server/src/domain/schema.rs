@@ -4,4 +4,4 @@ mod subscription;
 
 pub use mutation::RootMutation;
 pub use query::RootQuery;
-pub use subscription::RootSubscription;
+pub use subscription::{LastEventId, RootSubscription};
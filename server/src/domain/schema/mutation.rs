@@ -2,18 +2,24 @@ use async_graphql::{
     connection::{Edge, EmptyFields},
     Context, Object,
 };
+use time::OffsetDateTime;
 use tracing::instrument;
 
 use crate::{
     domain::{
-        app_user::{AddFriendInput, AppUser, AppUserInput},
-        comment::{Comment, CommentInput},
-        db_id::{CanDecodeId, DbId},
+        app_user::{
+            AddFriendInput, AddFriendPayload, AppUser, AppUserInput, CreateUserPayload, Role,
+            SetUserRoleInput, SetUserSuspendedInput,
+        },
+        auth::{ActiveUserGuard, OwnerGuard, RoleGuard, Session},
+        comment::{Comment, CommentInput, CreateCommentPayload, EditCommentInput},
+        db_id::CanDecodeId,
         errors::GqlError,
-        post::{Post, PostInput},
-        relay_meta::AppCursor,
+        post::{CreatePostPayload, EditPostInput, Post, PostInput, PublishPostInput},
+        relay_meta::{AppCursor, NodeId},
+        report::{Report, ReportContentInput, ResolveReportInput},
     },
-    infrastructure::db::{Loaders, Repo},
+    infrastructure::storage::{Loaders, Storage},
 };
 
 pub struct RootMutation;
@@ -25,86 +31,305 @@ impl RootMutation {
         &self,
         ctx: &Context<'_>,
         input: AppUserInput,
-    ) -> Result<AppUser, GqlError> {
-        let repo = ctx.data::<Repo>()?;
+    ) -> Result<CreateUserPayload, GqlError> {
+        let storage = ctx.data::<Storage>()?;
 
-        repo.save_user(&input.first_name, &input.last_name)
+        let result = storage
+            .save_user(&input.first_name, &input.last_name, Role::User)
             .await
-            .map_err(|_| GqlError::DbSave)
+            .map_err(GqlError::db_save);
+
+        CreateUserPayload::new(input.client_mutation_id, result)
     }
 
     #[instrument(skip(self, ctx), err)]
+    #[graphql(guard = "ActiveUserGuard")]
     async fn add_friend(
         &self,
         ctx: &Context<'_>,
         input: AddFriendInput,
-    ) -> Result<AppUser, GqlError> {
-        let repo = ctx.data::<Repo>()?;
+    ) -> Result<AddFriendPayload, GqlError> {
+        let storage = ctx.data::<Storage>()?;
         let loaders = ctx.data::<Loaders>()?;
 
-        let user_id = DbId::from(1); // Placeholder until we have auth
-        let friend_id =
-            AppUser::decode(&input.friend).map_err(|e| GqlError::InvalidRequest(e.to_string()))?;
+        let user_id = Session::of(ctx)?.user_id;
 
-        let mut users = loaders
-            .app_user
-            .load_many([user_id, friend_id])
-            .await
-            .map_err(|_| GqlError::DbLoad)?;
+        let result = async {
+            let friend_id =
+                AppUser::decode(&input.friend).map_err(|e| GqlError::invalid_field("friend", e))?;
 
-        let user = users.remove(&user_id).ok_or_else(|| {
-            GqlError::InvalidState("This was requested for a user that does not exist".to_string())
-        })?;
+            let mut users = loaders
+                .app_user
+                .load_many([user_id, friend_id])
+                .await
+                .map_err(GqlError::db_load)?;
 
-        users.get(&friend_id).ok_or_else(|| {
-            GqlError::InvalidState(
-                "This was requested for a friend that does not exist".to_string(),
-            )
-        })?;
+            let user = users.remove(&user_id).ok_or_else(|| {
+                GqlError::InvalidState(
+                    "This was requested for a user that does not exist".to_string(),
+                )
+            })?;
 
-        repo.add_friend(&user_id, &friend_id)
-            .await
-            .map_err(|_| GqlError::DbSave)?;
+            let friend = users.remove(&friend_id).ok_or_else(|| {
+                GqlError::NotFound(
+                    "This was requested for a friend that does not exist".to_string(),
+                )
+            })?;
 
-        Ok(user)
+            storage
+                .add_friend(&user_id, &friend_id)
+                .await
+                .map_err(GqlError::db_save)?;
+
+            Ok((user, friend))
+        }
+        .await;
+
+        AddFriendPayload::new(input.client_mutation_id, result)
     }
 
     #[instrument(skip(self, ctx), err)]
+    #[graphql(guard = "ActiveUserGuard")]
     async fn create_post(
         &self,
         ctx: &Context<'_>,
         input: PostInput,
+    ) -> Result<CreatePostPayload, GqlError> {
+        let storage = ctx.data::<Storage>()?;
+
+        let author = Session::of(ctx)?.user_id;
+
+        let result = async {
+            validate_schedule(input.scheduled_for)?;
+
+            storage
+                .save_post(&author, &input.content, input.state(), input.scheduled_for)
+                .await
+                .map_err(GqlError::db_save)
+        }
+        .await;
+
+        CreatePostPayload::new(input.client_mutation_id, result)
+    }
+
+    #[instrument(skip(self, ctx), err)]
+    #[graphql(guard = "ActiveUserGuard.and(OwnerGuard::new(&input.post))")]
+    async fn publish_post(
+        &self,
+        ctx: &Context<'_>,
+        input: PublishPostInput,
     ) -> Result<Edge<AppCursor, Post, EmptyFields>, GqlError> {
-        let repo = ctx.data::<Repo>()?;
+        let storage = ctx.data::<Storage>()?;
+
+        let author = Session::of(ctx)?.user_id;
+        let post_id = Post::decode(&input.post).map_err(|e| GqlError::invalid_field("post", e))?;
+
+        validate_schedule(input.scheduled_for)?;
+
+        let published = storage
+            .publish_post(&author, &post_id, input.scheduled_for)
+            .await
+            .map_err(GqlError::db_save)?
+            .ok_or_else(|| {
+                GqlError::InvalidRequest("Viewer has no unpublished post with this id".to_string())
+            })?;
+
+        Ok(Edge::new(AppCursor(published.post_id), published))
+    }
 
-        let author = DbId::from(1); // Placeholder until we have auth
+    #[instrument(skip(self, ctx), err)]
+    #[graphql(guard = "ActiveUserGuard.and(OwnerGuard::new(&input.post))")]
+    async fn edit_post(&self, ctx: &Context<'_>, input: EditPostInput) -> Result<Post, GqlError> {
+        let storage = ctx.data::<Storage>()?;
+        let loaders = ctx.data::<Loaders>()?;
+
+        let post_id = Post::decode(&input.post).map_err(|e| GqlError::invalid_field("post", e))?;
 
-        let saved = repo
-            .save_post(&author, &input.content)
+        let edited = storage
+            .update_post_content(&post_id, &input.content)
             .await
-            .map_err(|_| GqlError::DbSave)?;
+            .map_err(GqlError::db_save)?
+            .ok_or_else(|| GqlError::InvalidRequest("Post can not be edited".to_string()))?;
 
-        Ok(Edge::new(AppCursor(saved.post_id), saved))
+        loaders.post.feed_one(post_id, edited.clone()).await;
+
+        Ok(edited)
     }
 
     #[instrument(skip(self, ctx), err)]
+    #[graphql(guard = "ActiveUserGuard")]
     async fn create_comment(
         &self,
         ctx: &Context<'_>,
         input: CommentInput,
-    ) -> Result<Edge<AppCursor, Comment, EmptyFields>, GqlError> {
-        let repo = ctx.data::<Repo>()?;
+    ) -> Result<CreateCommentPayload, GqlError> {
+        let storage = ctx.data::<Storage>()?;
+
+        let author_id = Session::of(ctx)?.user_id;
+
+        let result = async {
+            let referenced_post_id = Post::decode(&input.referenced_post)
+                .map_err(|e| GqlError::invalid_field("referencedPost", e))?;
 
-        let author_id = DbId::from(1); // Placeholder until we have auth
+            storage
+                .save_comment(&author_id, &referenced_post_id, &input.content)
+                .await
+                .map_err(GqlError::db_save)?
+                .ok_or_else(|| GqlError::NotFound("Post does not exist".to_string()))
+        }
+        .await;
+
+        CreateCommentPayload::new(input.client_mutation_id, result)
+    }
+
+    #[instrument(skip(self, ctx), err)]
+    #[graphql(guard = "ActiveUserGuard.and(OwnerGuard::new(&input.comment))")]
+    async fn edit_comment(
+        &self,
+        ctx: &Context<'_>,
+        input: EditCommentInput,
+    ) -> Result<Comment, GqlError> {
+        let storage = ctx.data::<Storage>()?;
+        let loaders = ctx.data::<Loaders>()?;
+
+        let comment_id =
+            Comment::decode(&input.comment).map_err(|e| GqlError::invalid_field("comment", e))?;
+
+        let edited = storage
+            .update_comment_content(&comment_id, &input.content)
+            .await
+            .map_err(GqlError::db_save)?
+            .ok_or_else(|| GqlError::InvalidRequest("Comment can not be edited".to_string()))?;
+
+        loaders.comment.feed_one(comment_id, edited.clone()).await;
+
+        Ok(edited)
+    }
+
+    #[instrument(skip(self, ctx), err)]
+    #[graphql(guard = "ActiveUserGuard")]
+    async fn report_content(
+        &self,
+        ctx: &Context<'_>,
+        input: ReportContentInput,
+    ) -> Result<Report, GqlError> {
+        let storage = ctx.data::<Storage>()?;
+        let loaders = ctx.data::<Loaders>()?;
 
-        let referenced_post_id = Post::decode(&input.referenced_post)
+        let reporter = Session::of(ctx)?.user_id;
+        let target = NodeId::decode(&input.target_id)
             .map_err(|e| GqlError::InvalidRequest(e.to_string()))?;
 
-        let saved = repo
-            .save_comment(&author_id, &referenced_post_id, &input.content)
+        let reason = input.reason.trim();
+        if reason.is_empty() {
+            return Err(GqlError::invalid_field("reason", "must not be empty"));
+        }
+
+        target
+            .load(loaders)
+            .await?
+            .ok_or_else(|| GqlError::NotFound("Reported content does not exist".to_string()))?;
+
+        storage
+            .save_report(&reporter, &target, reason)
+            .await
+            .map_err(GqlError::db_save)
+    }
+
+    #[instrument(skip(self, ctx), err)]
+    #[graphql(guard = "RoleGuard::new(Role::Moderator)")]
+    async fn resolve_report(
+        &self,
+        ctx: &Context<'_>,
+        input: ResolveReportInput,
+    ) -> Result<Report, GqlError> {
+        let storage = ctx.data::<Storage>()?;
+        let loaders = ctx.data::<Loaders>()?;
+
+        let moderator = Session::of(ctx)?.user_id;
+
+        let report_id =
+            Report::decode(&input.report).map_err(|e| GqlError::invalid_field("report", e))?;
+
+        let report = storage
+            .report(&report_id)
+            .await
+            .map_err(GqlError::db_load)?
+            .ok_or_else(|| GqlError::NotFound("Report does not exist".to_string()))?;
+
+        if !input.action.applies_to(&report.target) {
+            return Err(GqlError::invalid_field(
+                "action",
+                "users can only be suspended, not hidden",
+            ));
+        }
+
+        let resolved = storage
+            .resolve_report(&report_id, &moderator, input.action)
+            .await
+            .map_err(GqlError::db_save)?
+            .ok_or_else(|| GqlError::InvalidRequest("Report was already resolved".to_string()))?;
+
+        loaders.clear_caches();
+
+        Ok(resolved)
+    }
+
+    #[instrument(skip(self, ctx), err)]
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn set_user_role(
+        &self,
+        ctx: &Context<'_>,
+        input: SetUserRoleInput,
+    ) -> Result<AppUser, GqlError> {
+        let storage = ctx.data::<Storage>()?;
+        let loaders = ctx.data::<Loaders>()?;
+
+        let user_id =
+            AppUser::decode(&input.user).map_err(|e| GqlError::invalid_field("user", e))?;
+
+        let user = storage
+            .set_role(&user_id, input.role)
             .await
-            .map_err(|_| GqlError::DbSave)?;
+            .map_err(GqlError::db_save)?
+            .ok_or_else(|| GqlError::NotFound("User does not exist".to_string()))?;
+
+        loaders.app_user.feed_one(user_id, user.clone()).await;
+
+        Ok(user)
+    }
+
+    #[instrument(skip(self, ctx), err)]
+    #[graphql(guard = "RoleGuard::new(Role::Admin)")]
+    async fn set_user_suspended(
+        &self,
+        ctx: &Context<'_>,
+        input: SetUserSuspendedInput,
+    ) -> Result<AppUser, GqlError> {
+        let storage = ctx.data::<Storage>()?;
+        let loaders = ctx.data::<Loaders>()?;
+
+        let user_id =
+            AppUser::decode(&input.user).map_err(|e| GqlError::invalid_field("user", e))?;
+
+        let user = storage
+            .set_suspended(&user_id, input.suspended)
+            .await
+            .map_err(GqlError::db_save)?
+            .ok_or_else(|| GqlError::NotFound("User does not exist".to_string()))?;
+
+        loaders.app_user.feed_one(user_id, user.clone()).await;
+
+        Ok(user)
+    }
+}
 
-        Ok(Edge::new(AppCursor(saved.comment_id), saved))
+fn validate_schedule(scheduled_for: Option<OffsetDateTime>) -> Result<(), GqlError> {
+    match scheduled_for {
+        Some(time) if time <= OffsetDateTime::now_utc() => Err(GqlError::invalid_field(
+            "scheduledFor",
+            "must be in the future",
+        )),
+        _ => Ok(()),
     }
 }
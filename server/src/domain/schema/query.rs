@@ -1,99 +1,130 @@
-use async_graphql::{Context, Object, ID};
+use async_graphql::{Context, ErrorExtensions, Object, PathSegment, ID};
 use tracing::instrument;
 
 use crate::{
     domain::{
-        app_user::AppUser,
-        comment::Comment,
-        db_id::{CanDecodeId as _, DbId},
+        app_user::{AppUser, Role},
+        auth::{RoleGuard, Session},
+        db_id::CanDecodeId as _,
         errors::GqlError,
-        post::Post,
-        relay_meta::Node,
+        relay_meta::{paginate, AppConnection, Node, NodeId, TotalCount},
+        report::Report,
         viewer::Viewer,
     },
-    infrastructure::db::Loaders,
+    infrastructure::storage::{Loaders, Storage},
 };
 
+const MAX_NODES: usize = 100;
+
 pub struct RootQuery;
 
 #[Object]
 impl RootQuery {
     #[instrument(skip(self, ctx), err)]
-    async fn node(&self, ctx: &Context<'_>, id: ID) -> Result<Node, GqlError> {
+    async fn node(&self, ctx: &Context<'_>, id: ID) -> Result<Option<Node>, GqlError> {
         let loaders = ctx.data::<Loaders>()?;
 
-        if let Ok(inner_id) = AppUser::decode(&id) {
-            let user = loaders
-                .app_user
-                .load_one(inner_id)
-                .await
-                .map_err(|_| GqlError::DbLoad)?
-                .ok_or_else(|| {
-                    GqlError::InvalidState("Expected empty vec, got None".to_string())
-                })?;
-
-            return Ok(Node::AppUser(user));
+        let node_id = NodeId::decode(&id).map_err(|e| GqlError::invalid_field("id", e))?;
+
+        let node = node_id.load(loaders).await?;
+
+        if node.is_none() {
+            not_found(ctx, format!("No node with id {}", id.as_str()), None);
         }
 
-        if let Ok(inner_id) = Comment::decode(&id) {
-            let comment = loaders
-                .comment
-                .load_one(inner_id)
-                .await
-                .map_err(|_| GqlError::DbLoad)?
-                .ok_or_else(|| {
-                    GqlError::InvalidState("Expected empty vec, got None".to_string())
-                })?;
-            return Ok(Node::Comment(comment));
+        Ok(node)
+    }
+
+    #[instrument(skip(self, ctx, ids), err)]
+    #[graphql(complexity = "ids.len() * child_complexity")]
+    async fn nodes(&self, ctx: &Context<'_>, ids: Vec<ID>) -> Result<Vec<Option<Node>>, GqlError> {
+        let loaders = ctx.data::<Loaders>()?;
+
+        if ids.len() > MAX_NODES {
+            return Err(GqlError::invalid_field(
+                "ids",
+                format!("must not contain more than {MAX_NODES} ids"),
+            ));
         }
 
-        if let Ok(inner_id) = Post::decode(&id) {
-            let post = loaders
-                .post
-                .load_one(inner_id)
-                .await
-                .map_err(|_| GqlError::DbLoad)?
-                .ok_or_else(|| {
-                    GqlError::InvalidState("Expected empty vec, got None".to_string())
-                })?;
-
-            return Ok(Node::Post(post));
+        let node_ids = ids
+            .iter()
+            .map(NodeId::decode)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| GqlError::invalid_field("ids", e))?;
+
+        let nodes = NodeId::load_many(&node_ids, loaders).await?;
+
+        for (index, id) in ids.iter().enumerate() {
+            if nodes[index].is_none() {
+                not_found(ctx, format!("No node with id {}", id.as_str()), Some(index));
+            }
         }
 
-        Err(GqlError::InvalidRequest(
-            "Given id did not match any available types".to_string(),
-        ))
+        Ok(nodes)
     }
 
     #[instrument(skip(self, ctx), err)]
-    async fn user(&self, ctx: &Context<'_>, id: ID) -> Result<AppUser, GqlError> {
+    async fn user(&self, ctx: &Context<'_>, id: ID) -> Result<Option<AppUser>, GqlError> {
         let loaders = ctx.data::<Loaders>()?;
 
-        let inner_id = AppUser::decode(&id).map_err(|e| GqlError::InvalidRequest(e.to_string()))?;
+        let inner_id = AppUser::decode(&id).map_err(|e| GqlError::invalid_field("id", e))?;
 
         let user = loaders
             .app_user
             .load_one(inner_id)
             .await
-            .map_err(|_| GqlError::DbLoad)?
-            .ok_or_else(|| GqlError::InvalidState("Expected empty vec, got None".to_string()))?;
+            .map_err(GqlError::db_load)?;
+
+        if user.is_none() {
+            not_found(ctx, format!("No user with id {}", id.as_str()), None);
+        }
 
         Ok(user)
     }
 
     #[instrument(skip(self, ctx), err)]
-    async fn viewer(&self, ctx: &Context<'_>) -> Result<Viewer, GqlError> {
-        let loaders = ctx.data::<Loaders>()?;
-
-        let id = DbId::from(1); // Placeholder until we have auth
+    #[graphql(
+        guard = "RoleGuard::new(Role::Moderator)",
+        complexity = "first.unwrap_or(0).try_into().unwrap_or(usize::MAX) * child_complexity 
+        + last.unwrap_or(0).try_into().unwrap_or(usize::MAX) * child_complexity"
+    )]
+    async fn moderation_queue(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<AppConnection<Report>, GqlError> {
+        let storage = ctx.data::<Storage>()?;
+
+        let reports = storage.open_reports().await.map_err(GqlError::db_load)?;
+
+        let total_count = TotalCount::Known(reports.len());
+        let connection = paginate(after, before, first, last, reports, total_count).await?;
+
+        Ok(connection)
+    }
 
-        let user = loaders
-            .app_user
-            .load_one(id)
-            .await
-            .map_err(|_| GqlError::DbLoad)?
-            .ok_or_else(|| GqlError::InvalidState("Expected empty vec, got None".to_string()))?;
+    #[instrument(skip(self, ctx), err)]
+    async fn viewer(&self, ctx: &Context<'_>) -> Result<Viewer, GqlError> {
+        let user = Session::of(ctx)?.viewer(ctx).await?;
 
         Ok(Viewer::new(user))
     }
 }
+
+/* Missing entities resolve to null like the field would on an error, and the NOT_FOUND
+error with its code is reported next to it. For `nodes` only the missing entry is null,
+with the error pointing at its index, instead of the whole list. */
+fn not_found(ctx: &Context<'_>, message: String, index: Option<usize>) {
+    let error = GqlError::NotFound(message)
+        .extend()
+        .into_server_error(ctx.item.pos);
+
+    let mut error = ctx.set_error_path(error);
+    error.path.extend(index.map(PathSegment::Index));
+
+    ctx.add_error(error);
+}
@@ -1,30 +1,39 @@
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
 
 use async_graphql::{
-    connection::{Edge, EmptyFields},
+    connection::{CursorType, Edge, EmptyFields},
     Context, Subscription, ID,
 };
 use async_stream::stream;
 use time::OffsetDateTime;
-use tokio::time::interval;
 use tokio_stream::Stream;
-use tracing::{error, instrument};
+use tracing::{instrument, warn};
 
 use crate::{
     domain::{
         app_user::AppUser,
+        auth::Session,
+        comment::{Comment, CommentChange, CommentOperation},
         db_id::{CanDecodeId, DbId},
         errors::GqlError,
         post::Post,
         relay_meta::AppCursor,
     },
     infrastructure::{
-        db::{Loaders, Repo},
-        notification_center::{ListenerTopic, Notification, NotificationCenter},
-        DbError,
+        config::SubscriptionConfig,
+        notification_center::{
+            CommentNotification, ListenerHandle, ListenerTopic, Notification, NotificationCenter,
+            Operation, OverflowPolicy,
+        },
+        storage::{Loaders, Storage},
     },
 };
 
+type PostEdge = Edge<AppCursor, Post, EmptyFields>;
+
+// Posts created this long before their feed got registered may still be notified to it
+const REGISTRATION_MARGIN: time::Duration = time::Duration::seconds(5);
+
 pub struct RootSubscription;
 
 #[Subscription]
@@ -34,133 +43,502 @@ impl RootSubscription {
         &'a self,
         ctx: &'a Context<'a>,
         user_id: ID,
-    ) -> Result<impl Stream<Item = Vec<Edge<AppCursor, Post, EmptyFields>>> + 'a, GqlError> {
-        let repo = ctx.data::<Repo>()?;
+        after: Option<String>,
+    ) -> Result<impl Stream<Item = Result<Vec<PostEdge>, GqlError>> + 'a, GqlError> {
+        let notification_center = ctx.data::<NotificationCenter>()?;
 
         let user_id =
-            AppUser::decode(&user_id).map_err(|e| GqlError::InvalidRequest(e.to_string()))?;
+            AppUser::decode(&user_id).map_err(|e| GqlError::invalid_field("userId", e))?;
+        let after = feed_position(ctx, after).await?;
 
-        let mut interval = interval(Duration::from_secs(10));
-        let mut last_seen = OffsetDateTime::now_utc();
-
-        let stream = stream!({
-            loop {
-                let posts: Result<Vec<Edge<AppCursor, Post, EmptyFields>>, DbError> = repo
-                    .query(
-                        "SELECT * FROM post WHERE author = ANY($1) AND created_on > $2",
-                        &[&user_id, &last_seen],
-                        |rows| {
-                            rows.into_iter()
-                                .map(|row| {
-                                    let post: Post = row.try_into()?;
-                                    Ok(Edge::new(AppCursor(post.post_id), post))
-                                })
-                                .collect::<Result<_, DbError>>()
-                        },
-                    )
-                    .await;
-
-                if let Ok(posts) = posts {
-                    if !posts.is_empty() {
-                        yield posts;
-                    }
-
-                    last_seen = OffsetDateTime::now_utc();
-                    let _ = ctx.data::<Loaders>().map(|loaders| loaders.clear_caches());
-                };
+        let handle = notification_center
+            .subscribe_catching_up(
+                vec![ListenerTopic::User(user_id)],
+                OverflowPolicy::Disconnect,
+            )
+            .await
+            .map_err(|e| GqlError::InternalData(e.to_string()))?;
 
-                interval.tick().await;
-            }
+        let replay = after.map(|after| Replay {
+            author_ids: vec![user_id],
+            after,
         });
 
-        Ok(stream)
+        post_feed(ctx, handle, None, replay)
     }
 
     #[instrument(skip(self, ctx), err)]
     async fn home_feed<'a>(
         &'a self,
         ctx: &'a Context<'a>,
-    ) -> Result<impl Stream<Item = Vec<Edge<AppCursor, Post, EmptyFields>>> + 'a, GqlError> {
-        let repo = ctx.data::<Repo>()?;
+        after: Option<String>,
+    ) -> Result<impl Stream<Item = Result<Vec<PostEdge>, GqlError>> + 'a, GqlError> {
+        let storage = ctx.data::<Storage>()?;
         let notification_center = ctx.data::<NotificationCenter>()?;
 
-        let user_id = DbId::from(1); // Placeholder until we have auth
-
-        let friend_ids: Vec<DbId> = repo
-            .query(
-                r"
-                    SELECT user_id_b AS friend_id
-                    FROM user_relation 
-                    WHERE user_id_a = $1 
-                    UNION
-                    SELECT user_id_a AS friend_id
-                    FROM user_relation
-                    WHERE user_id_b = $1
-                ",
-                &[&user_id],
-                |rows| {
-                    rows.into_iter()
-                        .map(|row| {
-                            let friend_id = row.try_get("friend_id").map_err(DbError::mapping)?;
-                            Ok::<_, DbError>(friend_id)
-                        })
-                        .collect()
-                },
-            )
-            .await
-            .map_err(|e| {
-                error!(message = e.to_string());
-                GqlError::DbLoad
-            })?;
+        let user_id = Session::of(ctx)?.user_id;
+        let after = feed_position(ctx, after).await?;
 
-        let mut author_ids = friend_ids;
-        author_ids.push(user_id);
+        let relations = storage
+            .relations_of(&[user_id])
+            .await
+            .map_err(GqlError::db_load)?;
 
-        let topics = author_ids.into_iter().map(ListenerTopic::User).collect();
+        let graph = FriendGraph {
+            user_id,
+            friends: relations
+                .into_iter()
+                .map(|(a, b)| if a == user_id { b } else { a })
+                .collect(),
+        };
 
-        let mut handle = notification_center
-            .subscribe(topics)
+        let handle = notification_center
+            .subscribe_catching_up(graph.topics(), OverflowPolicy::Disconnect)
             .await
             .map_err(|e| GqlError::InternalData(e.to_string()))?;
 
+        let replay = after.map(|after| Replay {
+            author_ids: graph.authors().collect(),
+            after,
+        });
+
+        post_feed(ctx, handle, Some(graph), replay)
+    }
+
+    #[instrument(skip(self, ctx), err)]
+    async fn post_comments<'a>(
+        &'a self,
+        ctx: &'a Context<'a>,
+        post_id: ID,
+    ) -> Result<impl Stream<Item = Result<Vec<CommentChange>, GqlError>> + 'a, GqlError> {
+        let storage = ctx.data::<Storage>()?;
+
+        let post_id = Post::decode(&post_id).map_err(|e| GqlError::invalid_field("postId", e))?;
+        let mut handle = subscribe_post(ctx, post_id, OverflowPolicy::Disconnect).await?;
+
         let stream = stream!({
             while let Some(notifications) = handle.receive().await {
-                let post_ids: Vec<DbId> = notifications
+                let notifications = match notifications {
+                    Ok(notifications) => notifications,
+                    Err(e) => {
+                        yield Err(GqlError::SubscriptionOverflow(e.to_string()));
+                        break;
+                    }
+                };
+
+                let notifications: Vec<CommentNotification> = notifications
                     .into_iter()
                     .filter_map(|n| {
-                        if let Notification::Post(post) = n {
-                            Some(post.post_id)
+                        if let Notification::Comment(comment) = n {
+                            Some(comment)
                         } else {
                             None
                         }
                     })
                     .collect();
 
-                let posts: Result<Vec<Edge<AppCursor, Post, EmptyFields>>, DbError> = repo
-                    .query(
-                        "SELECT * FROM post WHERE post_id = ANY($1)",
-                        &[&post_ids],
-                        |rows| {
-                            rows.into_iter()
-                                .map(|row| {
-                                    let post: Post = row.try_into()?;
-                                    Ok(Edge::new(AppCursor(post.post_id), post))
-                                })
-                                .collect::<Result<_, DbError>>()
-                        },
-                    )
-                    .await;
-
-                if let Ok(posts) = posts {
-                    if !posts.is_empty() {
-                        yield posts;
+                if notifications.is_empty() {
+                    continue;
+                }
+
+                let comment_ids: Vec<DbId> = notifications
+                    .iter()
+                    // Hidden comments are gone for the subscriber, without asking the db
+                    .filter(|n| n.operation != Operation::Delete && !n.hidden)
+                    .map(|n| n.comment_id)
+                    .collect();
+
+                let comments: HashMap<DbId, Comment> = if comment_ids.is_empty() {
+                    HashMap::new()
+                } else {
+                    match storage.visible_comments(&comment_ids).await {
+                        Ok(comments) => comments.into_iter().map(|c| (c.comment_id, c)).collect(),
+                        Err(e) => {
+                            warn!("Could not load comments of the post: {e}");
+                            continue;
+                        }
                     }
+                };
 
-                    let _ = ctx.data::<Loaders>().map(|loaders| loaders.clear_caches());
+                let changes: Vec<CommentChange> = notifications
+                    .into_iter()
+                    .filter_map(|n| {
+                        let comment = comments.get(&n.comment_id).cloned();
+
+                        let operation = match n.operation {
+                            // Never seen by the subscriber, so there is nothing to delete
+                            Operation::Insert if comment.is_none() => return None,
+                            Operation::Insert => CommentOperation::Created,
+                            Operation::Update | Operation::Publish => CommentOperation::Edited,
+                            Operation::Delete => CommentOperation::Deleted,
+                        };
+
+                        Some(CommentChange::new(operation, n.comment_id, comment))
+                    })
+                    .collect();
+
+                if !changes.is_empty() {
+                    yield Ok(changes);
+                }
+
+                let _ = ctx.data::<Loaders>().map(|loaders| loaders.clear_caches());
+            }
+        });
+
+        Ok(stream)
+    }
+
+    /// Ends once the post is no longer visible.
+    #[instrument(skip(self, ctx), err)]
+    async fn post_updated<'a>(
+        &'a self,
+        ctx: &'a Context<'a>,
+        post_id: ID,
+    ) -> Result<impl Stream<Item = Post> + 'a, GqlError> {
+        let storage = ctx.data::<Storage>()?;
+
+        let post_id = Post::decode(&post_id).map_err(|e| GqlError::invalid_field("postId", e))?;
+        let mut handle = subscribe_post(ctx, post_id, OverflowPolicy::DropOldest).await?;
+
+        // Only the latest state matters, so falling behind is fine
+        let stream = stream!({
+            while let Some(Ok(notifications)) = handle.receive().await {
+                let Some(hidden) = notifications.iter().rev().find_map(|n| match n {
+                    Notification::Post(post) if post.post_id == post_id => Some(post.hidden),
+                    _ => None,
+                }) else {
+                    continue;
                 };
+
+                if hidden {
+                    break;
+                }
+
+                match storage.published_posts(&[post_id]).await {
+                    Ok(posts) => match posts.into_iter().next() {
+                        Some(post) => yield post,
+                        None => break,
+                    },
+                    Err(e) => warn!("Could not load the updated post: {e}"),
+                }
+
+                let _ = ctx.data::<Loaders>().map(|loaders| loaders.clear_caches());
             }
         });
 
         Ok(stream)
     }
 }
+
+// Subscribing before the post is checked, so nothing happening in between gets lost
+async fn subscribe_post(
+    ctx: &Context<'_>,
+    post_id: DbId,
+    policy: OverflowPolicy,
+) -> Result<ListenerHandle, GqlError> {
+    let storage = ctx.data::<Storage>()?;
+    let notification_center = ctx.data::<NotificationCenter>()?;
+
+    let handle = notification_center
+        .subscribe(vec![ListenerTopic::Post(post_id)], policy)
+        .await
+        .map_err(|e| GqlError::InternalData(e.to_string()))?;
+
+    let posts = storage
+        .published_posts(&[post_id])
+        .await
+        .map_err(GqlError::db_load)?;
+
+    if posts.is_empty() {
+        return Err(GqlError::NotFound("Post does not exist".to_string()));
+    }
+
+    Ok(handle)
+}
+
+/// Whose posts a home feed shows, kept up to date while the feed runs.
+struct FriendGraph {
+    user_id: DbId,
+    friends: HashSet<DbId>,
+}
+
+impl FriendGraph {
+    fn authors(&self) -> impl Iterator<Item = DbId> + '_ {
+        self.friends.iter().chain([&self.user_id]).copied()
+    }
+
+    fn topics(&self) -> Vec<ListenerTopic> {
+        self.authors()
+            .map(ListenerTopic::User)
+            .chain([ListenerTopic::Friends(self.user_id)])
+            .collect()
+    }
+
+    fn follows(&self, author_id: DbId) -> bool {
+        author_id == self.user_id || self.friends.contains(&author_id)
+    }
+
+    fn apply(&mut self, notifications: &[Notification]) {
+        for notification in notifications {
+            let Notification::Relation(relation) = notification else {
+                continue;
+            };
+
+            let friend = if relation.user_id_a == self.user_id {
+                relation.user_id_b
+            } else if relation.user_id_b == self.user_id {
+                relation.user_id_a
+            } else {
+                continue;
+            };
+
+            match relation.operation {
+                Operation::Delete => self.friends.remove(&friend),
+                Operation::Insert | Operation::Update | Operation::Publish => {
+                    self.friends.insert(friend)
+                }
+            };
+        }
+    }
+}
+
+/// Replayed posts whose notification may still be on its way to the feed.
+struct ReplayedPosts {
+    // Creation times come from the db, whose clock may be a little off
+    registered_on: OffsetDateTime,
+    post_ids: HashSet<DbId>,
+}
+
+impl ReplayedPosts {
+    fn new(registered_on: OffsetDateTime) -> Self {
+        Self {
+            registered_on: registered_on - REGISTRATION_MARGIN,
+            post_ids: HashSet::new(),
+        }
+    }
+
+    // Older posts were notified before the listener was there to receive it
+    fn track(&mut self, created_on: OffsetDateTime, post_id: DbId) {
+        if created_on >= self.registered_on {
+            self.post_ids.insert(post_id);
+        }
+    }
+
+    /// True the one time a tracked post gets notified.
+    fn notified(&mut self, post_id: DbId) -> bool {
+        self.post_ids.remove(&post_id)
+    }
+}
+
+/// Where an event stream left off, sent by reconnecting clients instead of an `after` argument.
+pub struct LastEventId(pub String);
+
+/// Posts a reconnecting client missed, everything after the last post it saw.
+struct Replay {
+    author_ids: Vec<DbId>,
+    after: (OffsetDateTime, DbId),
+}
+
+async fn feed_position(
+    ctx: &Context<'_>,
+    after: Option<String>,
+) -> Result<Option<(OffsetDateTime, DbId)>, GqlError> {
+    let Some(after) = after.or_else(|| {
+        ctx.data_opt::<LastEventId>()
+            .map(|last_event_id| last_event_id.0.clone())
+    }) else {
+        return Ok(None);
+    };
+
+    let storage = ctx.data::<Storage>()?;
+
+    let AppCursor(post_id) =
+        AppCursor::decode_cursor(&after).map_err(|e| GqlError::invalid_field("after", e))?;
+
+    storage
+        .feed_position(&post_id)
+        .await
+        .map_err(GqlError::db_load)?
+        .map(Some)
+        .ok_or_else(|| GqlError::invalid_field("after", "Cursor does not point to a post"))
+}
+
+/* The listener is registered before the stream is handed out, so nothing published
+afterwards is missed. Posts are only loaded when a notification names some.
+
+A replay runs first, on top of the already buffering listener, which queues up to its
+catch up capacity until the replay is done. Replays longer than the configured pages end
+the feed, the client is better off loading it anew. Posts the replay returned that were
+created after the listener got registered are skipped once when their notification comes
+in, so the switch to live has no gaps and no duplicates. Older ones were notified before,
+so their ids are not kept around waiting for that. */
+fn post_feed<'a>(
+    ctx: &'a Context<'a>,
+    mut handle: ListenerHandle,
+    mut graph: Option<FriendGraph>,
+    replay: Option<Replay>,
+) -> Result<impl Stream<Item = Result<Vec<PostEdge>, GqlError>> + 'a, GqlError> {
+    let storage = ctx.data::<Storage>()?;
+    let config = ctx.data::<SubscriptionConfig>()?;
+    let replay_page = i64::from(config.replay_page);
+    let max_replay_pages = config.max_replay_pages;
+
+    let mut replayed = ReplayedPosts::new(OffsetDateTime::now_utc());
+
+    let stream = stream!({
+        if let Some(mut replay) = replay {
+            for page in 1.. {
+                let posts = match storage
+                    .published_posts_after(&replay.author_ids, replay.after, replay_page)
+                    .await
+                {
+                    Ok(posts) => posts,
+                    Err(e) => {
+                        yield Err(GqlError::db_load(e));
+                        return;
+                    }
+                };
+
+                let Some(last) = posts.last() else {
+                    break;
+                };
+
+                if page > max_replay_pages {
+                    yield Err(GqlError::InvalidRequest(format!(
+                        "Missed more than {} posts, load the feed again instead",
+                        i64::from(max_replay_pages) * replay_page
+                    )));
+                    return;
+                }
+
+                replay.after = (last.created_on, last.post_id);
+                let exhausted = (posts.len() as i64) < replay_page;
+
+                for post in &posts {
+                    replayed.track(post.created_on, post.post_id);
+                }
+
+                yield Ok(posts
+                    .into_iter()
+                    .map(|post| Edge::new(AppCursor(post.post_id), post))
+                    .collect());
+
+                if exhausted {
+                    break;
+                }
+            }
+        }
+
+        while let Some(notifications) = handle.receive().await {
+            let notifications = match notifications {
+                Ok(notifications) => notifications,
+                Err(e) => {
+                    yield Err(GqlError::SubscriptionOverflow(e.to_string()));
+                    break;
+                }
+            };
+
+            // The notification center already changed the topics along with the friendships
+            if let Some(graph) = &mut graph {
+                graph.apply(&notifications);
+            }
+
+            let post_ids: Vec<DbId> = notifications
+                .into_iter()
+                .filter_map(|n| match n {
+                    // Feeds show new posts, edits and moderation are for postUpdated
+                    Notification::Post(post)
+                        if matches!(post.operation, Operation::Insert | Operation::Publish) =>
+                    {
+                        Some(post)
+                    }
+                    _ => None,
+                })
+                .filter(|post| !replayed.notified(post.post_id))
+                // Posts of former friends might still have been queued
+                .filter(|post| !post.hidden && graph.iter().all(|g| g.follows(post.author_id)))
+                .map(|post| post.post_id)
+                .collect();
+
+            if post_ids.is_empty() {
+                continue;
+            }
+
+            match storage.published_posts(&post_ids).await {
+                Ok(posts) => {
+                    if !posts.is_empty() {
+                        yield Ok(posts
+                            .into_iter()
+                            .map(|post| Edge::new(AppCursor(post.post_id), post))
+                            .collect());
+                    }
+
+                    let _ = ctx.data::<Loaders>().map(|loaders| loaders.clear_caches());
+                }
+                Err(e) => warn!("Could not load posts of the feed: {e}"),
+            }
+        }
+    });
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use time::{Duration, OffsetDateTime};
+
+    use crate::{
+        domain::db_id::DbId,
+        infrastructure::notification_center::{Notification, Operation, RelationNotification},
+    };
+
+    use super::{FriendGraph, ReplayedPosts};
+
+    fn relation(user_id_a: i32, user_id_b: i32, operation: Operation) -> Notification {
+        Notification::Relation(RelationNotification {
+            user_id_a: DbId::from(user_id_a),
+            user_id_b: DbId::from(user_id_b),
+            operation,
+        })
+    }
+
+    #[test]
+    fn friend_graph_follows_relations_of_the_user() {
+        let mut graph = FriendGraph {
+            user_id: DbId::from(1),
+            friends: HashSet::from([DbId::from(2)]),
+        };
+
+        graph.apply(&[
+            relation(3, 1, Operation::Insert),
+            relation(1, 2, Operation::Delete),
+            relation(4, 5, Operation::Insert),
+        ]);
+
+        assert!(graph.follows(DbId::from(1)));
+        assert!(graph.follows(DbId::from(3)));
+        assert!(!graph.follows(DbId::from(2)));
+        assert!(!graph.follows(DbId::from(4)));
+    }
+
+    #[test]
+    fn replayed_posts_only_wait_for_notifications_still_to_come() {
+        let registered_on = OffsetDateTime::now_utc();
+        let mut replayed = ReplayedPosts::new(registered_on);
+
+        replayed.track(registered_on - Duration::minutes(5), DbId::from(1));
+        replayed.track(registered_on - Duration::seconds(1), DbId::from(2));
+        replayed.track(registered_on + Duration::seconds(1), DbId::from(3));
+
+        assert_eq!(
+            HashSet::from([DbId::from(2), DbId::from(3)]),
+            replayed.post_ids
+        );
+
+        assert!(!replayed.notified(DbId::from(1)));
+        assert!(replayed.notified(DbId::from(3)));
+        assert!(!replayed.notified(DbId::from(3)));
+    }
+}
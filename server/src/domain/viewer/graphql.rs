@@ -1,15 +1,20 @@
 use crate::{
-    domain::{db_id::DbId, errors::GqlError, relay_meta::paginate},
+    domain::{
+        app_user::Role,
+        errors::GqlError,
+        relay_meta::{paginate, selects_rows, ConnectionOrder, CreatedRange, TotalCount},
+    },
     infrastructure::{logging::current_span_as_headers, urls::Urls},
 };
 use crate::{
     domain::{post::Post, relay_meta::AppConnection, viewer::Viewer},
-    infrastructure::db::Loaders,
+    infrastructure::storage::{Loaders, Storage},
 };
 use async_graphql::{Context, Object};
 use reqwest::Client;
 use serde::Deserialize;
-use tracing::{error, instrument};
+use time::OffsetDateTime;
+use tracing::instrument;
 
 #[Object]
 impl Viewer {
@@ -21,6 +26,11 @@ impl Viewer {
         &self.user.last_name
     }
 
+    pub async fn role(&self) -> Role {
+        self.user.role
+    }
+
+    #[allow(clippy::too_many_arguments)]
     #[instrument(skip(self, ctx), err)]
     #[graphql(
         complexity = "first.unwrap_or(0).try_into().unwrap_or(usize::MAX) * child_complexity 
@@ -33,39 +43,68 @@ impl Viewer {
         before: Option<String>,
         first: Option<i32>,
         last: Option<i32>,
+        #[graphql(default_with = "ConnectionOrder::CreatedOnDesc")] order_by: ConnectionOrder,
+        since: Option<OffsetDateTime>,
+        until: Option<OffsetDateTime>,
     ) -> Result<AppConnection<Post>, GqlError> {
         let loaders = ctx.data::<Loaders>()?;
 
-        let id = DbId::from(1); // Placeholder until we have auth
+        let id = self.user.user_id;
+        let range = CreatedRange::new(since, until);
 
         let friends = loaders
             .friend_id
             .load_one(id)
             .await
-            .map_err(|e| {
-                error!(message = e.to_string());
-                GqlError::DbLoad
-            })?
+            .map_err(GqlError::db_load)?
             .ok_or_else(|| GqlError::InvalidState("Expected empty vec, got None".to_string()))?;
 
         let mut authors = friends;
         authors.push(id);
 
-        let mut posts: Vec<Post> = loaders
-            .posts_of_author
-            .load_many(authors)
-            .await
-            .map_err(|e| {
-                error!(message = e.to_string());
-                GqlError::DbLoad
-            })?
-            .drain()
-            .flat_map(|(_, posts)| posts)
-            .collect();
+        let posts: Vec<Post> = if selects_rows(ctx) {
+            loaders
+                .posts_of_author
+                .load_many(authors.iter().copied())
+                .await
+                .map_err(GqlError::db_load)?
+                .drain()
+                .flat_map(|(_, posts)| posts)
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let posts = range.arrange(posts, order_by, |p| p.created_on);
+        let total_count = TotalCount::PostsOfAuthors(authors, range);
+
+        let connection = paginate(after, before, first, last, posts, total_count).await?;
+
+        Ok(connection)
+    }
+
+    #[instrument(skip(self, ctx), err)]
+    #[graphql(
+        complexity = "first.unwrap_or(0).try_into().unwrap_or(usize::MAX) * child_complexity 
+        + last.unwrap_or(0).try_into().unwrap_or(usize::MAX) * child_complexity"
+    )]
+    pub async fn drafts(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<AppConnection<Post>, GqlError> {
+        let storage = ctx.data::<Storage>()?;
 
-        posts.sort_unstable_by_key(|p| p.created_on);
+        let posts = storage
+            .unpublished_posts(&self.user.user_id)
+            .await
+            .map_err(GqlError::db_load)?;
 
-        let connection = paginate(after, before, first, last, posts).await?;
+        let total_count = TotalCount::Known(posts.len());
+        let connection = paginate(after, before, first, last, posts, total_count).await?;
 
         Ok(connection)
     }
@@ -1,12 +1,25 @@
+pub mod app_env;
 pub mod app_state;
+pub mod cli;
+pub mod config;
+pub mod cost;
 pub mod db;
 mod errors;
 pub mod handlers;
 pub mod logging;
+#[cfg(test)]
+pub mod memory;
 pub mod notification_center;
+pub mod persisted_queries;
+pub mod query_limits;
 pub mod router;
+pub mod scheduler;
 pub mod schema;
 pub mod shutdown;
+pub mod storage;
+#[cfg(test)]
+pub mod testing;
 pub mod urls;
+pub mod websocket;
 
 pub use errors::DbError;
@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+
+/// Where the server runs. Only development gets seeded, production never does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AppEnv {
+    Development,
+    // Has to be opted out of
+    #[default]
+    Production,
+}
+
+impl AppEnv {
+    pub fn allows_seeding(&self) -> bool {
+        *self == Self::Development
+    }
+}
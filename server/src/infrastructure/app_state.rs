@@ -1,20 +1,57 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
 use super::{
+    config::Config,
     db::Repo,
     notification_center::NotificationCenter,
+    persisted_queries::PersistedQueries,
     schema::{self, Schema},
-    urls::Urls,
+    storage::Storage,
+    websocket::SubscriptionLimiter,
 };
 
 #[derive(Clone)]
 pub struct AppState {
+    pub(super) config: Arc<Config>,
     pub(super) repo: Repo,
+    pub(super) storage: Storage,
+    pub(super) notification_center: NotificationCenter,
     pub(super) schema: Schema,
+    pub(super) graphiql: bool,
+    pub(super) subscriptions: SubscriptionLimiter,
+    pub(super) shutdown: watch::Receiver<bool>,
 }
 
 impl AppState {
-    pub fn new(notification_center: NotificationCenter, repo: Repo, urls: Urls) -> Self {
-        let schema = schema::new(repo.clone(), notification_center, urls);
+    pub fn new(
+        config: Arc<Config>,
+        notification_center: NotificationCenter,
+        repo: Repo,
+        storage: Storage,
+        persisted_queries: PersistedQueries,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        // GraphiQL sends arbitrary queries, which strict mode would reject anyway
+        let graphiql = !persisted_queries.is_strict();
+        let schema = schema::new(
+            storage.clone(),
+            notification_center.clone(),
+            persisted_queries,
+            &config,
+        );
+        let subscriptions = SubscriptionLimiter::new(config.subscriptions.max_per_user);
 
-        Self { repo, schema }
+        Self {
+            config,
+            repo,
+            storage,
+            notification_center,
+            schema,
+            graphiql,
+            subscriptions,
+            shutdown,
+        }
     }
 }
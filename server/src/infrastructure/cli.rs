@@ -0,0 +1,393 @@
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+
+use crate::domain::{app_user::Role, db_id::DbId};
+
+use super::{db::SeedSizes, errors::InfrastructureError};
+
+pub const USAGE: &str = r"Usage:
+    server [serve]
+    server migrate [--target VERSION]
+    server migrate status
+    server seed [--users N] [--posts N] [--comments N] [--friendships N]
+    server export-schema [--out PATH]
+    server create-user --first-name NAME --last-name NAME [--role user|moderator|admin]
+    server reset-password --user ID
+    server check-config
+
+Every command takes these too:
+    --config PATH       TOML file to read the config from, instead of CONFIG_FILE
+    --set KEY=VALUE     Overrides a setting of the file and env, like pg.port=5433";
+
+/// The command along with the options every command takes.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Cli {
+    pub command: Command,
+    pub config_file: Option<PathBuf>,
+    pub overrides: Vec<(String, String)>,
+}
+
+impl Cli {
+    /// Parses the arguments without the name of the binary.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, InfrastructureError> {
+        let mut config_file = None;
+        let mut overrides = Vec::new();
+        let mut rest = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    let path = args.next().ok_or_else(|| {
+                        InfrastructureError::arguments("--config needs a value".to_string())
+                    })?;
+
+                    if config_file.replace(PathBuf::from(path)).is_some() {
+                        return Err(InfrastructureError::arguments(
+                            "--config was given twice".to_string(),
+                        ));
+                    }
+                }
+                "--set" => {
+                    let setting = args.next().unwrap_or_default();
+                    let (key, value) = setting.split_once('=').ok_or_else(|| {
+                        InfrastructureError::arguments(format!(
+                            "--set needs KEY=VALUE, got {setting}"
+                        ))
+                    })?;
+
+                    overrides.push((key.to_string(), value.to_string()));
+                }
+                _ => rest.push(arg),
+            }
+        }
+
+        Ok(Self {
+            command: Command::parse(rest)?,
+            config_file,
+            overrides,
+        })
+    }
+}
+
+/// What the binary was started for, serving unless told otherwise.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Serve,
+    Migrate {
+        target: Option<u32>,
+    },
+    MigrationStatus,
+    /// Without sizes when none were given, which does not insist on seeding.
+    Seed(Option<SeedSizes>),
+    /// Prints to stdout without a path.
+    ExportSchema {
+        out: Option<PathBuf>,
+    },
+    CreateUser {
+        first_name: String,
+        last_name: String,
+        role: Role,
+    },
+    /// Prints the new password, which is generated rather than given.
+    ResetPassword {
+        user_id: DbId,
+    },
+    CheckConfig,
+}
+
+impl Command {
+    /// Parses the arguments without the name of the binary.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, InfrastructureError> {
+        let mut args = args.into_iter().peekable();
+
+        let command = args.next().unwrap_or_else(|| "serve".to_string());
+
+        if command == "migrate" && args.peek().map(String::as_str) == Some("status") {
+            args.next();
+            Flags::parse(args, &[])?;
+
+            return Ok(Command::MigrationStatus);
+        }
+
+        match command.as_str() {
+            "serve" => {
+                Flags::parse(args, &[])?;
+
+                Ok(Command::Serve)
+            }
+            "migrate" => {
+                let mut flags = Flags::parse(args, &["--target"])?;
+
+                Ok(Command::Migrate {
+                    target: flags.optional("--target")?,
+                })
+            }
+            "seed" => {
+                let mut flags =
+                    Flags::parse(args, &["--users", "--posts", "--comments", "--friendships"])?;
+                let defaults = SeedSizes::default();
+
+                if flags.0.is_empty() {
+                    return Ok(Command::Seed(None));
+                }
+
+                Ok(Command::Seed(Some(SeedSizes {
+                    users: flags.optional("--users")?.unwrap_or(defaults.users),
+                    posts: flags.optional("--posts")?.unwrap_or(defaults.posts),
+                    comments: flags.optional("--comments")?.unwrap_or(defaults.comments),
+                    friendships: flags
+                        .optional("--friendships")?
+                        .unwrap_or(defaults.friendships),
+                })))
+            }
+            "export-schema" => {
+                let mut flags = Flags::parse(args, &["--out"])?;
+
+                Ok(Command::ExportSchema {
+                    out: flags.optional("--out")?,
+                })
+            }
+            "create-user" => {
+                let mut flags = Flags::parse(args, &["--first-name", "--last-name", "--role"])?;
+
+                Ok(Command::CreateUser {
+                    first_name: flags.required("--first-name")?,
+                    last_name: flags.required("--last-name")?,
+                    role: flags
+                        .optional::<RoleArg>("--role")?
+                        .map_or(Role::User, |role| role.0),
+                })
+            }
+            "reset-password" => {
+                let mut flags = Flags::parse(args, &["--user"])?;
+
+                Ok(Command::ResetPassword {
+                    user_id: flags.required("--user")?,
+                })
+            }
+            "check-config" => {
+                Flags::parse(args, &[])?;
+
+                Ok(Command::CheckConfig)
+            }
+            other => Err(InfrastructureError::arguments(format!(
+                "Unknown command: {other}"
+            ))),
+        }
+    }
+}
+
+/// Flags of a command, every one followed by its value.
+struct Flags(HashMap<String, String>);
+
+impl Flags {
+    fn parse(
+        mut args: impl Iterator<Item = String>,
+        known: &[&str],
+    ) -> Result<Self, InfrastructureError> {
+        let mut flags = HashMap::new();
+
+        while let Some(flag) = args.next() {
+            if !known.contains(&flag.as_str()) {
+                return Err(InfrastructureError::arguments(format!(
+                    "Unexpected argument: {flag}"
+                )));
+            }
+
+            let value = args
+                .next()
+                .ok_or_else(|| InfrastructureError::arguments(format!("{flag} needs a value")))?;
+
+            if flags.insert(flag.clone(), value).is_some() {
+                return Err(InfrastructureError::arguments(format!(
+                    "{flag} was given twice"
+                )));
+            }
+        }
+
+        Ok(Self(flags))
+    }
+
+    fn optional<T: FromStr>(&mut self, flag: &str) -> Result<Option<T>, InfrastructureError> {
+        self.0
+            .remove(flag)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| InfrastructureError::arguments(format!("Invalid {flag}: {value}")))
+            })
+            .transpose()
+    }
+
+    fn required<T: FromStr>(&mut self, flag: &str) -> Result<T, InfrastructureError> {
+        self.optional(flag)?
+            .ok_or_else(|| InfrastructureError::arguments(format!("{flag} is required")))
+    }
+}
+
+struct RoleArg(Role);
+
+impl FromStr for RoleArg {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Self(Role::User)),
+            "moderator" => Ok(Self(Role::Moderator)),
+            "admin" => Ok(Self(Role::Admin)),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        domain::{app_user::Role, db_id::DbId},
+        infrastructure::db::SeedSizes,
+    };
+
+    use super::{Cli, Command};
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        Command::parse(args.iter().map(|arg| arg.to_string())).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn serves_by_default() {
+        assert_eq!(Ok(Command::Serve), parse(&[]));
+        assert_eq!(Ok(Command::Serve), parse(&["serve"]));
+    }
+
+    #[test]
+    fn migrates_to_targets_or_shows_status() {
+        assert_eq!(Ok(Command::Migrate { target: None }), parse(&["migrate"]));
+        assert_eq!(
+            Ok(Command::Migrate { target: Some(4) }),
+            parse(&["migrate", "--target", "4"])
+        );
+        assert_eq!(Ok(Command::MigrationStatus), parse(&["migrate", "status"]));
+    }
+
+    #[test]
+    fn seeds_with_given_sizes() {
+        assert_eq!(
+            Ok(Command::Seed(Some(SeedSizes {
+                users: 10,
+                friendships: 0,
+                ..SeedSizes::default()
+            }))),
+            parse(&["seed", "--users", "10", "--friendships", "0"])
+        );
+        assert_eq!(Ok(Command::Seed(None)), parse(&["seed"]));
+    }
+
+    #[test]
+    fn exports_schema_to_stdout_unless_told_otherwise() {
+        assert_eq!(
+            Ok(Command::ExportSchema { out: None }),
+            parse(&["export-schema"])
+        );
+        assert_eq!(
+            Ok(Command::ExportSchema {
+                out: Some(PathBuf::from("/tmp/schema.graphql"))
+            }),
+            parse(&["export-schema", "--out", "/tmp/schema.graphql"])
+        );
+    }
+
+    #[test]
+    fn creates_users_with_a_role() {
+        assert_eq!(
+            Ok(Command::CreateUser {
+                first_name: "Ada".to_string(),
+                last_name: "Admin".to_string(),
+                role: Role::Admin,
+            }),
+            parse(&[
+                "create-user",
+                "--role",
+                "admin",
+                "--first-name",
+                "Ada",
+                "--last-name",
+                "Admin"
+            ])
+        );
+        assert_eq!(
+            Err("Arguments were invalid: --last-name is required".to_string()),
+            parse(&["create-user", "--first-name", "Ada"])
+        );
+    }
+
+    #[test]
+    fn resets_passwords_of_users() {
+        assert_eq!(
+            Ok(Command::ResetPassword {
+                user_id: DbId::from(7)
+            }),
+            parse(&["reset-password", "--user", "7"])
+        );
+        assert_eq!(
+            Err("Arguments were invalid: --user is required".to_string()),
+            parse(&["reset-password"])
+        );
+        assert!(parse(&["reset-password", "--user", "Ada"]).is_err());
+    }
+
+    #[test]
+    fn takes_config_options_around_any_command() {
+        let cli = Cli::parse(
+            [
+                "--config",
+                "/etc/fakebook.toml",
+                "migrate",
+                "--set",
+                "pg.port=5433",
+                "--target",
+                "3",
+                "--set",
+                "app_env=development",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Cli {
+                command: Command::Migrate { target: Some(3) },
+                config_file: Some(PathBuf::from("/etc/fakebook.toml")),
+                overrides: vec![
+                    ("pg.port".to_string(), "5433".to_string()),
+                    ("app_env".to_string(), "development".to_string()),
+                ],
+            },
+            cli
+        );
+        assert!(Cli::parse(["--set".to_string(), "pg.port".to_string()]).is_err());
+        assert!(Cli::parse(["--config".to_string()]).is_err());
+    }
+
+    #[test]
+    fn rejects_what_it_does_not_know() {
+        assert!(parse(&["delete-user"]).is_err());
+        assert!(parse(&["serve", "now"]).is_err());
+        assert!(parse(&["migrate", "status", "--target", "1"]).is_err());
+        assert!(parse(&["seed", "--users"]).is_err());
+        assert!(parse(&["seed", "--users", "-1"]).is_err());
+        assert!(parse(&["seed", "--users", "1", "--users", "2"]).is_err());
+        assert!(parse(&["seed", "--admins", "1"]).is_err());
+        assert!(parse(&[
+            "create-user",
+            "--first-name",
+            "A",
+            "--last-name",
+            "B",
+            "--role",
+            "root"
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,624 @@
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use reqwest::Url;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use super::{app_env::AppEnv, errors::InfrastructureError, query_limits::QueryLimits};
+
+/* Everything the server can be configured with. Layered from the defaults, a TOML file,
+env vars and `--set` flags, every layer overriding the ones before. A setting is named by
+its path in the file, `pg.port`, and its env var is that path in upper case, `PG_PORT`. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub app_env: AppEnv,
+    pub hosting_address: SocketAddr,
+    pub http: HttpConfig,
+    pub pg: PgConfig,
+    pub service_ads: ServiceAdsConfig,
+    pub query_limit: QueryLimits,
+    pub persisted_queries: PersistedQueriesConfig,
+    pub notifications: NotificationConfig,
+    pub subscriptions: SubscriptionConfig,
+    pub scheduler: SchedulerConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            app_env: AppEnv::default(),
+            hosting_address: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            http: HttpConfig::default(),
+            pg: PgConfig::default(),
+            service_ads: ServiceAdsConfig::default(),
+            query_limit: QueryLimits::default(),
+            persisted_queries: PersistedQueriesConfig::default(),
+            notifications: NotificationConfig::default(),
+            subscriptions: SubscriptionConfig::default(),
+            scheduler: SchedulerConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub request_timeout_secs: u64,
+    /// How long open requests and subscriptions get to finish on shutdown
+    pub shutdown_grace_secs: u64,
+    /// Clients sending one of these as x-api-key get a budget of their own
+    pub api_keys: ApiKeys,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout_secs: 30,
+            shutdown_grace_secs: 5,
+            api_keys: ApiKeys::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PgConfig {
+    pub host: String,
+    pub port: u16,
+    pub dbname: String,
+    pub user: String,
+    pub password: Secret,
+    /// Serialization failures are expected under contention, retrying usually resolves them
+    pub transaction_attempts: u32,
+    /// Doubles with every attempt
+    pub transaction_retry_ms: u64,
+}
+
+impl Default for PgConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 5432,
+            dbname: "postgres".to_string(),
+            user: "postgres".to_string(),
+            password: Secret::default(),
+            transaction_attempts: 5,
+            transaction_retry_ms: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceAdsConfig {
+    pub url: String,
+    pub ad_link_path: String,
+}
+
+impl Default for ServiceAdsConfig {
+    fn default() -> Self {
+        Self {
+            url: "http://localhost:3001".to_string(),
+            ad_link_path: "/api/ad-link".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistedQueriesConfig {
+    pub store: QueryStoreKind,
+    /// Only allows the queries of the allowlist
+    pub strict: bool,
+    /// Relay's persisted_queries.json
+    pub allowlist: Option<PathBuf>,
+    /// Registered queries Postgres keeps, the least recently used go first
+    pub max_stored: u32,
+    /// Registered queries unused for longer get removed from Postgres
+    pub expire_after_days: u32,
+}
+
+impl Default for PersistedQueriesConfig {
+    fn default() -> Self {
+        Self {
+            store: QueryStoreKind::default(),
+            strict: false,
+            allowlist: None,
+            max_stored: 10_000,
+            expire_after_days: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryStoreKind {
+    #[default]
+    Postgres,
+    Memory,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// Commands waiting for the daemon, notifications among them
+    pub channel_size: usize,
+    /// Notifications a listener may fall behind before its overflow policy kicks in
+    pub listener_capacity: usize,
+    /// Takes the place of the listener capacity while a feed replays what it missed
+    pub catch_up_capacity: usize,
+    /// Doubles with every reconnect attempt, up to the max
+    pub first_retry_ms: u64,
+    pub max_retry_ms: u64,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            channel_size: 32,
+            listener_capacity: 16,
+            catch_up_capacity: 1024,
+            first_retry_ms: 1000,
+            max_retry_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubscriptionConfig {
+    /// Pages the replay of a feed, so a long gap does not load everything at once
+    pub replay_page: u32,
+    /// Clients that missed more pages than this have to load the feed anew
+    pub max_replay_pages: u32,
+    pub keepalive_secs: u64,
+    /// Every client answers pings, so a socket silent for longer is gone
+    pub dead_after_secs: u64,
+    pub init_timeout_secs: u64,
+    /// Sockets without subscriptions only cost us
+    pub idle_timeout_secs: u64,
+    pub max_per_socket: usize,
+    /// Counted per client like the budgets are, sessions are no user of their own yet
+    pub max_per_user: usize,
+}
+
+impl Default for SubscriptionConfig {
+    fn default() -> Self {
+        Self {
+            replay_page: 50,
+            max_replay_pages: 20,
+            keepalive_secs: 15,
+            dead_after_secs: 45,
+            init_timeout_secs: 10,
+            idle_timeout_secs: 300,
+            max_per_socket: 25,
+            max_per_user: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    /// How often due posts get published
+    pub interval_secs: u64,
+    pub batch_size: u32,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 5,
+            batch_size: 100,
+        }
+    }
+}
+
+/// Kept out of printouts and logs, only [Secret::expose] hands it out.
+#[derive(Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("[redacted]")
+    }
+}
+
+/// Secrets as well, given as a list or comma separated, which is all env vars can do.
+#[derive(Clone, Default)]
+pub struct ApiKeys(HashSet<String>);
+
+impl ApiKeys {
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.contains(key)
+    }
+}
+
+impl<'de> Deserialize<'de> for ApiKeys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Keys {
+            List(Vec<String>),
+            Joined(String),
+        }
+
+        let keys = match Keys::deserialize(deserializer)? {
+            Keys::List(keys) => keys,
+            Keys::Joined(keys) => keys.split(',').map(str::to_string).collect(),
+        };
+
+        Ok(Self(
+            keys.into_iter()
+                .map(|key| key.trim().to_string())
+                .filter(|key| !key.is_empty())
+                .collect(),
+        ))
+    }
+}
+
+impl fmt::Debug for ApiKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{} redacted]", self.0.len())
+    }
+}
+
+impl Serialize for ApiKeys {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("[redacted]")
+    }
+}
+
+/// As TOML, which could be fed back in if it were not for the redacted secrets.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&toml::to_string(self).map_err(|_| fmt::Error)?)
+    }
+}
+
+type Leaf<'a> = (Vec<String>, &'a Value);
+
+impl Config {
+    /// Layers the file of `--config` or CONFIG_FILE, the env and the overrides of `--set`
+    /// onto the defaults. Fails with every problem found, not just the first.
+    pub fn load(
+        file: Option<&Path>,
+        env: impl Fn(&str) -> Option<String>,
+        overrides: &[(String, String)],
+    ) -> Result<Self, InfrastructureError> {
+        let defaults = serde_json::to_value(Self::default()).expect("Defaults should serialize");
+        let known = leaves(&defaults);
+
+        let mut layered = Value::Object(Map::new());
+        let mut errors = Vec::new();
+
+        let file = file
+            .map(Path::to_path_buf)
+            .or_else(|| env("CONFIG_FILE").map(PathBuf::from));
+        if let Some(path) = file {
+            match read_toml(&path) {
+                Ok(table) => merge(&mut layered, table),
+                Err(e) => errors.push(format!("{}: {e}", path.display())),
+            }
+        }
+
+        for (path, default) in &known {
+            let name = path.join("_").to_uppercase();
+
+            if let Some(raw) = env(&name) {
+                match typed(default, &raw) {
+                    Ok(value) => set(&mut layered, path, value),
+                    Err(e) => errors.push(format!("{name}: {e}")),
+                }
+            }
+        }
+
+        for (key, raw) in overrides {
+            let path: Vec<String> = key.split('.').map(str::to_string).collect();
+
+            match known.iter().find(|(known, _)| *known == path) {
+                Some((_, default)) => match typed(default, raw) {
+                    Ok(value) => set(&mut layered, &path, value),
+                    Err(e) => errors.push(format!("--set {key}: {e}")),
+                },
+                None => errors.push(format!("--set {key}: unknown setting")),
+            }
+        }
+
+        // Checked one by one, so every bad value gets reported and the good ones still validate
+        let mut valid = Value::Object(Map::new());
+        for (path, value) in leaves(&layered) {
+            let mut single = Value::Object(Map::new());
+            set(&mut single, &path, value.clone());
+
+            match serde_json::from_value::<Self>(single) {
+                Ok(_) => set(&mut valid, &path, value.clone()),
+                Err(e) => errors.push(format!("{}: {e}", path.join("."))),
+            }
+        }
+
+        match serde_json::from_value::<Self>(valid) {
+            Ok(config) => errors.extend(config.validate()),
+            Err(e) => errors.push(e.to_string()),
+        }
+
+        if !errors.is_empty() {
+            return Err(InfrastructureError::config(errors));
+        }
+
+        Ok(serde_json::from_value(layered).expect("Every setting should have deserialized"))
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        let positive = [
+            ("http.request_timeout_secs", self.http.request_timeout_secs),
+            (
+                "pg.transaction_attempts",
+                u64::from(self.pg.transaction_attempts),
+            ),
+            (
+                "notifications.channel_size",
+                self.notifications.channel_size as u64,
+            ),
+            (
+                "notifications.listener_capacity",
+                self.notifications.listener_capacity as u64,
+            ),
+            (
+                "notifications.catch_up_capacity",
+                self.notifications.catch_up_capacity as u64,
+            ),
+            (
+                "notifications.first_retry_ms",
+                self.notifications.first_retry_ms,
+            ),
+            (
+                "subscriptions.replay_page",
+                u64::from(self.subscriptions.replay_page),
+            ),
+            (
+                "subscriptions.max_replay_pages",
+                u64::from(self.subscriptions.max_replay_pages),
+            ),
+            (
+                "subscriptions.keepalive_secs",
+                self.subscriptions.keepalive_secs,
+            ),
+            (
+                "persisted_queries.max_stored",
+                u64::from(self.persisted_queries.max_stored),
+            ),
+            (
+                "persisted_queries.expire_after_days",
+                u64::from(self.persisted_queries.expire_after_days),
+            ),
+            ("scheduler.interval_secs", self.scheduler.interval_secs),
+            ("scheduler.batch_size", u64::from(self.scheduler.batch_size)),
+        ];
+        errors.extend(
+            positive
+                .iter()
+                .filter(|(_, value)| *value == 0)
+                .map(|(key, _)| format!("{key}: has to be more than 0")),
+        );
+
+        if self.notifications.first_retry_ms > self.notifications.max_retry_ms {
+            errors.push(
+                "notifications.first_retry_ms: can not exceed notifications.max_retry_ms"
+                    .to_string(),
+            );
+        }
+
+        // Silence is only noticed on keepalive ticks
+        if self.subscriptions.dead_after_secs <= self.subscriptions.keepalive_secs {
+            errors.push(
+                "subscriptions.dead_after_secs: has to exceed subscriptions.keepalive_secs"
+                    .to_string(),
+            );
+        }
+
+        if self.persisted_queries.strict && self.persisted_queries.allowlist.is_none() {
+            errors.push("persisted_queries.allowlist: is needed in strict mode".to_string());
+        }
+
+        if let Err(e) = Url::parse(&self.service_ads.url) {
+            errors.push(format!("service_ads.url: {e}"));
+        }
+
+        errors
+    }
+}
+
+fn read_toml(path: &Path) -> Result<Value, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+    toml::from_str(&content).map_err(|e| e.message().to_string())
+}
+
+/// Env vars and flags are text, which gets the type of the default it overrides.
+fn typed(default: &Value, raw: &str) -> Result<Value, String> {
+    match default {
+        Value::Bool(_) => raw
+            .parse::<bool>()
+            .map(Value::from)
+            .map_err(|_| format!("expected true or false, got {raw}")),
+        Value::Number(_) => raw
+            .parse::<u64>()
+            .map(Value::from)
+            .map_err(|_| format!("expected a whole number, got {raw}")),
+        _ => Ok(Value::from(raw)),
+    }
+}
+
+// Settings are the leaves, tables only group them
+fn leaves(value: &Value) -> Vec<Leaf<'_>> {
+    match value {
+        Value::Object(table) => table
+            .iter()
+            .flat_map(|(key, value)| {
+                leaves(value).into_iter().map(move |(mut path, leaf)| {
+                    path.insert(0, key.clone());
+                    (path, leaf)
+                })
+            })
+            .collect(),
+        leaf => vec![(Vec::new(), leaf)],
+    }
+}
+
+fn set(tree: &mut Value, path: &[String], value: Value) {
+    let Some((key, rest)) = path.split_first() else {
+        *tree = value;
+        return;
+    };
+
+    if !tree.is_object() {
+        *tree = Value::Object(Map::new());
+    }
+
+    let child = tree
+        .as_object_mut()
+        .expect("Tree should be a table")
+        .entry(key.clone())
+        .or_insert(Value::Null);
+    set(child, rest, value);
+}
+
+fn merge(tree: &mut Value, layer: Value) {
+    for (path, value) in leaves(&layer) {
+        set(tree, &path, value.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, env, fs, path::Path};
+
+    use uuid::Uuid;
+
+    use crate::infrastructure::app_env::AppEnv;
+
+    use super::Config;
+
+    fn load(
+        file: Option<&Path>,
+        env: &[(&str, &str)],
+        overrides: &[(&str, &str)],
+    ) -> Result<Config, String> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let overrides: Vec<(String, String)> = overrides
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        Config::load(file, |name| env.get(name).cloned(), &overrides).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let file = env::temp_dir().join(format!("config-{}.toml", Uuid::new_v4()));
+        fs::write(
+            &file,
+            r#"
+                app_env = "development"
+
+                [pg]
+                host = "file"
+                port = 1111
+                dbname = "file"
+
+                [query_limit]
+                depth = 5
+            "#,
+        )
+        .unwrap();
+
+        let config = load(
+            Some(&file),
+            &[("PG_PORT", "2222"), ("PG_DBNAME", "env")],
+            &[("pg.dbname", "flag")],
+        );
+        fs::remove_file(&file).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(AppEnv::Development, config.app_env);
+        assert_eq!("file", config.pg.host);
+        assert_eq!(2222, config.pg.port);
+        assert_eq!("flag", config.pg.dbname);
+        assert_eq!(5, config.query_limit.depth);
+        assert_eq!(1000, config.query_limit.complexity);
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let error = load(
+            None,
+            &[
+                ("CONFIG_FILE", "/nonexistent/config.toml"),
+                ("APP_ENV", "staging"),
+                ("PG_PORT", "x"),
+                ("QUERY_LIMIT_DEPTH", "-1"),
+                ("HOSTING_ADDRESS", "nowhere"),
+                ("SCHEDULER_INTERVAL_SECS", "0"),
+                ("PERSISTED_QUERIES_STRICT", "true"),
+            ],
+            &[("pg.hots", "localhost")],
+        )
+        .unwrap_err();
+
+        for expected in [
+            "/nonexistent/config.toml: ",
+            "app_env: unknown variant `staging`",
+            "PG_PORT: expected a whole number, got x",
+            "QUERY_LIMIT_DEPTH: expected a whole number, got -1",
+            "hosting_address: invalid socket address syntax",
+            "scheduler.interval_secs: has to be more than 0",
+            "persisted_queries.allowlist: is needed in strict mode",
+            "--set pg.hots: unknown setting",
+        ] {
+            assert!(error.contains(expected), "{expected} is missing in {error}");
+        }
+    }
+
+    #[test]
+    fn secrets_are_redacted_when_printed() {
+        let config = load(
+            None,
+            &[("PG_PASSWORD", "hunter2"), ("HTTP_API_KEYS", "key1, key2")],
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!("hunter2", config.pg.password.expose());
+        assert!(config.http.api_keys.contains("key2"));
+        for secret in ["hunter2", "key1"] {
+            assert!(!config.to_string().contains(secret));
+            assert!(!format!("{config:?}").contains(secret));
+        }
+        assert!(config.to_string().contains(r#"password = "[redacted]""#));
+    }
+}
@@ -0,0 +1,367 @@
+use std::{
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use async_graphql::{
+    extensions::{
+        Extension, ExtensionContext, ExtensionFactory, NextParseQuery, NextRequest, NextSubscribe,
+        NextValidation,
+    },
+    indexmap::IndexMap,
+    parser::types::ExecutableDocument,
+    Name, Response, ServerError, ServerResult, ValidationResult, Value, Variables,
+};
+use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt};
+use lru::LruCache;
+use tracing::{info, warn};
+
+use crate::domain::{auth::Session, db_id::DbId, GqlError};
+
+use super::query_limits::QueryLimits;
+
+const TRACKED_CLIENTS: usize = 10_000;
+
+/// Who a budget belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClientId {
+    ApiKey(String),
+    Address(IpAddr),
+    Session(DbId),
+    Anonymous,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(capacity: f64, now: Instant) -> Self {
+        Self {
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refilled(&self, capacity: f64, per_second: f64, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        (self.tokens + elapsed * per_second).min(capacity)
+    }
+
+    fn refill(&mut self, capacity: f64, per_second: f64, now: Instant) {
+        self.tokens = self.refilled(capacity, per_second, now);
+        self.updated = now;
+    }
+
+    /// Takes the cost if it is covered and returns what is left either way.
+    fn take(&mut self, cost: f64) -> Result<f64, f64> {
+        if cost > self.tokens {
+            return Err(self.tokens);
+        }
+
+        self.tokens -= cost;
+        Ok(self.tokens)
+    }
+}
+
+/* Forgetting a bucket refills it, so only buckets that are full again get evicted. The least
+recently used bucket has had the longest to refill, if it has not, clients that are not tracked
+yet share the overflow bucket until it has. */
+struct Buckets {
+    tracked: LruCache<ClientId, Bucket>,
+    overflow: Bucket,
+}
+
+impl Buckets {
+    fn of(
+        &mut self,
+        client: &ClientId,
+        capacity: f64,
+        per_second: f64,
+        now: Instant,
+    ) -> &mut Bucket {
+        if !self.tracked.contains(client) && self.tracked.len() == self.tracked.cap().get() {
+            let evictable = self
+                .tracked
+                .peek_lru()
+                .is_some_and(|(_, bucket)| bucket.refilled(capacity, per_second, now) >= capacity);
+
+            if !evictable {
+                return &mut self.overflow;
+            }
+            self.tracked.pop_lru();
+        }
+
+        self.tracked
+            .get_or_insert_mut(client.clone(), || Bucket::full(capacity, now))
+    }
+}
+
+/// Per client token buckets, filled up to `budget` over time.
+#[derive(Clone)]
+pub struct CostBudgets {
+    limits: QueryLimits,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl CostBudgets {
+    pub fn new(limits: QueryLimits) -> Self {
+        Self::tracking(limits, TRACKED_CLIENTS)
+    }
+
+    fn tracking(limits: QueryLimits, clients: usize) -> Self {
+        let overflow = Bucket::full(limits.budget as f64, Instant::now());
+
+        Self {
+            limits,
+            buckets: Arc::new(Mutex::new(Buckets {
+                tracked: LruCache::new(
+                    NonZeroUsize::new(clients).expect("Tracked clients should not be zero"),
+                ),
+                overflow,
+            })),
+        }
+    }
+
+    fn charge(&self, client: &ClientId, cost: usize, now: Instant) -> Result<usize, usize> {
+        let capacity = self.limits.budget as f64;
+        let per_second = self.limits.budget_refill_per_second as f64;
+
+        let Ok(mut buckets) = self.buckets.lock() else {
+            // Nobody should be blocked because budgeting broke
+            return Ok(self.limits.budget);
+        };
+
+        let bucket = buckets.of(client, capacity, per_second, now);
+        bucket.refill(capacity, per_second, now);
+
+        bucket
+            .take(cost as f64)
+            .map(|left| left as usize)
+            .map_err(|left| left as usize)
+    }
+
+    fn remaining(&self, client: &ClientId, now: Instant) -> usize {
+        self.charge(client, 0, now).unwrap_or_default()
+    }
+}
+
+impl ExtensionFactory for CostBudgets {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(CostExtension {
+            budgets: self.clone(),
+            state: Arc::default(),
+        })
+    }
+}
+
+#[derive(Default)]
+struct CostState {
+    client: Option<ClientId>,
+    query: Option<String>,
+    requested: usize,
+}
+
+/// Charges every operation against the budget of its client and reports the cost in the response.
+struct CostExtension {
+    budgets: CostBudgets,
+    state: Arc<Mutex<CostState>>,
+}
+
+impl CostExtension {
+    fn client(ctx: &ExtensionContext<'_>) -> ClientId {
+        ctx.data_opt::<ClientId>()
+            .cloned()
+            .or_else(|| {
+                ctx.data_opt::<Session>()
+                    .map(|session| ClientId::Session(session.user_id))
+            })
+            .unwrap_or(ClientId::Anonymous)
+    }
+}
+
+#[async_trait]
+impl Extension for CostExtension {
+    async fn request(&self, ctx: &ExtensionContext<'_>, next: NextRequest<'_>) -> Response {
+        let response = next.run(ctx).await;
+        with_cost(response, &self.budgets, &self.state)
+    }
+
+    fn subscribe<'s>(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        stream: BoxStream<'s, Response>,
+        next: NextSubscribe<'_>,
+    ) -> BoxStream<'s, Response> {
+        let budgets = self.budgets.clone();
+        let state = self.state.clone();
+
+        next.run(ctx, stream)
+            .map(move |response| with_cost(response, &budgets, &state))
+            .boxed()
+    }
+
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        if let Ok(mut state) = self.state.lock() {
+            state.client = Some(Self::client(ctx));
+            state.query = Some(query.to_string());
+        }
+
+        next.run(ctx, query, variables).await
+    }
+
+    async fn validation(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        next: NextValidation<'_>,
+    ) -> Result<ValidationResult, Vec<ServerError>> {
+        let result = next.run(ctx).await?;
+
+        let Ok(mut state) = self.state.lock() else {
+            return Ok(result);
+        };
+        let state = &mut *state;
+
+        let client = state.client.get_or_insert_with(|| Self::client(ctx));
+        let requested = result.complexity;
+        let charged = self.budgets.charge(client, requested, Instant::now());
+        let remaining = charged.unwrap_or_else(|left| left);
+
+        info!(cost.requested = requested, cost.remaining = remaining, client = ?client, "Query cost");
+
+        if requested >= self.budgets.limits.expensive {
+            warn!(
+                cost.requested = requested,
+                client = ?client,
+                query = state.query.as_deref().unwrap_or_default(),
+                "Expensive query"
+            );
+        }
+
+        state.requested = requested;
+
+        match charged {
+            Ok(_) => Ok(result),
+            Err(left) => Err(vec![GqlError::RateLimited(format!(
+                "Query costs {requested}, but only {left} of the budget is left"
+            ))
+            .into_server_error()]),
+        }
+    }
+}
+
+fn with_cost(mut response: Response, budgets: &CostBudgets, state: &Mutex<CostState>) -> Response {
+    let Ok(state) = state.lock() else {
+        return response;
+    };
+
+    // Requests that never got validated did not cost anything
+    let remaining = match &state.client {
+        Some(client) => budgets.remaining(client, Instant::now()),
+        None => budgets.limits.budget,
+    };
+
+    let cost = IndexMap::from([
+        (Name::new("requested"), Value::from(state.requested as u64)),
+        (
+            Name::new("limit"),
+            Value::from(budgets.limits.budget as u64),
+        ),
+        (Name::new("remaining"), Value::from(remaining as u64)),
+    ]);
+
+    response
+        .extensions
+        .insert("cost".to_string(), Value::Object(cost));
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::infrastructure::query_limits::QueryLimits;
+
+    use super::{ClientId, CostBudgets};
+
+    fn budgets() -> CostBudgets {
+        CostBudgets::new(QueryLimits {
+            budget: 100,
+            budget_refill_per_second: 10,
+            ..QueryLimits::default()
+        })
+    }
+
+    #[test]
+    fn charges_until_exhausted() {
+        let budgets = budgets();
+        let now = Instant::now();
+
+        assert_eq!(Ok(40), budgets.charge(&ClientId::Anonymous, 60, now));
+        assert_eq!(Err(40), budgets.charge(&ClientId::Anonymous, 60, now));
+        assert_eq!(Ok(0), budgets.charge(&ClientId::Anonymous, 40, now));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let budgets = budgets();
+        let now = Instant::now();
+
+        assert_eq!(Ok(0), budgets.charge(&ClientId::Anonymous, 100, now));
+
+        let later = now + Duration::from_secs(3);
+        assert_eq!(Ok(10), budgets.charge(&ClientId::Anonymous, 20, later));
+
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(100, budgets.remaining(&ClientId::Anonymous, much_later));
+    }
+
+    #[test]
+    fn clients_have_separate_budgets() {
+        let budgets = budgets();
+        let now = Instant::now();
+        let client = ClientId::ApiKey("key".to_string());
+
+        assert_eq!(Ok(0), budgets.charge(&client, 100, now));
+        assert_eq!(Ok(0), budgets.charge(&ClientId::Anonymous, 100, now));
+        assert_eq!(Err(0), budgets.charge(&client, 1, now));
+    }
+
+    #[test]
+    fn draining_buckets_are_not_evicted() {
+        let budgets = CostBudgets::tracking(
+            QueryLimits {
+                budget: 100,
+                budget_refill_per_second: 10,
+                ..QueryLimits::default()
+            },
+            2,
+        );
+        let now = Instant::now();
+        let key = |key: &str| ClientId::ApiKey(key.to_string());
+
+        assert_eq!(Ok(0), budgets.charge(&key("a"), 100, now));
+        assert_eq!(Ok(0), budgets.charge(&key("b"), 100, now));
+
+        // Rotating through new clients neither resets the drained ones nor gets fresh budgets
+        assert_eq!(Ok(50), budgets.charge(&key("c"), 50, now));
+        assert_eq!(Ok(0), budgets.charge(&key("d"), 50, now));
+        assert_eq!(Err(0), budgets.charge(&key("a"), 1, now));
+
+        // Until the least recently used one refilled
+        let later = now + Duration::from_secs(10);
+        assert_eq!(Ok(90), budgets.charge(&key("e"), 10, later));
+    }
+}
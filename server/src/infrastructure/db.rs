@@ -1,93 +1,398 @@
-use std::{future::Future, ops::DerefMut};
+use std::{
+    future::Future,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    time::Duration,
+};
 
-use async_graphql::dataloader::{DataLoader, HashMapCache};
-use deadpool_postgres::{Manager, ManagerConfig, Pool};
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool};
 use postgres_types::ToSql;
-use tokio::{spawn, task::JoinHandle};
+use refinery::Target;
+use time::OffsetDateTime;
+use tokio::{spawn, time::sleep};
 use tokio_postgres::{tls::NoTlsStream, Client, Config, Connection, NoTls, Row, Socket};
-use tracing::{instrument, Instrument};
+use tracing::{instrument, warn};
 
-use crate::domain::{
-    app_user::{AppUserLoader, FriendIdLoader},
-    comment::{CommentLoader, CommentsOfPostLoader},
-    post::{PostLoader, PostsOfAuthorLoader},
+use super::{
+    config::PgConfig,
+    errors::{DbError, InfrastructureError},
 };
 
-use super::errors::{DbError, InfrastructureError};
-
 mod default {
     use refinery::embed_migrations;
 
     embed_migrations!();
 }
 
-mod debug {
+// Outside of migrations, which embeds its subdirectories too
+mod seeds {
     use refinery::embed_migrations;
 
-    embed_migrations!("migrations/debug");
+    embed_migrations!("seeds");
 }
 
-#[instrument(skip_all, err)]
-pub async fn initiate_repo() -> Result<Repo, InfrastructureError> {
-    let host = dotenvy::var("PG_HOST")?;
-    let port = dotenvy::var("PG_PORT")?;
-    let port = port
-        .parse()
-        .map_err(|_| InfrastructureError::env_invalid(format!("Invalid port: {port}")))?;
-    let dbname = dotenvy::var("PG_DBNAME")?;
-    let user = dotenvy::var("PG_USER")?;
-    let password = dotenvy::var("PG_PASSWORD")?;
+// Seeds are not part of the schema, so they keep a history of their own
+const SEED_HISTORY: &str = "refinery_seed_history";
 
+#[instrument(skip_all, err)]
+pub async fn initiate_repo(pg: &PgConfig) -> Result<Repo, InfrastructureError> {
     let mut config = Config::new();
-    config.host(&host);
-    config.port(port);
-    config.dbname(&dbname);
-    config.user(&user);
-    config.password(&password);
+    config.host(&pg.host);
+    config.port(pg.port);
+    config.dbname(&pg.dbname);
+    config.user(&pg.user);
+    config.password(pg.password.expose());
 
+    connect(config, TransactionRetries::from(pg))
+}
+
+pub fn connect(config: Config, retries: TransactionRetries) -> Result<Repo, InfrastructureError> {
     let manager = Manager::from_config(config.clone(), NoTls, ManagerConfig::default());
 
     let pool = Pool::builder(manager).build()?;
 
-    let repo = Repo::new(pool, config);
-
-    Ok(repo)
+    Ok(Repo::new(pool, config, retries))
 }
 
-#[instrument(skip_all, err)]
 pub async fn migrate(repo: &Repo) -> Result<(), InfrastructureError> {
+    migrate_to(repo, None).await.map(|_| ())
+}
+
+/// Migrates up to and including `target`, or all the way. Returns what it applied.
+#[instrument(skip(repo), err)]
+pub async fn migrate_to(
+    repo: &Repo,
+    target: Option<u32>,
+) -> Result<Vec<String>, InfrastructureError> {
     let mut db = repo.pool.get().await?;
 
-    default::migrations::runner()
+    let report = default::migrations::runner()
         .set_abort_missing(false)
+        .set_target(target.map_or(Target::Latest, Target::Version))
         .run_async(db.deref_mut().deref_mut())
         .await?;
 
-    debug::migrations::runner()
-        .set_abort_missing(false)
+    Ok(report
+        .applied_migrations()
+        .iter()
+        .map(ToString::to_string)
+        .collect())
+}
+
+/// A migration of this build, or one the db knows from another build.
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: u32,
+    pub name: String,
+    pub applied_on: Option<OffsetDateTime>,
+    pub known: bool,
+}
+
+/// Every migration in order of version, whether applied or not.
+#[instrument(skip(repo), err)]
+pub async fn migration_status(repo: &Repo) -> Result<Vec<MigrationStatus>, InfrastructureError> {
+    let mut db = repo.pool.get().await?;
+    let runner = default::migrations::runner();
+
+    // Asking for the history of a db that never migrated would fail
+    let migrated: bool = db
+        .query_one(
+            "SELECT to_regclass('refinery_schema_history') IS NOT NULL",
+            &[],
+        )
+        .await?
+        .try_get(0)?;
+    let applied = if migrated {
+        runner
+            .get_applied_migrations_async(db.deref_mut().deref_mut())
+            .await?
+    } else {
+        Vec::new()
+    };
+
+    let mut status: Vec<MigrationStatus> = runner
+        .get_migrations()
+        .iter()
+        .map(|migration| MigrationStatus {
+            version: migration.version(),
+            name: migration.name().to_string(),
+            applied_on: applied
+                .iter()
+                .find(|applied| applied.version() == migration.version())
+                .and_then(|applied| applied.applied_on().copied()),
+            known: true,
+        })
+        .collect();
+
+    status.extend(
+        applied
+            .iter()
+            .filter(|applied| {
+                !status
+                    .iter()
+                    .any(|known| known.version == applied.version())
+            })
+            .map(|applied| MigrationStatus {
+                version: applied.version(),
+                name: applied.name().to_string(),
+                applied_on: applied.applied_on().copied(),
+                known: false,
+            })
+            .collect::<Vec<_>>(),
+    );
+    status.sort_by_key(|migration| migration.version);
+
+    Ok(status)
+}
+
+/// How much a seed generates. Friendships are drawn at random, so duplicates make for fewer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeedSizes {
+    pub users: u32,
+    pub posts: u32,
+    pub comments: u32,
+    pub friendships: u32,
+}
+
+impl Default for SeedSizes {
+    fn default() -> Self {
+        Self {
+            users: 10_000,
+            posts: 100_000,
+            comments: 100_000,
+            friendships: 100_000,
+        }
+    }
+}
+
+impl SeedSizes {
+    pub fn validate(&self) -> Result<(), InfrastructureError> {
+        if self.posts > 0 && self.users == 0 {
+            return Err(InfrastructureError::arguments(
+                "Posts need users to be seeded".to_string(),
+            ));
+        }
+
+        if self.comments > 0 && self.posts == 0 {
+            return Err(InfrastructureError::arguments(
+                "Comments need posts to be seeded".to_string(),
+            ));
+        }
+
+        if self.friendships > 0 && self.users < 2 {
+            return Err(InfrastructureError::arguments(
+                "Friendships need at least two users to be seeded".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Fills the db with generated users, posts and comments, unless it was seeded before.
+/// Returns whether it seeded.
+#[instrument(skip(repo), err)]
+pub async fn seed(repo: &Repo, sizes: SeedSizes) -> Result<bool, InfrastructureError> {
+    sizes.validate()?;
+
+    let mut db = repo.pool.get().await?;
+
+    // The seed reads its sizes from the session
+    db.batch_execute(&format!(
+        r"
+            SET seed.users = {};
+            SET seed.posts = {};
+            SET seed.comments = {};
+            SET seed.friendships = {};
+        ",
+        sizes.users, sizes.posts, sizes.comments, sizes.friendships
+    ))
+    .await?;
+
+    let report = seeds::migrations::runner()
+        .set_migration_table_name(SEED_HISTORY)
         .run_async(db.deref_mut().deref_mut())
-        .await?;
+        .await;
+
+    // Pooled connections outlive the session settings
+    db.batch_execute(
+        "RESET seed.users; RESET seed.posts; RESET seed.comments; RESET seed.friendships",
+    )
+    .await?;
+
+    Ok(!report?.applied_migrations().is_empty())
+}
+
+/// How often a transaction runs before its serialization failure is given up on. The wait
+/// before a retry doubles with every attempt.
+#[derive(Debug, Clone, Copy)]
+pub struct TransactionRetries {
+    attempts: u32,
+    first_retry: Duration,
+}
+
+impl From<&PgConfig> for TransactionRetries {
+    fn from(pg: &PgConfig) -> Self {
+        Self {
+            attempts: pg.transaction_attempts,
+            first_retry: Duration::from_millis(pg.transaction_retry_ms),
+        }
+    }
+}
+
+impl Default for TransactionRetries {
+    fn default() -> Self {
+        Self::from(&PgConfig::default())
+    }
+}
+
+// Picked per transaction, the weakest one that keeps its statements consistent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Isolation {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl Isolation {
+    fn begin(&self) -> &'static str {
+        match self {
+            Isolation::ReadCommitted => "BEGIN ISOLATION LEVEL READ COMMITTED",
+            Isolation::RepeatableRead => "BEGIN ISOLATION LEVEL REPEATABLE READ",
+            Isolation::Serializable => "BEGIN ISOLATION LEVEL SERIALIZABLE",
+        }
+    }
+}
+
+// The connection of a repo inside a transaction, or one from the pool. Only ever
+// lives for a statement, so the size of a pooled object does not matter.
+#[allow(clippy::large_enum_variant)]
+enum Db<'a> {
+    Pooled(Object),
+    Transaction(&'a Object),
+}
+
+impl Deref for Db<'_> {
+    type Target = Object;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Db::Pooled(object) => object,
+            Db::Transaction(object) => object,
+        }
+    }
+}
+
+/* Rolls back transactions that ended without a commit. A future dropped midway would
+otherwise hand a connection that is still inside the transaction back to the pool. */
+struct RollbackGuard(Option<Arc<Object>>);
+
+impl RollbackGuard {
+    async fn commit(mut self) -> Result<(), DbError> {
+        if let Some(db) = self.0.take() {
+            db.batch_execute("COMMIT")
+                .await
+                .map_err(DbError::statement)?;
+        }
 
-    Ok(())
+        Ok(())
+    }
+
+    async fn rollback(mut self) {
+        if let Some(db) = self.0.take() {
+            if let Err(e) = db.batch_execute("ROLLBACK").await {
+                warn!("Could not roll back transaction: {e}");
+            }
+        }
+    }
 }
 
-fn spawn_in_span<F>(future: F) -> JoinHandle<F::Output>
-where
-    F: Future + Send + 'static,
-    F::Output: Send + 'static,
-{
-    spawn(future.in_current_span())
+impl Drop for RollbackGuard {
+    fn drop(&mut self) {
+        if let Some(db) = self.0.take() {
+            spawn(async move {
+                if let Err(e) = db.batch_execute("ROLLBACK").await {
+                    warn!("Could not roll back transaction: {e}");
+                }
+            });
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Repo {
     pool: Pool,
     config: Config,
+    retries: TransactionRetries,
+    transaction: Option<Arc<Object>>,
 }
 
 impl Repo {
-    pub fn new(pool: Pool, config: Config) -> Self {
-        Self { pool, config }
+    pub fn new(pool: Pool, config: Config, retries: TransactionRetries) -> Self {
+        Self {
+            pool,
+            config,
+            retries,
+            transaction: None,
+        }
+    }
+
+    async fn db(&self) -> Result<Db<'_>, DbError> {
+        match &self.transaction {
+            Some(db) => Ok(Db::Transaction(db)),
+            None => Ok(Db::Pooled(self.pool.get().await?)),
+        }
+    }
+
+    /// Runs `f` with a repo whose statements all go to one transaction, which commits
+    /// once `f` succeeds. Serialization failures and deadlocks run `f` again, so it should
+    /// not have effects outside the db. Inside a transaction `f` joins the running one.
+    #[instrument(skip(self, f), err)]
+    pub async fn transaction<T, F, Fut>(&self, isolation: Isolation, f: F) -> Result<T, DbError>
+    where
+        F: Fn(Repo) -> Fut,
+        Fut: Future<Output = Result<T, DbError>>,
+    {
+        if self.transaction.is_some() {
+            return f(self.clone()).await;
+        }
+
+        let mut retry = self.retries.first_retry;
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            let db = Arc::new(self.pool.get().await?);
+
+            db.batch_execute(isolation.begin())
+                .await
+                .map_err(DbError::statement)?;
+
+            let guard = RollbackGuard(Some(db.clone()));
+            let tx = Repo {
+                pool: self.pool.clone(),
+                config: self.config.clone(),
+                retries: self.retries,
+                transaction: Some(db),
+            };
+
+            let result = match f(tx).await {
+                Ok(value) => guard.commit().await.map(|_| value),
+                Err(e) => {
+                    guard.rollback().await;
+                    Err(e)
+                }
+            };
+
+            match result {
+                Err(e) if e.is_serialization_failure() && attempt < self.retries.attempts => {
+                    warn!("Retrying transaction after attempt {attempt}: {e}");
+                    sleep(retry).await;
+                    retry *= 2;
+                }
+                result => return result,
+            }
+        }
     }
 
     pub(super) async fn new_connection(
@@ -106,7 +411,7 @@ impl Repo {
         params: &[&(dyn ToSql + Sync)],
         mapper: fn(Row) -> Result<T, Err>,
     ) -> Result<T, DbError> {
-        let db = self.pool.get().await?;
+        let db = self.db().await?;
 
         let prepared_statement = db
             .prepare_cached(statement)
@@ -128,7 +433,7 @@ impl Repo {
         params: &[&(dyn ToSql + Sync)],
         mapper: fn(Vec<Row>) -> Result<T, Err>,
     ) -> Result<T, DbError> {
-        let db = self.pool.get().await?;
+        let db = self.db().await?;
 
         let prepared_statement = db
             .prepare_cached(statement)
@@ -149,7 +454,7 @@ impl Repo {
         statement: &str,
         params: &[&(dyn ToSql + Sync)],
     ) -> Result<(), DbError> {
-        let db = self.pool.get().await?;
+        let db = self.db().await?;
 
         let prepared_statement = db
             .prepare_cached(statement)
@@ -169,57 +474,239 @@ impl Repo {
     }
 }
 
-pub struct Loaders {
-    pub app_user: DataLoader<AppUserLoader, HashMapCache>,
-    pub friend_id: DataLoader<FriendIdLoader, HashMapCache>,
-    pub post: DataLoader<PostLoader, HashMapCache>,
-    pub posts_of_author: DataLoader<PostsOfAuthorLoader, HashMapCache>,
-    pub comment: DataLoader<CommentLoader, HashMapCache>,
-    pub comments_of_post: DataLoader<CommentsOfPostLoader, HashMapCache>,
-}
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use crate::infrastructure::{
+        testing::{TestApp, TestDb},
+        DbError,
+    };
+
+    use super::{seed, Isolation, PgConfig, Repo, SeedSizes};
+
+    const SIZES: SeedSizes = SeedSizes {
+        users: 20,
+        posts: 50,
+        comments: 80,
+        friendships: 30,
+    };
+
+    #[test]
+    fn seed_sizes_need_what_they_refer_to() {
+        assert!(SIZES.validate().is_ok());
+        assert!(SeedSizes::default().validate().is_ok());
+
+        for sizes in [
+            SeedSizes { users: 0, ..SIZES },
+            SeedSizes { posts: 0, ..SIZES },
+            SeedSizes { users: 1, ..SIZES },
+        ] {
+            assert!(sizes.validate().is_err(), "{sizes:?}");
+        }
+    }
 
-impl Loaders {
-    pub fn new(repo: Repo) -> Self {
-        Self {
-            app_user: DataLoader::with_cache(
-                AppUserLoader::new(repo.clone()),
-                spawn_in_span,
-                HashMapCache::default(),
-            ),
-            friend_id: DataLoader::with_cache(
-                FriendIdLoader::new(repo.clone()),
-                spawn_in_span,
-                HashMapCache::default(),
-            ),
-            post: DataLoader::with_cache(
-                PostLoader::new(repo.clone()),
-                spawn_in_span,
-                HashMapCache::default(),
-            ),
-            posts_of_author: DataLoader::with_cache(
-                PostsOfAuthorLoader::new(repo.clone()),
-                spawn_in_span,
-                HashMapCache::default(),
-            ),
-            comment: DataLoader::with_cache(
-                CommentLoader::new(repo.clone()),
-                spawn_in_span,
-                HashMapCache::default(),
-            ),
-            comments_of_post: DataLoader::with_cache(
-                CommentsOfPostLoader::new(repo),
-                spawn_in_span,
-                HashMapCache::default(),
-            ),
-        }
-    }
-
-    pub fn clear_caches(&self) {
-        self.app_user.clear();
-        self.friend_id.clear();
-        self.post.clear();
-        self.posts_of_author.clear();
-        self.comment.clear();
-        self.comments_of_post.clear();
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn seeds_once_and_keeps_sequences_ahead() {
+        let db = TestDb::new().await;
+
+        assert!(seed(&db.repo, SIZES).await.unwrap());
+        assert!(!seed(&db.repo, SIZES).await.unwrap());
+
+        let counts: Vec<i64> = db
+            .repo
+            .query_one(
+                r"
+                    SELECT
+                        (SELECT COUNT(*) FROM app_user),
+                        (SELECT COUNT(*) FROM post),
+                        (SELECT COUNT(*) FROM comment),
+                        (SELECT COUNT(*) FROM refinery_seed_history),
+                        (SELECT COUNT(*) FROM refinery_schema_history WHERE name = 'generated_content')
+                ",
+                &[],
+                |row| {
+                    (0..5)
+                        .map(|i| row.try_get(i))
+                        .collect::<Result<_, tokio_postgres::Error>>()
+                        .map_err(DbError::mapping)
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(vec![20, 50, 80, 1, 0], counts);
+
+        // New rows get ids after the seeded ones
+        let app = TestApp::on(&db).await;
+        let (user, _) = app.user("New").await;
+        assert_eq!(21, *user);
+        let post_id = app.post(user, "New").await;
+        app.comment(user, &post_id, "New").await;
+
+        db.drop().await;
+    }
+
+    async fn numbers(repo: &Repo) -> Vec<i32> {
+        repo.query("SELECT n FROM number ORDER BY n", &[], |rows| {
+            rows.iter()
+                .map(|row| row.try_get(0))
+                .collect::<Result<_, tokio_postgres::Error>>()
+                .map_err(DbError::mapping)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn insert(repo: &Repo, n: i32) -> Result<(), DbError> {
+        repo.execute("INSERT INTO number VALUES ($1)", &[&n]).await
+    }
+
+    async fn number_table() -> TestDb {
+        let db = TestDb::new().await;
+        db.repo
+            .execute("CREATE TABLE number (n INT)", &[])
+            .await
+            .unwrap();
+
+        db
+    }
+
+    async fn fail_serialization(repo: &Repo) -> Result<(), DbError> {
+        repo.execute(
+            "DO $$ BEGIN RAISE EXCEPTION USING ERRCODE = 'serialization_failure'; END $$",
+            &[],
+        )
+        .await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn transactions_commit_or_roll_back() {
+        let db = number_table().await;
+
+        db.repo
+            .transaction(Isolation::Serializable, |tx| async move {
+                insert(&tx, 1).await?;
+                insert(&tx, 2).await
+            })
+            .await
+            .unwrap();
+
+        let failed = db
+            .repo
+            .transaction(Isolation::Serializable, |tx| async move {
+                insert(&tx, 3).await?;
+                Err::<(), _>(DbError::Missing("Number".to_string()))
+            })
+            .await;
+
+        assert!(matches!(failed, Err(DbError::Missing(_))));
+        assert_eq!(vec![1, 2], numbers(&db.repo).await);
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn transactions_run_again_on_serialization_failures() {
+        let db = number_table().await;
+        let attempts = AtomicU32::new(0);
+
+        db.repo
+            .transaction(Isolation::Serializable, |tx| {
+                let attempts = &attempts;
+
+                async move {
+                    insert(&tx, 1).await?;
+
+                    if attempts.fetch_add(1, Ordering::Relaxed) < 2 {
+                        fail_serialization(&tx).await?;
+                    }
+
+                    Ok(())
+                }
+            })
+            .await
+            .unwrap();
+
+        // The failed attempts left nothing behind
+        assert_eq!(3, attempts.load(Ordering::Relaxed));
+        assert_eq!(vec![1], numbers(&db.repo).await);
+
+        let attempts = AtomicU32::new(0);
+        let failed = db
+            .repo
+            .transaction(Isolation::Serializable, |tx| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async move { fail_serialization(&tx).await }
+            })
+            .await;
+
+        assert!(failed.unwrap_err().is_serialization_failure());
+        assert_eq!(
+            PgConfig::default().transaction_attempts,
+            attempts.load(Ordering::Relaxed)
+        );
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn nested_transactions_join_the_running_one() {
+        let db = number_table().await;
+        let outside = &db.repo;
+
+        let failed = db
+            .repo
+            .transaction(Isolation::Serializable, |tx| async move {
+                insert(&tx, 1).await?;
+
+                tx.transaction(Isolation::Serializable, |nested| async move {
+                    assert_eq!(vec![1], numbers(&nested).await);
+                    insert(&nested, 2).await
+                })
+                .await?;
+
+                assert_eq!(vec![1, 2], numbers(&tx).await);
+                assert!(numbers(outside).await.is_empty());
+
+                Err::<(), _>(DbError::Missing("Number".to_string()))
+            })
+            .await;
+
+        // Nothing the nested one did survives the outer rollback
+        assert!(failed.is_err());
+        assert!(numbers(&db.repo).await.is_empty());
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn transactions_begin_at_their_isolation() {
+        let db = TestDb::new().await;
+
+        for (isolation, expected) in [
+            (Isolation::ReadCommitted, "read committed"),
+            (Isolation::RepeatableRead, "repeatable read"),
+            (Isolation::Serializable, "serializable"),
+        ] {
+            let level = db
+                .repo
+                .transaction(isolation, |tx| async move {
+                    tx.query_one("SHOW transaction_isolation", &[], |row| {
+                        row.try_get::<_, String>(0).map_err(DbError::mapping)
+                    })
+                    .await
+                })
+                .await
+                .unwrap();
+
+            assert_eq!(expected, level);
+        }
+
+        db.drop().await;
     }
 }
@@ -2,19 +2,20 @@ use axum::response::{IntoResponse, Response};
 use deadpool_postgres::{BuildError, PoolError};
 use hyper::StatusCode;
 use thiserror::Error;
+use tokio_postgres::error::SqlState;
 
 #[derive(Debug, Error)]
 pub enum InfrastructureError {
+    #[error("Arguments were invalid: {0}")]
+    Arguments(String),
     #[error("Db failed on pool connection: {0}")]
     DbPoolConnection(#[from] PoolError),
     #[error("Db failed on separate connection: {0}")]
     DbExplicitConnection(#[from] tokio_postgres::Error),
     #[error("Db failed on startup: {0}")]
     DbStartup(#[from] BuildError),
-    #[error("Env had missing values: {0}")]
-    Env(#[from] dotenvy::Error),
-    #[error("Env had invalid values: {0}")]
-    EnvInvalid(String),
+    #[error("Config was invalid:\n  {}", .0.join("\n  "))]
+    Config(Vec<String>),
     #[error("Filesystem did not cooperate: {0}")]
     Filesystem(#[from] std::io::Error),
     #[error("Health check failed: {0}")]
@@ -32,8 +33,12 @@ impl From<refinery::Error> for InfrastructureError {
 }
 
 impl InfrastructureError {
-    pub fn env_invalid(msg: String) -> Self {
-        Self::EnvInvalid(msg)
+    pub fn arguments(msg: String) -> Self {
+        Self::Arguments(msg)
+    }
+
+    pub fn config(errors: Vec<String>) -> Self {
+        Self::Config(errors)
     }
 
     pub fn health(e: impl std::error::Error + 'static) -> Self {
@@ -53,6 +58,8 @@ pub enum DbError {
     ConnectionFailed(#[source] PoolError),
     #[error("Could not parse row: {0}")]
     Mapping(#[source] tokio_postgres::Error),
+    #[error("Expected row was missing: {0}")]
+    Missing(String),
     #[error("Could not execute statement: {0}")]
     Statement(#[source] tokio_postgres::Error),
 }
@@ -62,6 +69,19 @@ impl DbError {
         Self::Mapping(e)
     }
 
+    pub fn is_serialization_failure(&self) -> bool {
+        let Self::Statement(e) = self else {
+            return false;
+        };
+
+        [
+            SqlState::T_R_SERIALIZATION_FAILURE,
+            SqlState::T_R_DEADLOCK_DETECTED,
+        ]
+        .iter()
+        .any(|state| e.code() == Some(state))
+    }
+
     pub fn statement(e: tokio_postgres::Error) -> Self {
         Self::Statement(e)
     }
@@ -79,8 +99,14 @@ pub enum NotificationCenterError {
     DaemonFailedToStart(String),
     #[error("The daemon seems to be dead: {0}")]
     DaemonDead(String),
+    #[error("The daemon lost its connection: {0}")]
+    Disconnected(String),
     #[error("Failed to subscribe to a topic")]
     SubscriptionFailed,
+    #[error("Listener fell more than {0} notifications behind")]
+    Overflowed(usize),
     #[error("Failed to parse Notification")]
     ParsingFailed,
+    #[error("Notification payload has unsupported version {0}")]
+    UnsupportedVersion(u32),
 }
@@ -1,20 +1,173 @@
-use async_graphql::http::GraphiQLSource;
+use std::{convert::Infallible, net::SocketAddr};
+
+use async_graphql::{http::GraphiQLSource, Data, Request, Value};
 use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
 use axum::{
-    extract::State,
-    response::{Html, IntoResponse},
+    extract::{ConnectInfo, State, WebSocketUpgrade},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
 };
+use futures::{stream, StreamExt};
 use tracing::instrument;
 
-use super::{app_state::AppState, db::Loaders, errors::InfrastructureError};
+use crate::domain::{auth::Session, db_id::DbId, schema::LastEventId};
+
+use super::{
+    app_state::AppState, cost::ClientId, errors::InfrastructureError, storage::Loaders, websocket,
+};
+
+fn session() -> Session {
+    Session::new(DbId::from(1)) // Placeholder until we have auth
+}
+
+/* Budgets are kept per API key we handed out, per address otherwise. Unknown keys would
+get a fresh budget each, and every session is the placeholder for now. */
+fn client_id(state: &AppState, headers: &HeaderMap, addr: SocketAddr) -> ClientId {
+    headers
+        .get("x-api-key")
+        .and_then(|key| key.to_str().ok())
+        .filter(|key| state.config.http.api_keys.contains(key))
+        .map(|key| ClientId::ApiKey(key.to_string()))
+        .unwrap_or(ClientId::Address(addr.ip()))
+}
+
+// Requests over plain HTTP and SSE are authenticated alike
+fn with_request_data(req: Request, state: &AppState, client: ClientId) -> Request {
+    req.data(Loaders::new(state.storage.clone()))
+        .data(client)
+        .data(session())
+}
+
+// Feed events carry the cursor of their newest post, which is where a reconnect resumes
+fn event_id(data: &Value) -> Option<String> {
+    let Value::Object(fields) = data else {
+        return None;
+    };
+
+    fields.values().find_map(|field| {
+        let Value::List(edges) = field else {
+            return None;
+        };
+
+        edges.iter().rev().find_map(|edge| match edge {
+            Value::Object(edge) => match edge.get("cursor") {
+                Some(Value::String(cursor)) => Some(cursor.clone()),
+                _ => None,
+            },
+            _ => None,
+        })
+    })
+}
 
 pub async fn graphql_handler(
     State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     req: GraphQLRequest,
 ) -> GraphQLResponse {
-    let req_with_loaders = req.into_inner().data(Loaders::new(state.repo));
+    let client = client_id(&state, &headers, addr);
+    let req_with_data = with_request_data(req.into_inner(), &state, client);
+
+    state.schema.execute(req_with_data).await.into()
+}
+
+/// Subscriptions for clients that cannot keep a websocket open, following graphql-sse.
+pub async fn graphql_stream_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    req: GraphQLRequest,
+) -> Response {
+    let client = client_id(&state, &headers, addr);
+
+    // Streams count towards the same limit as subscriptions over websockets
+    let Some(permit) = state.subscriptions.acquire(&client) else {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many subscriptions of this client",
+        )
+            .into_response();
+    };
+
+    let mut req_with_data = with_request_data(req.into_inner(), &state, client);
+
+    if let Some(last_event_id) = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+    {
+        req_with_data = req_with_data.data(LastEventId(last_event_id.to_string()));
+    }
+
+    let mut shutdown = state.shutdown;
 
-    state.schema.execute(req_with_loaders).await.into()
+    let events = state
+        .schema
+        .execute_stream(req_with_data)
+        .map(|response| {
+            let event = Event::default().event("next");
+            let event = match event_id(&response.data) {
+                Some(id) => event.id(id),
+                None => event,
+            };
+
+            // A response always serializes
+            event.json_data(response).unwrap_or_default()
+        })
+        .chain(stream::once(async {
+            Event::default().event("complete").data("")
+        }))
+        // Left without completing, so clients reconnect to another instance
+        .take_until(async move {
+            let _ = shutdown.changed().await;
+        })
+        .map(move |event| {
+            // The slot is taken until the client goes away
+            let _ = &permit;
+            Ok::<_, Infallible>(event)
+        });
+
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+pub async fn graphql_ws_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let Some(protocol) = websocket::negotiate(&headers) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Expected subprotocol graphql-transport-ws or graphql-ws",
+        )
+            .into_response();
+    };
+
+    let client = client_id(&state, &headers, addr);
+
+    let mut data = Data::default();
+    data.insert(client.clone());
+    data.insert(session());
+
+    upgrade
+        .protocols([protocol.sec_websocket_protocol()])
+        .on_upgrade(move |socket| {
+            websocket::serve(
+                socket,
+                state.schema,
+                protocol,
+                client,
+                data,
+                state.subscriptions,
+                state.config.subscriptions.clone(),
+                state.shutdown,
+            )
+        })
 }
 
 pub async fn graphiql() -> impl IntoResponse {
@@ -32,5 +185,216 @@ pub async fn health_check(State(state): State<AppState>) -> Result<(), Infrastru
         .repo
         .health()
         .await
+        .map_err(InfrastructureError::health)?;
+
+    state
+        .notification_center
+        .health()
         .map_err(InfrastructureError::health)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use async_graphql_axum::GraphQLRequest;
+    use axum::{
+        body::{Body, BodyDataStream},
+        extract::{ConnectInfo, FromRequest, State},
+        http::{self, header::CONTENT_TYPE, HeaderMap, HeaderValue, StatusCode},
+        response::Response,
+    };
+    use futures::StreamExt;
+    use serde_json::{json, Value};
+    use tokio::{sync::watch, time::timeout};
+
+    use crate::{
+        domain::db_id::DbId,
+        infrastructure::{
+            app_state::AppState,
+            config::Config,
+            testing::{TestApp, TestDb},
+            websocket::SubscriptionLimiter,
+        },
+    };
+
+    use super::graphql_stream_handler;
+
+    struct Event {
+        kind: String,
+        id: Option<String>,
+        data: String,
+    }
+
+    /// Reads the events of a graphql-sse response as they come.
+    struct Events {
+        body: BodyDataStream,
+        buffer: String,
+    }
+
+    impl Events {
+        fn of(response: Response) -> Self {
+            Self {
+                body: response.into_body().into_data_stream(),
+                buffer: String::new(),
+            }
+        }
+
+        // Like TestApp::subscribe, nothing published afterwards is missed
+        async fn listening(response: Response) -> Self {
+            let mut events = Self::of(response);
+
+            assert!(timeout(Duration::from_millis(50), events.body.next())
+                .await
+                .is_err());
+
+            events
+        }
+
+        async fn next(&mut self) -> Option<Event> {
+            loop {
+                // Keep-alive comments come without an event
+                while let Some(end) = self.buffer.find("\n\n") {
+                    let block: String = self.buffer.drain(..end + 2).collect();
+                    let mut event = Event {
+                        kind: String::new(),
+                        id: None,
+                        data: String::new(),
+                    };
+
+                    for (field, value) in block.lines().filter_map(|line| line.split_once(':')) {
+                        let value = value.strip_prefix(' ').unwrap_or(value).to_string();
+
+                        match field {
+                            "event" => event.kind = value,
+                            "id" => event.id = Some(value),
+                            "data" => event.data = value,
+                            _ => {}
+                        }
+                    }
+
+                    if !event.kind.is_empty() {
+                        return Some(event);
+                    }
+                }
+
+                let chunk = timeout(Duration::from_secs(5), self.body.next())
+                    .await
+                    .expect("Stream should have sent something")?
+                    .unwrap();
+                self.buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+            }
+        }
+
+        async fn next_content(&mut self) -> (Option<String>, String) {
+            let event = self.next().await.expect("Stream should not have ended");
+            assert_eq!("next", event.kind);
+
+            let data: Value = serde_json::from_str(&event.data).unwrap();
+            let content = data["data"]["userFeed"][0]["node"]["content"]
+                .as_str()
+                .unwrap()
+                .to_string();
+
+            (event.id, content)
+        }
+    }
+
+    async fn post(app: &TestApp, author: DbId, content: &str) -> String {
+        let query = format!(
+            r#"mutation {{ createPost(input: {{ content: "{content}" }}) {{ edge {{ cursor }} }} }}"#
+        );
+        let data = app.execute(author, &query).await;
+
+        data["createPost"]["edge"]["cursor"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn streams_events_that_resume_where_they_left_off() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, alice_id) = app.user("Alice").await;
+
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let state = AppState {
+            config: Arc::new(Config::default()),
+            repo: db.repo.clone(),
+            storage: app.storage.clone(),
+            notification_center: app.notification_center.clone(),
+            schema: app.schema.clone(),
+            graphiql: false,
+            subscriptions: SubscriptionLimiter::new(1),
+            shutdown: shutdown_rx,
+        };
+
+        let stream = |query: &str, headers: HeaderMap| {
+            let body = json!({ "query": query }).to_string();
+            let state = state.clone();
+
+            async move {
+                let req = http::Request::post("/graphql/stream")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .unwrap();
+                let req = GraphQLRequest::from_request(req, &()).await;
+
+                let Ok(req) = req else {
+                    panic!("Request should have been a GraphQL request");
+                };
+
+                graphql_stream_handler(
+                    State(state),
+                    ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))),
+                    headers,
+                    req,
+                )
+                .await
+            }
+        };
+        let feed = format!(
+            r#"subscription {{ userFeed(userId: "{alice_id}") {{ cursor node {{ content }} }} }}"#
+        );
+
+        let mut events = Events::listening(stream(&feed, HeaderMap::new()).await).await;
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS,
+            stream(&feed, HeaderMap::new()).await.status()
+        );
+
+        let seen = post(&app, alice, "Seen").await;
+        assert_eq!(
+            (Some(seen.clone()), "Seen".to_string()),
+            events.next_content().await
+        );
+
+        // Posted while the client reconnects
+        drop(events);
+        let missed = post(&app, alice, "Missed").await;
+
+        // Anything but a subscription is a single event
+        let mut events = Events::of(stream("{ __typename }", HeaderMap::new()).await);
+        let event = events.next().await.unwrap();
+        assert_eq!(("next", None), (event.kind.as_str(), event.id));
+        assert_eq!("complete", events.next().await.unwrap().kind);
+        assert!(events.next().await.is_none());
+        drop(events);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("last-event-id", HeaderValue::from_str(&seen).unwrap());
+        let mut events = Events::of(stream(&feed, headers).await);
+        assert_eq!(
+            (Some(missed), "Missed".to_string()),
+            events.next_content().await
+        );
+
+        // Ends without completing, so the client reconnects elsewhere
+        shutdown.send(true).unwrap();
+        assert!(events.next().await.is_none());
+
+        db.drop().await;
+    }
+}
@@ -68,3 +68,18 @@ pub fn current_span_as_headers() -> HeaderMap {
 
     HeaderMap::try_from(&header_map).unwrap_or_default()
 }
+
+// Falls back to a random id, so errors can always be correlated with logs
+pub fn current_trace_id() -> String {
+    use opentelemetry::trace::TraceContextExt;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let context = Span::current().context();
+    let span_context = context.span().span_context().clone();
+
+    if span_context.is_valid() {
+        span_context.trace_id().to_string()
+    } else {
+        uuid::Uuid::new_v4().simple().to_string()
+    }
+}
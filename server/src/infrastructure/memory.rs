@@ -0,0 +1,115 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Mutex, MutexGuard},
+};
+
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use time::OffsetDateTime;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::domain::{app_user::AppUser, comment::Comment, db_id::DbId, post::Post, report::Report};
+
+use super::{
+    errors::{DbError, InfrastructureError},
+    notification_center::{Notification, NotificationSource, Notifications},
+};
+
+/// Keeps everything in memory and notifies like the triggers of the db do, so the
+/// whole schema runs without Postgres. Each aggregate implements its store on it.
+pub struct MemoryStore {
+    tables: Mutex<Tables>,
+    notifications: broadcast::Sender<Notification>,
+}
+
+#[derive(Default)]
+pub struct Tables {
+    last_id: i32,
+    pub users: BTreeMap<DbId, AppUser>,
+    // Ordered pairs, like the db keeps them
+    pub relations: BTreeSet<(DbId, DbId)>,
+    pub posts: BTreeMap<DbId, Hideable<Post>>,
+    pub comments: BTreeMap<DbId, Hideable<Comment>>,
+    pub reports: BTreeMap<DbId, Report>,
+    journal: Vec<(OffsetDateTime, Notification)>,
+}
+
+impl Tables {
+    /// Ids are unique over all tables, which the db does not promise but does not hurt.
+    pub fn next_id(&mut self) -> DbId {
+        self.last_id += 1;
+        DbId::from(self.last_id)
+    }
+}
+
+/// A row that moderation can hide, which the domain types do not carry.
+pub struct Hideable<T> {
+    pub row: T,
+    pub hidden: bool,
+}
+
+impl<T> Hideable<T> {
+    pub fn new(row: T) -> Self {
+        Self { row, hidden: false }
+    }
+
+    pub fn visible(&self) -> Option<&T> {
+        (!self.hidden).then_some(&self.row)
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self {
+            tables: Mutex::default(),
+            notifications: broadcast::channel(1024).0,
+        }
+    }
+}
+
+impl MemoryStore {
+    pub fn tables(&self) -> MutexGuard<'_, Tables> {
+        self.tables.lock().expect("Lock should not be poisoned")
+    }
+
+    /// Sent right away, where the db waits for the commit. Must not be called while
+    /// holding the tables.
+    pub fn notify(&self, notifications: impl IntoIterator<Item = Notification>) {
+        let now = OffsetDateTime::now_utc();
+
+        for notification in notifications {
+            self.tables().journal.push((now, notification.clone()));
+
+            // Nobody listening is fine
+            let _ = self.notifications.send(notification);
+        }
+    }
+}
+
+#[async_trait]
+impl NotificationSource for MemoryStore {
+    async fn listen(&self) -> Result<Notifications, InfrastructureError> {
+        let notifications = stream::unfold(self.notifications.subscribe(), |mut rx| async {
+            loop {
+                match rx.recv().await {
+                    Ok(notification) => return Some((notification, rx)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Ok(notifications.boxed())
+    }
+
+    // Anything announced counts, the listener copes with notifications it already had
+    async fn created_since(&self, since: OffsetDateTime) -> Result<Vec<Notification>, DbError> {
+        Ok(self
+            .tables()
+            .journal
+            .iter()
+            .filter(|(sent, _)| *sent >= since)
+            .map(|(_, notification)| notification.clone())
+            .collect())
+    }
+}
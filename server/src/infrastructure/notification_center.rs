@@ -1,146 +1,451 @@
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::ready,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use futures::StreamExt;
-use tokio::{spawn, sync::mpsc, time::sleep};
+use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize};
+use time::OffsetDateTime;
+use tokio::{
+    spawn,
+    sync::{mpsc, watch, Notify},
+    time::sleep,
+};
 use tokio_postgres::AsyncMessage;
-use tracing::{debug, error, warn};
+use tracing::{debug, info, instrument, warn};
 
 use crate::domain::db_id::DbId;
 
-use super::{db::Repo, errors::NotificationCenterError};
+use super::{
+    config::NotificationConfig,
+    db::Repo,
+    errors::{DbError, InfrastructureError, NotificationCenterError},
+};
+
+const CHANNELS: &str = r"
+    LISTEN post_notification;
+    LISTEN comment_notification;
+    LISTEN user_relation_notification;
+";
+
+// Notifications sent shortly before a loss may not have reached us either
+const RECONCILE_MARGIN: time::Duration = time::Duration::seconds(5);
+
+// Far more than gets created within the margin, whose replays have to be recognized
+const ROUTED_WINDOW: usize = 4096;
+
+/// Where notifications come from, a LISTEN connection to the db outside of tests.
+#[async_trait]
+pub trait NotificationSource: Send + Sync {
+    /// Notifications as they happen, until the connection is lost.
+    async fn listen(&self) -> Result<Notifications, InfrastructureError>;
+
+    /// What was created since, for what got lost with a connection. Friendships come
+    /// first, so feeds follow new friends before their posts arrive.
+    async fn created_since(&self, since: OffsetDateTime) -> Result<Vec<Notification>, DbError>;
+}
+
+pub type Notifications = BoxStream<'static, Notification>;
 
 #[derive(Clone)]
 pub struct NotificationCenter {
-    repo: Repo,
+    source: Arc<dyn NotificationSource>,
+    config: NotificationConfig,
     daemon_tx: Option<mpsc::Sender<NotificationCenterDaemonCommand>>,
+    state: Arc<watch::Sender<ListenerState>>,
+}
+
+/// Whether notifications of the database currently reach the subscribers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerState {
+    Stopped,
+    Listening,
+    Reconnecting { since: OffsetDateTime, attempt: u32 },
 }
 
 impl NotificationCenter {
-    pub fn new(repo: Repo) -> Self {
+    pub fn new(source: Arc<dyn NotificationSource>, config: NotificationConfig) -> Self {
         Self {
-            repo,
+            source,
+            config,
             daemon_tx: None,
+            state: Arc::new(watch::Sender::new(ListenerState::Stopped)),
+        }
+    }
+
+    pub fn state(&self) -> ListenerState {
+        *self.state.borrow()
+    }
+
+    pub fn health(&self) -> Result<(), NotificationCenterError> {
+        match self.state() {
+            ListenerState::Listening => Ok(()),
+            ListenerState::Stopped => Err(NotificationCenterError::DaemonDead(
+                "The daemon was never started".to_string(),
+            )),
+            ListenerState::Reconnecting { since, attempt } => {
+                Err(NotificationCenterError::Disconnected(format!(
+                    "Lost the db at {since}, reconnect attempt {attempt}"
+                )))
+            }
         }
     }
 
     pub async fn start_daemon(&mut self) -> Result<(), NotificationCenterError> {
-        let (tx, rx) = mpsc::channel::<NotificationCenterDaemonCommand>(32);
-        let maintainence_tx = tx.clone();
+        let (tx, rx) = mpsc::channel::<NotificationCenterDaemonCommand>(self.config.channel_size);
         self.daemon_tx = Some(tx.clone());
-        let repo = self.repo.clone();
-        let mut daemon = NotificationCenterDaemon::new(tx, rx);
+        let mut daemon = NotificationCenterDaemon::new(rx);
+
+        // The first connection has to work, so a broken setup fails on startup
+        let source_listener = SourceListener {
+            source: self.source.clone(),
+            daemon_tx: tx,
+            state: self.state.clone(),
+            first_retry: Duration::from_millis(self.config.first_retry_ms),
+            max_retry: Duration::from_millis(self.config.max_retry_ms),
+        };
+        let notifications = source_listener
+            .source
+            .listen()
+            .await
+            .map_err(|e| NotificationCenterError::DaemonFailedToStart(e.to_string()))?;
 
-        daemon.start_repo_listener(repo).await?;
+        spawn(source_listener.run(notifications));
 
         spawn(async move {
             daemon.listen().await;
             debug!("Notification daemon shut down");
         });
 
-        spawn(async move {
-            loop {
-                sleep(Duration::from_secs(10)).await;
-                let _ = maintainence_tx
-                    .send(NotificationCenterDaemonCommand::Maintainance)
-                    .await
-                    .inspect_err(|e| error!("Could not send the maintainance notification {}", e));
-            }
-        });
-
         Ok(())
     }
 
     pub async fn subscribe(
         &self,
         topics: Vec<ListenerTopic>,
+        policy: OverflowPolicy,
     ) -> Result<ListenerHandle, NotificationCenterError> {
-        let (tx, rx) = tokio::sync::mpsc::channel::<Notification>(16);
-        let listener = Listener { topics, tx };
+        self.add_listener(topics, policy, false).await
+    }
 
-        self.daemon_tx
+    /// Like `subscribe`, but queues up to the larger catch up capacity until the first
+    /// `receive`, for listeners that have to catch up on something else before they can
+    /// receive.
+    pub async fn subscribe_catching_up(
+        &self,
+        topics: Vec<ListenerTopic>,
+        policy: OverflowPolicy,
+    ) -> Result<ListenerHandle, NotificationCenterError> {
+        self.add_listener(topics, policy, true).await
+    }
+
+    async fn add_listener(
+        &self,
+        topics: Vec<ListenerTopic>,
+        policy: OverflowPolicy,
+        catching_up: bool,
+    ) -> Result<ListenerHandle, NotificationCenterError> {
+        let daemon_tx = self
+            .daemon_tx
             .as_ref()
-            .ok_or(NotificationCenterError::SubscriptionFailed)?
+            .ok_or(NotificationCenterError::SubscriptionFailed)?;
+
+        let (listener, handle) = Listener::new(
+            topics,
+            self.config.listener_capacity,
+            policy,
+            daemon_tx.clone(),
+        );
+        if catching_up {
+            handle.mailbox.catch_up(self.config.catch_up_capacity);
+        }
+
+        daemon_tx
             .send(NotificationCenterDaemonCommand::AddListener(listener))
             .await
             .map_err(|e| NotificationCenterError::DaemonDead(e.to_string()))?;
 
-        Ok(ListenerHandle::new(rx))
+        Ok(handle)
     }
 }
 
-#[derive(Debug, Clone)]
 enum NotificationCenterDaemonCommand {
     AddListener(Listener),
-    Maintainance,
+    RemoveListener(ListenerId),
     HandleNotification(Notification),
 }
 
-struct NotificationCenterDaemon {
-    listeners: Vec<Listener>,
-    tx: mpsc::Sender<NotificationCenterDaemonCommand>,
-    rx: mpsc::Receiver<NotificationCenterDaemonCommand>,
+/// Owns the connection to the source and replaces it whenever it breaks.
+struct SourceListener {
+    source: Arc<dyn NotificationSource>,
+    daemon_tx: mpsc::Sender<NotificationCenterDaemonCommand>,
+    state: Arc<watch::Sender<ListenerState>>,
+    first_retry: Duration,
+    max_retry: Duration,
 }
 
-impl NotificationCenterDaemon {
-    fn new(
-        tx: mpsc::Sender<NotificationCenterDaemonCommand>,
-        rx: mpsc::Receiver<NotificationCenterDaemonCommand>,
-    ) -> Self {
-        Self {
-            listeners: Vec::new(),
-            tx,
-            rx,
+impl SourceListener {
+    async fn run(self, mut notifications: Notifications) {
+        loop {
+            self.state.send_replace(ListenerState::Listening);
+            debug!("Started listener");
+
+            if !self.forward(&mut notifications).await {
+                break;
+            }
+
+            let lost_since = OffsetDateTime::now_utc();
+
+            match self.reconnect(lost_since).await {
+                Some(reconnected) => notifications = reconnected,
+                None => break,
+            }
         }
-    }
 
-    async fn start_repo_listener(&self, repo: Repo) -> Result<(), NotificationCenterError> {
-        let (client, mut connection) = repo
-            .new_connection()
-            .await
-            .map_err(|e| NotificationCenterError::DaemonFailedToStart(e.to_string()))?;
+        self.state.send_replace(ListenerState::Stopped);
+        debug!("Stopped listener");
+    }
 
-        let (tx, mut rx) = futures::channel::mpsc::channel::<AsyncMessage>(64);
+    /// Hands notifications to the daemon until the connection is gone.
+    /// Returns false if the daemon is gone instead.
+    async fn forward(&self, notifications: &mut Notifications) -> bool {
+        while let Some(notification) = notifications.next().await {
+            if !self.send(notification).await {
+                return false;
+            }
+        }
 
-        let stream = futures::stream::poll_fn(move |cx| {
-            connection.poll_message(cx).map_err(|e| panic!("{}", e))
-        })
-        .forward(tx);
+        true
+    }
 
-        spawn(stream);
+    /// Only done once what was missed got replayed, until then the listener counts as
+    /// reconnecting. Returns nothing if the daemon is gone.
+    async fn reconnect(&self, since: OffsetDateTime) -> Option<Notifications> {
+        let mut attempt = 0;
+        let mut retry = self.first_retry;
 
-        let daemon_tx = self.tx.clone();
+        loop {
+            attempt += 1;
+            self.state
+                .send_replace(ListenerState::Reconnecting { since, attempt });
 
-        spawn(async move {
-            client
-                .batch_execute(
-                    r"
-                    LISTEN post_notification;
-                    LISTEN comment_notification;
-                    ",
-                )
-                .await
-                .map_err(|e| NotificationCenterError::DaemonFailedToStart(e.to_string()))
-                .unwrap();
+            sleep(retry).await;
 
-            debug!("Started listener");
+            let connected = match self.source.listen().await {
+                Ok(connected) => Some(connected),
+                Err(e) => {
+                    warn!(
+                        attempt,
+                        "Could not reconnect the notification listener: {e}"
+                    );
+                    None
+                }
+            };
 
-            while let Some(AsyncMessage::Notification(msg)) = rx.next().await {
-                if let Ok(notification) = Notification::try_from(msg) {
-                    let _ = daemon_tx
-                        .send(NotificationCenterDaemonCommand::HandleNotification(
-                            notification,
-                        ))
-                        .await
-                        .inspect_err(|e| warn!("Failed to send notification: {}", e));
-                } else {
-                    warn!("Received malformed db message");
+            if let Some(connected) = connected {
+                match self.reconcile(since - RECONCILE_MARGIN).await {
+                    Ok(true) => {
+                        info!(attempt, "Reconnected the notification listener");
+                        return Some(connected);
+                    }
+                    Ok(false) => return None,
+                    Err(e) => warn!(attempt, "Could not reconcile missed notifications: {e}"),
                 }
             }
 
-            debug!("Stopped listener");
+            retry = (retry * 2).min(self.max_retry);
+        }
+    }
+
+    /// Replays what was created while nobody listened. Edits in that time are not recoverable.
+    /// Returns false if the daemon is gone.
+    async fn reconcile(&self, since: OffsetDateTime) -> Result<bool, DbError> {
+        let missed = self.source.created_since(since).await?;
+
+        info!(missed = missed.len(), "Reconciled missed notifications");
+
+        for notification in missed {
+            if !self.send(notification).await {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn send(&self, notification: Notification) -> bool {
+        self.daemon_tx
+            .send(NotificationCenterDaemonCommand::HandleNotification(
+                notification,
+            ))
+            .await
+            .inspect_err(|e| warn!("Failed to send notification: {}", e))
+            .is_ok()
+    }
+}
+
+#[async_trait]
+impl NotificationSource for Repo {
+    async fn listen(&self) -> Result<Notifications, InfrastructureError> {
+        let (client, mut connection) = self.new_connection().await?;
+
+        let (tx, rx) = futures::channel::mpsc::channel::<AsyncMessage>(64);
+
+        // Ends with the connection, which is how a loss gets noticed
+        let messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx))
+            .take_while(|message| {
+                if let Err(e) = message {
+                    warn!("Lost the notification connection: {e}");
+                }
+                ready(message.is_ok())
+            })
+            .filter_map(|message| ready(message.ok().map(Ok)))
+            .forward(tx);
+
+        spawn(messages);
+
+        client.batch_execute(CHANNELS).await?;
+
+        let notifications = rx.filter_map(move |message| {
+            // Dropping the client ends the session, so it lives as long as the stream
+            let _ = &client;
+
+            ready(match message {
+                AsyncMessage::Notification(message) => Notification::try_from(message)
+                    .inspect_err(|_| warn!("Received malformed db message"))
+                    .ok(),
+                _ => None,
+            })
         });
 
-        Ok(())
+        Ok(notifications.boxed())
+    }
+
+    #[instrument(skip(self), err)]
+    async fn created_since(&self, since: OffsetDateTime) -> Result<Vec<Notification>, DbError> {
+        let relations: Vec<Notification> = self
+            .query(
+                r"
+                    SELECT user_id_a, user_id_b FROM user_relation
+                    WHERE created_on >= $1
+                    ORDER BY created_on
+                ",
+                &[&since],
+                |rows| {
+                    rows.into_iter()
+                        .map(|row| {
+                            Ok(Notification::Relation(RelationNotification {
+                                user_id_a: row.try_get(0)?,
+                                user_id_b: row.try_get(1)?,
+                                operation: Operation::Insert,
+                            }))
+                        })
+                        .collect::<Result<_, tokio_postgres::Error>>()
+                        .map_err(DbError::mapping)
+                },
+            )
+            .await?;
+
+        // Publishing moves the creation to the time of publishing, scheduled posts included
+        let posts: Vec<Notification> = self
+            .query(
+                r"
+                    SELECT post_id, author FROM post
+                    WHERE created_on >= $1 AND state = 'published' AND NOT hidden
+                    ORDER BY created_on
+                ",
+                &[&since],
+                |rows| {
+                    rows.into_iter()
+                        .map(|row| {
+                            Ok(Notification::Post(PostNotification {
+                                post_id: row.try_get(0)?,
+                                author_id: row.try_get(1)?,
+                                operation: Operation::Insert,
+                                hidden: false,
+                            }))
+                        })
+                        .collect::<Result<_, tokio_postgres::Error>>()
+                        .map_err(DbError::mapping)
+                },
+            )
+            .await?;
+
+        let comments: Vec<Notification> = self
+            .query(
+                r"
+                    SELECT comment_id, referenced_post, author FROM comment
+                    WHERE created_on >= $1 AND NOT hidden
+                    ORDER BY created_on
+                ",
+                &[&since],
+                |rows| {
+                    rows.into_iter()
+                        .map(|row| {
+                            Ok(Notification::Comment(CommentNotification {
+                                comment_id: row.try_get(0)?,
+                                post_id: row.try_get(1)?,
+                                author_id: row.try_get(2)?,
+                                operation: Operation::Insert,
+                                hidden: false,
+                            }))
+                        })
+                        .collect::<Result<_, tokio_postgres::Error>>()
+                        .map_err(DbError::mapping)
+                },
+            )
+            .await?;
+
+        Ok(relations.into_iter().chain(posts).chain(comments).collect())
+    }
+}
+
+/// The latest creations the daemon routed, so reconciling does not deliver them twice.
+#[derive(Default)]
+struct RoutedWindow {
+    order: VecDeque<Creation>,
+    routed: HashSet<Creation>,
+}
+
+impl RoutedWindow {
+    /// Returns false if the creation was routed already.
+    fn first_time(&mut self, creation: Creation) -> bool {
+        if !self.routed.insert(creation) {
+            return false;
+        }
+
+        self.order.push_back(creation);
+        if self.order.len() > ROUTED_WINDOW {
+            if let Some(oldest) = self.order.pop_front() {
+                self.routed.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+struct NotificationCenterDaemon {
+    listeners: HashMap<ListenerId, Listener>,
+    topics: HashMap<ListenerTopic, HashSet<ListenerId>>,
+    routed: RoutedWindow,
+    rx: mpsc::Receiver<NotificationCenterDaemonCommand>,
+}
+
+impl NotificationCenterDaemon {
+    fn new(rx: mpsc::Receiver<NotificationCenterDaemonCommand>) -> Self {
+        Self {
+            listeners: HashMap::new(),
+            topics: HashMap::new(),
+            routed: RoutedWindow::default(),
+            rx,
+        }
     }
 
     async fn listen(&mut self) {
@@ -149,151 +454,464 @@ impl NotificationCenterDaemon {
 
             match message {
                 AddListener(listener) => self.add_listener(listener),
-                Maintainance => self.maintainence(),
-                HandleNotification(notification) => self.handle_notification(notification).await,
+                RemoveListener(id) => self.remove_listener(id),
+                HandleNotification(notification) => self.handle_notification(notification),
             }
         }
     }
 
     fn add_listener(&mut self, listener: Listener) {
-        self.listeners.push(listener);
+        // The handle may have been dropped before the daemon got to it
+        if listener.mailbox.is_closed() {
+            return;
+        }
+
+        for topic in &listener.topics {
+            self.topics.entry(*topic).or_default().insert(listener.id);
+        }
+
+        self.listeners.insert(listener.id, listener);
     }
 
-    fn maintainence(&mut self) {
-        self.listeners.retain(|listener| !listener.tx.is_closed());
+    fn update_listener(&mut self, id: ListenerId, topics: Vec<ListenerTopic>) {
+        let Some(mut listener) = self.listeners.remove(&id) else {
+            return;
+        };
+
+        self.unindex(id, &listener.topics);
+        listener.topics = topics;
+        self.add_listener(listener);
     }
 
-    async fn handle_notification(&self, notification: Notification) {
-        for listener in &self.listeners {
-            if listener.cares_about(&notification) {
-                let _ = listener
-                    .tx
-                    .send(notification.clone())
-                    .await
-                    .inspect_err(|e| debug!("Could not inform listener: {}", e));
+    fn remove_listener(&mut self, id: ListenerId) {
+        if let Some(listener) = self.listeners.remove(&id) {
+            self.unindex(id, &listener.topics);
+        }
+    }
+
+    fn unindex(&mut self, id: ListenerId, topics: &[ListenerTopic]) {
+        for topic in topics {
+            if let Some(ids) = self.topics.get_mut(topic) {
+                ids.remove(&id);
+
+                if ids.is_empty() {
+                    self.topics.remove(topic);
+                }
             }
         }
     }
-}
 
-pub struct ListenerHandle {
-    buffer: Vec<Notification>,
-    rx: mpsc::Receiver<Notification>,
-}
+    /* Listeners of a user's friendships get the content of new friends from here on, and
+    none of former ones. Doing it here rather than in the listener leaves no gap in which
+    a new friend's posts get routed before the listener asked for them. */
+    fn follow_relation(&mut self, relation: &RelationNotification) {
+        let pairs = [
+            (relation.user_id_a, relation.user_id_b),
+            (relation.user_id_b, relation.user_id_a),
+        ];
 
-impl ListenerHandle {
-    pub fn new(rx: mpsc::Receiver<Notification>) -> Self {
-        let buffer = Vec::with_capacity(16);
-        Self { buffer, rx }
+        for (user_id, friend_id) in pairs {
+            let Some(ids) = self.topics.get(&ListenerTopic::Friends(user_id)) else {
+                continue;
+            };
+
+            let friend = ListenerTopic::User(friend_id);
+            for id in ids.clone() {
+                let Some(listener) = self.listeners.get(&id) else {
+                    continue;
+                };
+
+                let mut topics = listener.topics.clone();
+                topics.retain(|topic| *topic != friend);
+                if relation.operation != Operation::Delete {
+                    topics.push(friend);
+                }
+
+                self.update_listener(id, topics);
+            }
+        }
     }
 
-    pub async fn receive(&mut self) -> Option<Vec<Notification>> {
-        let length = self.buffer.capacity();
-        let received = self.rx.recv_many(&mut self.buffer, length).await;
+    // Never waits on a listener, so a slow one can not hold up the others
+    fn handle_notification(&mut self, notification: Notification) {
+        if let Some(creation) = notification.creation() {
+            if !self.routed.first_time(creation) {
+                debug!(?creation, "Dropped notification that was routed already");
+                return;
+            }
+        }
 
-        if received == 0 {
-            None
-        } else {
-            Some(self.buffer.drain(..).collect())
+        if let Notification::Relation(relation) = &notification {
+            self.follow_relation(relation);
+        }
+
+        let ids: HashSet<ListenerId> = notification
+            .topics()
+            .iter()
+            .filter_map(|topic| self.topics.get(topic))
+            .flatten()
+            .copied()
+            .collect();
+
+        let closed: Vec<ListenerId> = ids
+            .into_iter()
+            .filter(|id| {
+                self.listeners
+                    .get(id)
+                    .is_some_and(|listener| !listener.mailbox.push(notification.clone()))
+            })
+            .collect();
+
+        for id in closed {
+            debug!(id, "Removed closed listener");
+            self.remove_listener(id);
         }
     }
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
-pub enum ListenerTopic {
-    User(DbId),
-    Post(DbId),
+/// What happens to a listener that does not keep up with its notifications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Keeps the newest notifications, for listeners that only care about the latest state
+    DropOldest,
+    /// Ends the listener with an error, for listeners that must not miss anything
+    Disconnect,
 }
 
-impl ListenerTopic {
-    fn matches(&self, notification: &Notification) -> bool {
-        match (self, notification) {
-            (ListenerTopic::User(user), Notification::Post(note_post)) => {
-                *user == note_post.author_id
-            }
-            (ListenerTopic::User(user), Notification::Comment(note_comment)) => {
-                *user == note_comment.author_id
-            }
-            (ListenerTopic::Post(post), Notification::Post(note_post)) => {
-                *post == note_post.post_id
-            }
-            (ListenerTopic::Post(post), Notification::Comment(note_comment)) => {
-                *post == note_comment.post_id
+type ListenerId = u64;
+
+static NEXT_LISTENER_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Default)]
+struct MailboxState {
+    queue: VecDeque<Notification>,
+    // Set while the listener catches up, in place of the usual capacity
+    catch_up_capacity: Option<usize>,
+    dropped: usize,
+    // The capacity that was exceeded
+    overflowed: Option<usize>,
+    closed: bool,
+}
+
+/// Bounded queue between the daemon and a single listener.
+struct Mailbox {
+    state: Mutex<MailboxState>,
+    notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl Mailbox {
+    fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            state: Mutex::default(),
+            notify: Notify::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    /// Returns false once the listener should be removed.
+    fn push(&self, notification: Notification) -> bool {
+        let Ok(mut state) = self.state.lock() else {
+            return false;
+        };
+
+        if state.closed {
+            return false;
+        }
+
+        let capacity = state.catch_up_capacity.unwrap_or(self.capacity);
+
+        if state.queue.len() >= capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    state.queue.pop_front();
+                    state.dropped += 1;
+                }
+                OverflowPolicy::Disconnect => {
+                    state.overflowed = Some(capacity);
+                    state.closed = true;
+                    drop(state);
+                    self.notify.notify_one();
+                    return false;
+                }
             }
         }
+
+        state.queue.push_back(notification);
+        drop(state);
+        self.notify.notify_one();
+
+        true
+    }
+
+    fn catch_up(&self, capacity: usize) {
+        if let Ok(mut state) = self.state.lock() {
+            state.catch_up_capacity = Some(capacity);
+        }
+    }
+
+    fn close(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.closed = true;
+        }
+
+        self.notify.notify_one();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().map_or(true, |state| state.closed)
     }
 }
 
-#[derive(Debug, Clone)]
 struct Listener {
+    id: ListenerId,
     topics: Vec<ListenerTopic>,
-    tx: mpsc::Sender<Notification>,
+    mailbox: Arc<Mailbox>,
 }
 
 impl Listener {
-    fn cares_about(&self, notification: &Notification) -> bool {
-        self.topics.iter().any(|topic| topic.matches(notification))
+    fn new(
+        topics: Vec<ListenerTopic>,
+        capacity: usize,
+        policy: OverflowPolicy,
+        daemon_tx: mpsc::Sender<NotificationCenterDaemonCommand>,
+    ) -> (Self, ListenerHandle) {
+        let id = NEXT_LISTENER_ID.fetch_add(1, Ordering::Relaxed);
+        let mailbox = Arc::new(Mailbox::new(capacity, policy));
+
+        let listener = Self {
+            id,
+            topics,
+            mailbox: mailbox.clone(),
+        };
+        let handle = ListenerHandle {
+            id,
+            mailbox,
+            daemon_tx,
+        };
+
+        (listener, handle)
+    }
+}
+
+// Lets a waiting handle know that nothing will follow
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.mailbox.close();
+    }
+}
+
+pub struct ListenerHandle {
+    id: ListenerId,
+    mailbox: Arc<Mailbox>,
+    daemon_tx: mpsc::Sender<NotificationCenterDaemonCommand>,
+}
+
+impl ListenerHandle {
+    /// Waits for the next batch of notifications. Ends with an error if the listener
+    /// overflowed and got disconnected.
+    pub async fn receive(&mut self) -> Option<Result<Vec<Notification>, NotificationCenterError>> {
+        loop {
+            {
+                let mut state = self.mailbox.state.lock().ok()?;
+
+                if state.dropped > 0 {
+                    warn!(dropped = state.dropped, "Listener fell behind");
+                    state.dropped = 0;
+                }
+
+                // Caught up from here on, whatever was queued in the meantime gets taken now
+                state.catch_up_capacity = None;
+
+                if !state.queue.is_empty() {
+                    return Some(Ok(state.queue.drain(..).collect()));
+                }
+
+                if let Some(capacity) = state.overflowed.take() {
+                    return Some(Err(NotificationCenterError::Overflowed(capacity)));
+                }
+
+                if state.closed {
+                    return None;
+                }
+            }
+
+            self.mailbox.notify.notified().await;
+        }
+    }
+}
+
+// Pruned right away, without waiting for the next notification to find it closed
+impl Drop for ListenerHandle {
+    fn drop(&mut self) {
+        self.mailbox.close();
+
+        let _ = self
+            .daemon_tx
+            .try_send(NotificationCenterDaemonCommand::RemoveListener(self.id));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ListenerTopic {
+    /// Content created by the user
+    User(DbId),
+    /// The post and its comments
+    Post(DbId),
+    /// Friendships of the user, which also subscribes to the content of their friends
+    /// as friendships come and go
+    Friends(DbId),
+}
+
+const PAYLOAD_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+struct Envelope {
+    v: u32,
+}
+
+/// Triggers send versioned JSON. Colon separated ids are what they sent before,
+/// which still arrive while the migration is rolled out.
+fn parse_payload<T: DeserializeOwned>(
+    value: &str,
+    legacy: impl FnOnce(&[&str]) -> Result<T, NotificationCenterError>,
+) -> Result<T, NotificationCenterError> {
+    if !value.starts_with('{') {
+        let parts: Vec<&str> = value.split(':').collect();
+        return legacy(&parts);
+    }
+
+    let Envelope { v } =
+        serde_json::from_str(value).map_err(|_| NotificationCenterError::ParsingFailed)?;
+
+    if v != PAYLOAD_VERSION {
+        return Err(NotificationCenterError::UnsupportedVersion(v));
     }
+
+    serde_json::from_str(value).map_err(|_| NotificationCenterError::ParsingFailed)
+}
+
+fn parse_id(part: &str) -> Result<DbId, NotificationCenterError> {
+    part.parse()
+        .map_err(|_| NotificationCenterError::ParsingFailed)
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct PostNotification {
     pub author_id: DbId,
     pub post_id: DbId,
+    #[serde(rename = "op")]
+    pub operation: Operation,
+    /// Left out of id-only payloads, so `false` still has to be checked against the db
+    #[serde(default)]
+    pub hidden: bool,
 }
 
 impl TryFrom<&str> for PostNotification {
     type Error = NotificationCenterError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let parts: Vec<&str> = value.split(':').collect();
-        if parts.len() != 2 {
-            return Err(NotificationCenterError::ParsingFailed);
-        }
+        parse_payload(value, |parts| match parts {
+            // The old trigger did not tell, feeds would rather show an edit than miss a post
+            [post_id, author_id] => Ok(PostNotification {
+                author_id: parse_id(author_id)?,
+                post_id: parse_id(post_id)?,
+                operation: Operation::Insert,
+                hidden: false,
+            }),
+            _ => Err(NotificationCenterError::ParsingFailed),
+        })
+    }
+}
 
-        let post_id = parts[0]
-            .parse()
-            .map_err(|_| NotificationCenterError::ParsingFailed)?;
-        let author_id = parts[1]
-            .parse()
-            .map_err(|_| NotificationCenterError::ParsingFailed)?;
+/// What the database did to the row behind a notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Insert,
+    Update,
+    Delete,
+    /// A draft or scheduled post got published
+    Publish,
+}
+
+impl TryFrom<&str> for Operation {
+    type Error = NotificationCenterError;
 
-        Ok(PostNotification { author_id, post_id })
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "insert" => Ok(Operation::Insert),
+            "update" => Ok(Operation::Update),
+            "delete" => Ok(Operation::Delete),
+            "publish" => Ok(Operation::Publish),
+            _ => Err(NotificationCenterError::ParsingFailed),
+        }
     }
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct CommentNotification {
     pub author_id: DbId,
     pub post_id: DbId,
     pub comment_id: DbId,
+    #[serde(rename = "op")]
+    pub operation: Operation,
+    /// Left out of id-only payloads, so `false` still has to be checked against the db
+    #[serde(default)]
+    pub hidden: bool,
 }
 
 impl TryFrom<&str> for CommentNotification {
     type Error = NotificationCenterError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let parts: Vec<&str> = value.split(':').collect();
-        if parts.len() != 3 {
-            return Err(NotificationCenterError::ParsingFailed);
-        }
-
-        let comment_id = parts[0]
-            .parse()
-            .map_err(|_| NotificationCenterError::ParsingFailed)?;
-        let post_id = parts[1]
-            .parse()
-            .map_err(|_| NotificationCenterError::ParsingFailed)?;
-        let author_id = parts[2]
-            .parse()
-            .map_err(|_| NotificationCenterError::ParsingFailed)?;
-
-        Ok(CommentNotification {
-            author_id,
-            post_id,
-            comment_id,
+        parse_payload(value, |parts| {
+            let (comment_id, post_id, author_id, operation) = match parts {
+                [comment_id, post_id, author_id, operation] => (
+                    comment_id,
+                    post_id,
+                    author_id,
+                    Operation::try_from(*operation)?,
+                ),
+                // The first trigger did not tell, loading the comment sorts it out
+                [comment_id, post_id, author_id] => {
+                    (comment_id, post_id, author_id, Operation::Update)
+                }
+                _ => return Err(NotificationCenterError::ParsingFailed),
+            };
+
+            Ok(CommentNotification {
+                author_id: parse_id(author_id)?,
+                post_id: parse_id(post_id)?,
+                comment_id: parse_id(comment_id)?,
+                operation,
+                hidden: false,
+            })
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RelationNotification {
+    pub user_id_a: DbId,
+    pub user_id_b: DbId,
+    #[serde(rename = "op")]
+    pub operation: Operation,
+}
+
+impl TryFrom<&str> for RelationNotification {
+    type Error = NotificationCenterError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        parse_payload(value, |parts| match parts {
+            [user_id_a, user_id_b, operation] => Ok(RelationNotification {
+                user_id_a: parse_id(user_id_a)?,
+                user_id_b: parse_id(user_id_b)?,
+                operation: Operation::try_from(*operation)?,
+            }),
+            _ => Err(NotificationCenterError::ParsingFailed),
         })
     }
 }
@@ -302,6 +920,48 @@ impl TryFrom<&str> for CommentNotification {
 pub enum Notification {
     Post(PostNotification),
     Comment(CommentNotification),
+    Relation(RelationNotification),
+}
+
+/// Something that can only be created once, which is all reconciling replays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Creation {
+    Post(DbId),
+    Comment(DbId),
+}
+
+impl Notification {
+    fn creation(&self) -> Option<Creation> {
+        match self {
+            Notification::Post(post)
+                if matches!(post.operation, Operation::Insert | Operation::Publish) =>
+            {
+                Some(Creation::Post(post.post_id))
+            }
+            Notification::Comment(comment) if comment.operation == Operation::Insert => {
+                Some(Creation::Comment(comment.comment_id))
+            }
+            _ => None,
+        }
+    }
+
+    /// Everything a listener could have subscribed to, to be told about this.
+    fn topics(&self) -> [ListenerTopic; 2] {
+        match self {
+            Notification::Post(post) => [
+                ListenerTopic::User(post.author_id),
+                ListenerTopic::Post(post.post_id),
+            ],
+            Notification::Comment(comment) => [
+                ListenerTopic::User(comment.author_id),
+                ListenerTopic::Post(comment.post_id),
+            ],
+            Notification::Relation(relation) => [
+                ListenerTopic::Friends(relation.user_id_a),
+                ListenerTopic::Friends(relation.user_id_b),
+            ],
+        }
+    }
 }
 
 impl TryFrom<tokio_postgres::Notification> for Notification {
@@ -315,7 +975,635 @@ impl TryFrom<tokio_postgres::Notification> for Notification {
             "comment_notification" => {
                 CommentNotification::try_from(value.payload()).map(Notification::Comment)
             }
+            "user_relation_notification" => {
+                RelationNotification::try_from(value.payload()).map(Notification::Relation)
+            }
             _ => Err(NotificationCenterError::ParsingFailed),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_graphql::ID;
+    use futures::StreamExt;
+    use serde_json::json;
+    use tokio::{
+        sync::mpsc,
+        time::{sleep, timeout},
+    };
+
+    use crate::domain::{
+        comment::Comment,
+        db_id::{CanDecodeId, DbId},
+        post::{Post, PostState},
+    };
+
+    use crate::infrastructure::{
+        errors::NotificationCenterError,
+        testing::{next_data, TestApp, TestDb},
+    };
+
+    use super::{
+        CommentNotification, Creation, Listener, ListenerHandle, ListenerState, ListenerTopic,
+        Notification, NotificationCenterDaemon, NotificationCenterDaemonCommand, Operation,
+        OverflowPolicy, PostNotification, RelationNotification, RoutedWindow, ROUTED_WINDOW,
+    };
+
+    const LISTENER_CAPACITY: usize = 16;
+    const CATCH_UP_CAPACITY: usize = 64;
+
+    fn daemon() -> (
+        NotificationCenterDaemon,
+        mpsc::Sender<NotificationCenterDaemonCommand>,
+    ) {
+        let (tx, rx) = mpsc::channel(32);
+        (NotificationCenterDaemon::new(rx), tx)
+    }
+
+    fn subscribe(
+        daemon: &mut NotificationCenterDaemon,
+        tx: &mpsc::Sender<NotificationCenterDaemonCommand>,
+        topics: Vec<ListenerTopic>,
+        policy: OverflowPolicy,
+    ) -> ListenerHandle {
+        let (listener, handle) = Listener::new(topics, LISTENER_CAPACITY, policy, tx.clone());
+        daemon.add_listener(listener);
+        handle
+    }
+
+    fn post(post_id: i32, author_id: i32) -> Notification {
+        Notification::Post(PostNotification {
+            post_id: DbId::from(post_id),
+            author_id: DbId::from(author_id),
+            operation: Operation::Insert,
+            hidden: false,
+        })
+    }
+
+    fn post_ids(notifications: Vec<Notification>) -> Vec<i32> {
+        notifications
+            .into_iter()
+            .filter_map(|n| match n {
+                Notification::Post(post) => Some(*post.post_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn routes_by_topic() {
+        let (mut daemon, tx) = daemon();
+        let user = ListenerTopic::User(DbId::from(1));
+        let post_topic = ListenerTopic::Post(DbId::from(20));
+
+        let mut by_user = subscribe(&mut daemon, &tx, vec![user], OverflowPolicy::Disconnect);
+        let mut by_post = subscribe(
+            &mut daemon,
+            &tx,
+            vec![post_topic],
+            OverflowPolicy::Disconnect,
+        );
+        let mut by_both = subscribe(
+            &mut daemon,
+            &tx,
+            vec![user, post_topic],
+            OverflowPolicy::Disconnect,
+        );
+
+        daemon.handle_notification(post(10, 1));
+        daemon.handle_notification(post(20, 2));
+
+        assert_eq!(
+            vec![10],
+            post_ids(by_user.receive().await.unwrap().unwrap())
+        );
+        assert_eq!(
+            vec![20],
+            post_ids(by_post.receive().await.unwrap().unwrap())
+        );
+        assert_eq!(
+            vec![10, 20],
+            post_ids(by_both.receive().await.unwrap().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn updated_topics_reroute() {
+        let (mut daemon, tx) = daemon();
+        let old = ListenerTopic::User(DbId::from(1));
+        let new = ListenerTopic::User(DbId::from(2));
+
+        let mut handle = subscribe(&mut daemon, &tx, vec![old], OverflowPolicy::Disconnect);
+        daemon.handle_notification(post(10, 1));
+
+        daemon.update_listener(handle.id, vec![new]);
+        daemon.handle_notification(post(11, 1));
+        daemon.handle_notification(post(12, 2));
+
+        assert_eq!(
+            vec![10, 12],
+            post_ids(handle.receive().await.unwrap().unwrap())
+        );
+        assert!(!daemon.topics.contains_key(&old));
+    }
+
+    #[tokio::test]
+    async fn friendships_change_what_is_routed() {
+        let (mut daemon, tx) = daemon();
+        let topics = vec![
+            ListenerTopic::User(DbId::from(1)),
+            ListenerTopic::Friends(DbId::from(1)),
+        ];
+        let mut handle = subscribe(&mut daemon, &tx, topics, OverflowPolicy::Disconnect);
+
+        let relation = |operation| {
+            Notification::Relation(RelationNotification {
+                user_id_a: DbId::from(2),
+                user_id_b: DbId::from(1),
+                operation,
+            })
+        };
+
+        daemon.handle_notification(post(10, 2));
+        daemon.handle_notification(relation(Operation::Insert));
+        // Routed before the listener got to see the friendship
+        daemon.handle_notification(post(11, 2));
+        daemon.handle_notification(relation(Operation::Insert));
+        daemon.handle_notification(relation(Operation::Delete));
+        daemon.handle_notification(post(12, 2));
+        daemon.handle_notification(post(13, 1));
+
+        assert_eq!(
+            vec![11, 13],
+            post_ids(handle.receive().await.unwrap().unwrap())
+        );
+        assert!(!daemon
+            .topics
+            .contains_key(&ListenerTopic::User(DbId::from(2))));
+    }
+
+    #[tokio::test]
+    async fn reconciled_creations_are_routed_once() {
+        let (mut daemon, tx) = daemon();
+        let topic = ListenerTopic::User(DbId::from(1));
+        let mut handle = subscribe(&mut daemon, &tx, vec![topic], OverflowPolicy::Disconnect);
+
+        daemon.handle_notification(post(10, 1));
+        // Replayed by reconciling, after the post got published live
+        daemon.handle_notification(post(10, 1));
+        daemon.handle_notification(Notification::Post(PostNotification {
+            post_id: DbId::from(10),
+            author_id: DbId::from(1),
+            operation: Operation::Update,
+            hidden: true,
+        }));
+        daemon.handle_notification(post(11, 1));
+
+        let received = handle.receive().await.unwrap().unwrap();
+        assert_eq!(vec![10, 10, 11], post_ids(received.clone()));
+        assert!(matches!(
+            &received[1],
+            Notification::Post(post) if post.operation == Operation::Update
+        ));
+    }
+
+    #[tokio::test]
+    async fn routed_window_forgets_the_oldest() {
+        let mut window = RoutedWindow::default();
+
+        for post_id in 0..=ROUTED_WINDOW as i32 {
+            assert!(window.first_time(Creation::Post(DbId::from(post_id))));
+        }
+
+        assert!(window.first_time(Creation::Post(DbId::from(0))));
+        assert!(!window.first_time(Creation::Post(DbId::from(2))));
+        assert!(window.first_time(Creation::Comment(DbId::from(2))));
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_newest() {
+        let (mut daemon, tx) = daemon();
+        let topic = ListenerTopic::User(DbId::from(1));
+        let mut handle = subscribe(&mut daemon, &tx, vec![topic], OverflowPolicy::DropOldest);
+
+        let sent = LISTENER_CAPACITY as i32 + 5;
+        for post_id in 0..sent {
+            daemon.handle_notification(post(post_id, 1));
+        }
+
+        let received = post_ids(handle.receive().await.unwrap().unwrap());
+        assert_eq!((5..sent).collect::<Vec<_>>(), received);
+        assert_eq!(1, daemon.listeners.len());
+    }
+
+    #[tokio::test]
+    async fn catching_up_queues_more_until_received() {
+        let (mut daemon, tx) = daemon();
+        let topic = ListenerTopic::User(DbId::from(1));
+        let mut handle = subscribe(&mut daemon, &tx, vec![topic], OverflowPolicy::Disconnect);
+        handle.mailbox.catch_up(CATCH_UP_CAPACITY);
+
+        let sent = CATCH_UP_CAPACITY as i32;
+        for post_id in 0..sent {
+            daemon.handle_notification(post(post_id, 1));
+        }
+
+        let received = post_ids(handle.receive().await.unwrap().unwrap());
+        assert_eq!((0..sent).collect::<Vec<_>>(), received);
+
+        for post_id in sent..=sent + LISTENER_CAPACITY as i32 {
+            daemon.handle_notification(post(post_id, 1));
+        }
+
+        assert!(handle.receive().await.unwrap().is_ok());
+        assert!(matches!(
+            handle.receive().await,
+            Some(Err(NotificationCenterError::Overflowed(LISTENER_CAPACITY)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn catching_up_overflows_past_its_capacity() {
+        let (mut daemon, tx) = daemon();
+        let topic = ListenerTopic::User(DbId::from(1));
+        let mut handle = subscribe(&mut daemon, &tx, vec![topic], OverflowPolicy::Disconnect);
+        handle.mailbox.catch_up(CATCH_UP_CAPACITY);
+
+        for post_id in 0..=CATCH_UP_CAPACITY as i32 {
+            daemon.handle_notification(post(post_id, 1));
+        }
+
+        assert!(daemon.listeners.is_empty());
+
+        let pending = handle.receive().await.unwrap().unwrap();
+        assert_eq!(CATCH_UP_CAPACITY, pending.len());
+        assert!(matches!(
+            handle.receive().await,
+            Some(Err(NotificationCenterError::Overflowed(CATCH_UP_CAPACITY)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn disconnect_delivers_pending_then_errors() {
+        let (mut daemon, tx) = daemon();
+        let topic = ListenerTopic::User(DbId::from(1));
+        let mut handle = subscribe(&mut daemon, &tx, vec![topic], OverflowPolicy::Disconnect);
+
+        for post_id in 0..=LISTENER_CAPACITY as i32 {
+            daemon.handle_notification(post(post_id, 1));
+        }
+
+        assert!(daemon.listeners.is_empty());
+        assert!(daemon.topics.is_empty());
+
+        let pending = handle.receive().await.unwrap().unwrap();
+        assert_eq!(LISTENER_CAPACITY, pending.len());
+        assert!(handle.receive().await.unwrap().is_err());
+        assert!(handle.receive().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn dropped_handles_are_pruned_immediately() {
+        let (tx, mut rx) = mpsc::channel(32);
+        let mut daemon = NotificationCenterDaemon::new(mpsc::channel(1).1);
+        let topic = ListenerTopic::User(DbId::from(1));
+
+        let handle = subscribe(&mut daemon, &tx, vec![topic], OverflowPolicy::Disconnect);
+        drop(handle);
+
+        match rx.recv().await {
+            Some(NotificationCenterDaemonCommand::RemoveListener(id)) => daemon.remove_listener(id),
+            _ => panic!("Expected the listener to be removed"),
+        }
+
+        assert!(daemon.listeners.is_empty());
+        assert!(daemon.topics.is_empty());
+    }
+
+    #[tokio::test]
+    async fn thousands_of_listeners() {
+        const USERS: i32 = 1_000;
+        const LISTENERS: i32 = 20_000;
+        const NOTIFICATIONS: i32 = 10_000;
+
+        let (mut daemon, tx) = daemon();
+
+        let mut handles: Vec<ListenerHandle> = (0..LISTENERS)
+            .map(|i| {
+                let topics = vec![
+                    ListenerTopic::User(DbId::from(i % USERS)),
+                    ListenerTopic::User(DbId::from((i + 1) % USERS)),
+                ];
+                subscribe(&mut daemon, &tx, topics, OverflowPolicy::DropOldest)
+            })
+            .collect();
+
+        for post_id in 0..NOTIFICATIONS {
+            daemon.handle_notification(post(post_id, post_id % USERS));
+        }
+
+        // Every author has 40 listeners and none of them read, so nobody got blocked
+        assert_eq!(LISTENERS as usize, daemon.listeners.len());
+
+        for (i, handle) in (0..LISTENERS).zip(handles.iter_mut()) {
+            let authors = [i % USERS, (i + 1) % USERS];
+            let mut written: Vec<i32> = (0..NOTIFICATIONS / USERS)
+                .flat_map(|round| authors.map(|author| round * USERS + author))
+                .collect();
+            written.sort_unstable();
+
+            let received = handle.receive().await.unwrap().unwrap();
+            assert_eq!(
+                written[written.len() - LISTENER_CAPACITY..],
+                post_ids(received)[..]
+            );
+        }
+    }
+
+    #[test]
+    fn parses_json_payloads() {
+        let post = PostNotification::try_from(
+            r#"{"v":1,"op":"update","post_id":3,"author_id":7,"hidden":true,"created_on":"2024-01-01T00:00:00"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            (post.post_id, post.author_id),
+            (DbId::from(3), DbId::from(7))
+        );
+        assert_eq!(post.operation, Operation::Update);
+        assert!(post.hidden);
+
+        let post =
+            PostNotification::try_from(r#"{"v":1,"op":"publish","post_id":3,"author_id":7}"#)
+                .unwrap();
+        assert_eq!(post.operation, Operation::Publish);
+
+        let comment = CommentNotification::try_from(
+            r#"{"v":1,"op":"delete","comment_id":5,"post_id":3,"author_id":7}"#,
+        )
+        .unwrap();
+        assert_eq!(comment.comment_id, DbId::from(5));
+        assert_eq!(comment.operation, Operation::Delete);
+        assert!(!comment.hidden);
+
+        let relation =
+            RelationNotification::try_from(r#"{"v":1,"op":"insert","user_id_a":1,"user_id_b":2}"#)
+                .unwrap();
+        assert_eq!(relation.user_id_b, DbId::from(2));
+        assert_eq!(relation.operation, Operation::Insert);
+    }
+
+    #[test]
+    fn parses_legacy_payloads() {
+        let post = PostNotification::try_from("3:7").unwrap();
+        assert_eq!(
+            (post.post_id, post.author_id),
+            (DbId::from(3), DbId::from(7))
+        );
+        assert_eq!(post.operation, Operation::Insert);
+
+        let comment = CommentNotification::try_from("5:3:7:delete").unwrap();
+        assert_eq!(comment.operation, Operation::Delete);
+
+        let comment = CommentNotification::try_from("5:3:7").unwrap();
+        assert_eq!(comment.operation, Operation::Update);
+
+        let relation = RelationNotification::try_from("1:2:delete").unwrap();
+        assert_eq!(relation.operation, Operation::Delete);
+    }
+
+    #[test]
+    fn rejects_unknown_payloads() {
+        assert!(matches!(
+            PostNotification::try_from(r#"{"v":2,"post_id":3,"author_id":7}"#),
+            Err(NotificationCenterError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            PostNotification::try_from(r#"{"v":1,"post_id":3}"#),
+            Err(NotificationCenterError::ParsingFailed)
+        ));
+        assert!(matches!(
+            CommentNotification::try_from("5:3:7:upsert"),
+            Err(NotificationCenterError::ParsingFailed)
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn home_feed_follows_new_friends_over_listen_notify() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, _) = app.user("Alice").await;
+        let (bob, bob_id) = app.user("Bob").await;
+
+        let mut feed = app
+            .subscribe(alice, "subscription { homeFeed { node { content } } }")
+            .await;
+
+        app.post(bob, "Before").await;
+        app.befriend(alice, &bob_id).await;
+        app.post(bob, "After").await;
+
+        assert_eq!(
+            json!({ "homeFeed": [{ "node": { "content": "After" } }] }),
+            next_data(&mut feed).await
+        );
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn comment_changes_arrive_over_listen_notify() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, _) = app.user("Alice").await;
+        let post_id = app.post(alice, "Post").await;
+
+        let query = format!(
+            r#"subscription {{ postComments(postId: "{post_id}") {{ operation edge {{ node {{ content }} }} }} }}"#
+        );
+        let mut changes = app.subscribe(alice, &query).await;
+
+        let comment_id = app.comment(alice, &post_id, "Hi").await;
+        assert_eq!(
+            json!({ "postComments": [{ "operation": "CREATED", "edge": { "node": { "content": "Hi" } } }] }),
+            next_data(&mut changes).await
+        );
+
+        let query = format!(
+            r#"mutation {{ editComment(input: {{ comment: "{comment_id}", content: "Hello" }}) {{ id }} }}"#
+        );
+        app.execute(alice, &query).await;
+        assert_eq!(
+            json!({ "postComments": [{ "operation": "EDITED", "edge": { "node": { "content": "Hello" } } }] }),
+            next_data(&mut changes).await
+        );
+
+        app.storage
+            .hide_comment(&Comment::decode(&ID(comment_id)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            json!({ "postComments": [{ "operation": "DELETED", "edge": null }] }),
+            next_data(&mut changes).await
+        );
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn post_updates_end_once_hidden() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, _) = app.user("Alice").await;
+        let post_id = app.post(alice, "Post").await;
+
+        let query = format!(r#"subscription {{ postUpdated(postId: "{post_id}") {{ content }} }}"#);
+        let mut updates = app.subscribe(alice, &query).await;
+
+        let query = format!(
+            r#"mutation {{ editPost(input: {{ post: "{post_id}", content: "Edited" }}) {{ id }} }}"#
+        );
+        app.execute(alice, &query).await;
+        assert_eq!(
+            json!({ "postUpdated": { "content": "Edited" } }),
+            next_data(&mut updates).await
+        );
+
+        app.storage
+            .hide_post(&Post::decode(&ID(post_id)).unwrap())
+            .await
+            .unwrap();
+        assert!(timeout(Duration::from_secs(5), updates.next())
+            .await
+            .unwrap()
+            .is_none());
+
+        db.drop().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn posts_missed_while_reconnecting_are_replayed() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, _) = app.user("Alice").await;
+        let (bob, bob_id) = app.user("Bob").await;
+        app.befriend(alice, &bob_id).await;
+
+        let mut feed = app
+            .subscribe(alice, "subscription { homeFeed { node { content } } }")
+            .await;
+
+        terminate_listener(&db).await;
+
+        app.post(bob, "Missed").await;
+
+        assert_eq!(
+            json!({ "homeFeed": [{ "node": { "content": "Missed" } }] }),
+            next_data(&mut feed).await
+        );
+
+        db.drop().await;
+    }
+
+    // Like a restart of the db would
+    async fn terminate_listener(db: &TestDb) {
+        db.repo
+            .execute(
+                r"
+                    SELECT pg_terminate_backend(pid) FROM pg_stat_activity
+                    WHERE datname = current_database() AND query LIKE '%LISTEN%'
+                        AND pid <> pg_backend_pid()
+                ",
+                &[],
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn reconnecting_retries_until_friends_and_publications_are_replayed() {
+        let db = TestDb::new().await;
+        let app = TestApp::on(&db).await;
+        let (alice, _) = app.user("Alice").await;
+        let (bob, bob_id) = app.user("Bob").await;
+        let (carol, carol_id) = app.user("Carol").await;
+        app.befriend(alice, &bob_id).await;
+
+        // Written long before it gets published
+        let in_an_hour = time::OffsetDateTime::now_utc() + time::Duration::hours(1);
+        let scheduled = app
+            .storage
+            .save_post(&bob, "Scheduled", PostState::Scheduled, Some(in_an_hour))
+            .await
+            .unwrap();
+        db.repo
+            .execute(
+                r"
+                    UPDATE post
+                    SET created_on = now() - interval '1 hour', scheduled_for = now()
+                    WHERE post_id = $1
+                ",
+                &[&scheduled.post_id],
+            )
+            .await
+            .unwrap();
+
+        let mut feed = app
+            .subscribe(alice, "subscription { homeFeed { node { content } } }")
+            .await;
+
+        // Reconciling reads comments last, so everything else still works meanwhile
+        db.repo
+            .execute("ALTER TABLE comment RENAME TO comment_away", &[])
+            .await
+            .unwrap();
+        terminate_listener(&db).await;
+
+        app.befriend(alice, &carol_id).await;
+        app.post(carol, "Befriended").await;
+        app.storage.publish_due_posts(10).await.unwrap();
+
+        // Past the first attempt, whose reconciling failed
+        timeout(Duration::from_secs(5), async {
+            while !matches!(
+                app.notification_center.state(),
+                ListenerState::Reconnecting { attempt, .. } if attempt > 1
+            ) {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(app.notification_center.health().is_err());
+
+        db.repo
+            .execute("ALTER TABLE comment_away RENAME TO comment", &[])
+            .await
+            .unwrap();
+
+        let mut contents = Vec::new();
+        while contents.len() < 2 {
+            let data = next_data(&mut feed).await;
+            contents.extend(
+                data["homeFeed"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|edge| edge["node"]["content"].as_str().unwrap().to_string()),
+            );
+        }
+        contents.sort();
+        assert_eq!(vec!["Befriended", "Scheduled"], contents);
+        assert!(app.notification_center.health().is_ok());
+
+        db.drop().await;
+    }
+}
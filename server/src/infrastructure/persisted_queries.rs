@@ -0,0 +1,459 @@
+use std::{
+    collections::HashMap,
+    fs,
+    num::NonZeroUsize,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use async_graphql::{
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextPrepareRequest},
+    Request, ServerResult, Value,
+};
+use async_trait::async_trait;
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
+use tracing::{info, instrument, warn};
+
+use crate::domain::GqlError;
+
+use super::{
+    config::{PersistedQueriesConfig, QueryStoreKind},
+    db::Repo,
+    errors::{DbError, InfrastructureError},
+};
+
+const CACHE_SIZE: usize = 1024;
+
+/// Where queries registered by clients at runtime are kept.
+#[async_trait]
+pub trait QueryStore: Send + Sync {
+    async fn get(&self, hash: &str) -> Option<String>;
+
+    async fn set(&self, hash: &str, query: &str);
+}
+
+pub struct LruQueryStore {
+    cache: Mutex<LruCache<String, String>>,
+}
+
+impl LruQueryStore {
+    pub fn new() -> Self {
+        Self {
+            cache: Mutex::new(LruCache::new(
+                NonZeroUsize::new(CACHE_SIZE).expect("Cache size should not be zero"),
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl QueryStore for LruQueryStore {
+    async fn get(&self, hash: &str) -> Option<String> {
+        self.cache.lock().ok()?.get(hash).cloned()
+    }
+
+    async fn set(&self, hash: &str, query: &str) {
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.put(hash.to_string(), query.to_string());
+        }
+    }
+}
+
+/// Shared between instances and kept across restarts, with an in-memory cache in front.
+/// Anyone may register queries, so the table is bounded. A query that got removed costs
+/// its clients a retry with the full query.
+pub struct PostgresQueryStore {
+    cache: LruQueryStore,
+    repo: Repo,
+    max_stored: i64,
+    expire_after: Duration,
+}
+
+impl PostgresQueryStore {
+    pub fn new(repo: Repo, config: &PersistedQueriesConfig) -> Self {
+        Self {
+            cache: LruQueryStore::new(),
+            repo,
+            max_stored: i64::from(config.max_stored),
+            expire_after: Duration::days(i64::from(config.expire_after_days)),
+        }
+    }
+}
+
+#[async_trait]
+impl QueryStore for PostgresQueryStore {
+    async fn get(&self, hash: &str) -> Option<String> {
+        if let Some(query) = self.cache.get(hash).await {
+            return Some(query);
+        }
+
+        // A failed lookup only costs the client a retry with the full query
+        let query = self
+            .repo
+            .persisted_query(hash)
+            .await
+            .inspect_err(|e| warn!("Could not load persisted query: {e}"))
+            .ok()??;
+
+        self.cache.set(hash, &query).await;
+
+        Some(query)
+    }
+
+    async fn set(&self, hash: &str, query: &str) {
+        let expired_before = OffsetDateTime::now_utc() - self.expire_after;
+
+        if let Err(e) = self
+            .repo
+            .save_persisted_query(hash, query, self.max_stored, expired_before)
+            .await
+        {
+            warn!("Could not save persisted query: {e}");
+        }
+
+        self.cache.set(hash, query).await;
+    }
+}
+
+impl Repo {
+    // Only lookups that miss the cache count as use, which is enough to keep queries in use
+    #[instrument(skip(self), err)]
+    async fn persisted_query(&self, hash: &str) -> Result<Option<String>, DbError> {
+        let now = OffsetDateTime::now_utc();
+
+        self.query(
+            "UPDATE persisted_query SET used_on = $2 WHERE hash = $1 RETURNING query",
+            &[&hash, &now],
+            |rows| {
+                rows.into_iter()
+                    .next()
+                    .map(|row| row.try_get(0).map_err(DbError::mapping))
+                    .transpose()
+            },
+        )
+        .await
+    }
+
+    /// Also prunes what expired and what exceeds `max_stored`.
+    #[instrument(skip(self, query), err)]
+    async fn save_persisted_query(
+        &self,
+        hash: &str,
+        query: &str,
+        max_stored: i64,
+        expired_before: OffsetDateTime,
+    ) -> Result<(), DbError> {
+        let now = OffsetDateTime::now_utc();
+
+        // Both statements see the table as it was, the saved query is left out of pruning
+        self.execute(
+            r"
+                WITH saved AS (
+                    INSERT INTO persisted_query (hash, query, created_on, used_on)
+                    VALUES ($1, $2, $3, $3)
+                    ON CONFLICT (hash) DO UPDATE SET used_on = EXCLUDED.used_on
+                )
+                DELETE FROM persisted_query
+                WHERE hash != $1 AND (used_on < $4 OR hash IN (
+                    SELECT hash FROM persisted_query
+                    WHERE hash != $1
+                    ORDER BY used_on DESC
+                    OFFSET $5::bigint - 1
+                ))
+            ",
+            &[&hash, &query, &now, &expired_before, &max_stored],
+        )
+        .await
+    }
+}
+
+/// Apollo style automatic persisted queries. In strict mode only the queries
+/// the client registered at build time get executed.
+#[derive(Clone)]
+pub struct PersistedQueries {
+    store: Arc<dyn QueryStore>,
+    allowlist: Arc<HashMap<String, String>>,
+    strict: bool,
+}
+
+// Registered queries only live as long as the process, and anything may run
+impl Default for PersistedQueries {
+    fn default() -> Self {
+        Self {
+            store: Arc::new(LruQueryStore::new()),
+            allowlist: Arc::default(),
+            strict: false,
+        }
+    }
+}
+
+impl PersistedQueries {
+    pub fn new(config: &PersistedQueriesConfig, repo: Repo) -> Result<Self, InfrastructureError> {
+        let store: Arc<dyn QueryStore> = match config.store {
+            QueryStoreKind::Memory => Arc::new(LruQueryStore::new()),
+            QueryStoreKind::Postgres => Arc::new(PostgresQueryStore::new(repo, config)),
+        };
+
+        let strict = config.strict;
+
+        // Validation made sure strict mode has one
+        let allowlist = match &config.allowlist {
+            Some(path) if strict => load_allowlist(path)?,
+            // The client might not have been compiled yet, which is fine during development
+            Some(path) => load_allowlist(path)
+                .inspect_err(|e| warn!("Starting without allowlist: {e}"))
+                .unwrap_or_default(),
+            None => HashMap::new(),
+        };
+
+        info!(
+            strict,
+            allowed = allowlist.len(),
+            "Persisted queries configured"
+        );
+
+        Ok(Self {
+            store,
+            allowlist: Arc::new(allowlist),
+            strict,
+        })
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    fn check_allowed(&self, hash: &str) -> ServerResult<()> {
+        if self.strict && !self.allowlist.contains_key(hash) {
+            return Err(
+                GqlError::Forbidden("Only persisted queries are allowed".to_string())
+                    .into_server_error(),
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn resolve(&self, mut request: Request) -> ServerResult<Request> {
+        let Some(hash) = requested_hash(&request) else {
+            self.check_allowed(&sha256(&request.query))?;
+            return Ok(request);
+        };
+
+        if request.query.is_empty() {
+            let query = match self.allowlist.get(&hash) {
+                Some(query) => Some(query.clone()),
+                None if !self.strict => self.store.get(&hash).await,
+                None => None,
+            };
+
+            request.query =
+                query.ok_or_else(|| GqlError::PersistedQueryNotFound.into_server_error())?;
+
+            return Ok(request);
+        }
+
+        if sha256(&request.query) != hash {
+            return Err(
+                GqlError::InvalidRequest("Provided sha does not match query".to_string())
+                    .into_server_error(),
+            );
+        }
+
+        self.check_allowed(&hash)?;
+
+        if !self.allowlist.contains_key(&hash) {
+            self.store.set(&hash, &request.query).await;
+        }
+
+        Ok(request)
+    }
+}
+
+impl ExtensionFactory for PersistedQueries {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(PersistedQueriesExtension(self.clone()))
+    }
+}
+
+struct PersistedQueriesExtension(PersistedQueries);
+
+#[async_trait]
+impl Extension for PersistedQueriesExtension {
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        request: Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<Request> {
+        let request = self.0.resolve(request).await?;
+        next.run(ctx, request).await
+    }
+}
+
+/// Relay writes a JSON object of query ids to query texts.
+fn load_allowlist(path: &Path) -> Result<HashMap<String, String>, InfrastructureError> {
+    let content = fs::read_to_string(path)?;
+
+    serde_json::from_str(&content).map_err(|e| {
+        InfrastructureError::config(vec![format!(
+            "persisted_queries.allowlist: {} is invalid: {e}",
+            path.display()
+        )])
+    })
+}
+
+fn requested_hash(request: &Request) -> Option<String> {
+    let Some(Value::Object(persisted_query)) = request.extensions.get("persistedQuery") else {
+        return None;
+    };
+
+    match persisted_query.get("sha256Hash") {
+        Some(Value::String(hash)) => Some(hash.clone()),
+        _ => None,
+    }
+}
+
+fn sha256(query: &str) -> String {
+    format!("{:x}", Sha256::digest(query))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_graphql::{Request, Value};
+    use time::Duration;
+
+    use crate::infrastructure::{config::PersistedQueriesConfig, db::Repo, testing::TestDb};
+
+    use super::{sha256, LruQueryStore, PersistedQueries, PostgresQueryStore, QueryStore};
+
+    const QUERY: &str = "{ viewer { role } }";
+
+    fn persisted_queries(strict: bool, allowlist: &[&str]) -> PersistedQueries {
+        PersistedQueries {
+            store: Arc::new(LruQueryStore::new()),
+            allowlist: Arc::new(
+                allowlist
+                    .iter()
+                    .map(|query| (sha256(query), query.to_string()))
+                    .collect(),
+            ),
+            strict,
+        }
+    }
+
+    fn request(query: &str, hash: &str) -> Request {
+        let persisted_query = Value::from_json(serde_json::json!({
+            "version": 1,
+            "sha256Hash": hash,
+        }))
+        .unwrap();
+
+        let mut request = Request::new(query);
+        request
+            .extensions
+            .insert("persistedQuery".to_string(), persisted_query);
+
+        request
+    }
+
+    #[test]
+    fn sha256_is_lowercase_hex() {
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            sha256("")
+        );
+    }
+
+    #[tokio::test]
+    async fn registers_and_serves_by_hash() {
+        let persisted_queries = persisted_queries(false, &[]);
+        let hash = sha256(QUERY);
+
+        assert!(persisted_queries.resolve(request("", &hash)).await.is_err());
+
+        persisted_queries
+            .resolve(request(QUERY, &hash))
+            .await
+            .unwrap();
+
+        let resolved = persisted_queries.resolve(request("", &hash)).await.unwrap();
+        assert_eq!(QUERY, resolved.query);
+    }
+
+    #[tokio::test]
+    async fn rejects_mismatched_hash() {
+        let persisted_queries = persisted_queries(false, &[]);
+
+        let result = persisted_queries
+            .resolve(request(QUERY, &sha256("{ other }")))
+            .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn strict_only_runs_allowlist() {
+        let persisted_queries = persisted_queries(true, &[QUERY]);
+
+        let resolved = persisted_queries
+            .resolve(request("", &sha256(QUERY)))
+            .await
+            .unwrap();
+        assert_eq!(QUERY, resolved.query);
+
+        assert!(persisted_queries.resolve(Request::new(QUERY)).await.is_ok());
+        assert!(persisted_queries
+            .resolve(Request::new("{ other }"))
+            .await
+            .is_err());
+        assert!(persisted_queries
+            .resolve(request("{ other }", &sha256("{ other }")))
+            .await
+            .is_err());
+    }
+
+    async fn stored(repo: &Repo, query: &str) -> bool {
+        repo.persisted_query(&sha256(query))
+            .await
+            .unwrap()
+            .is_some()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see infrastructure::testing"]
+    async fn postgres_store_prunes_least_recently_used() {
+        let db = TestDb::new().await;
+        let config = PersistedQueriesConfig {
+            max_stored: 2,
+            ..PersistedQueriesConfig::default()
+        };
+        let store = PostgresQueryStore::new(db.repo.clone(), &config);
+
+        for query in ["{ a }", "{ b }"] {
+            store.set(&sha256(query), query).await;
+        }
+        // Skips the cache, like another instance would
+        db.repo.persisted_query(&sha256("{ a }")).await.unwrap();
+        store.set(&sha256("{ c }"), "{ c }").await;
+
+        assert!(stored(&db.repo, "{ a }").await);
+        assert!(!stored(&db.repo, "{ b }").await);
+        assert!(stored(&db.repo, "{ c }").await);
+
+        let store = PostgresQueryStore {
+            expire_after: Duration::ZERO,
+            ..PostgresQueryStore::new(db.repo.clone(), &config)
+        };
+        store.set(&sha256("{ d }"), "{ d }").await;
+
+        assert!(!stored(&db.repo, "{ a }").await);
+        assert!(stored(&db.repo, "{ d }").await);
+
+        db.drop().await;
+    }
+}
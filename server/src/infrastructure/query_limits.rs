@@ -0,0 +1,305 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use async_graphql::{
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextParseQuery, NextValidation},
+    parser::types::{ExecutableDocument, Selection, SelectionSet},
+    ServerError, ServerResult, ValidationResult, Variables,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::GqlError;
+
+/// Limits every operation has to stay within, checked before anything gets resolved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryLimits {
+    pub complexity: usize,
+    pub depth: usize,
+    pub aliases: usize,
+    pub root_fields: usize,
+    pub friends: usize,
+    /// Cost every client can spend before having to wait for a refill
+    pub budget: usize,
+    pub budget_refill_per_second: usize,
+    /// Cost from which queries get reported in traces
+    pub expensive: usize,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        Self {
+            complexity: 1000,
+            depth: 15, // Enough for the introspection query of GraphiQL
+            aliases: 30,
+            root_fields: 10,
+            friends: 50,
+            budget: 20_000,
+            budget_refill_per_second: 1000,
+            expensive: 500,
+        }
+    }
+}
+
+impl QueryLimits {
+    fn check_document(&self, document: &ExecutableDocument) -> ServerResult<()> {
+        let aliases = count_aliases(document);
+        if aliases > self.aliases {
+            return Err(rejection(format!(
+                "Query uses {aliases} aliases, the limit is {}",
+                self.aliases
+            )));
+        }
+
+        let root_fields = count_root_fields(document);
+        if root_fields > self.root_fields {
+            return Err(rejection(format!(
+                "Query selects {root_fields} root fields, the limit is {}",
+                self.root_fields
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn check_validated(&self, result: &ValidationResult) -> Result<(), Vec<ServerError>> {
+        if result.complexity > self.complexity {
+            return Err(vec![rejection(format!(
+                "Query is too complex, it costs {} and the limit is {}",
+                result.complexity, self.complexity
+            ))]);
+        }
+
+        if result.depth > self.depth {
+            return Err(vec![rejection(format!(
+                "Query is nested too deep, it has depth {} and the limit is {}",
+                result.depth, self.depth
+            ))]);
+        }
+
+        Ok(())
+    }
+}
+
+// Checked here instead of with the limits of the schema builder, which only say that a limit was hit
+impl ExtensionFactory for QueryLimits {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(QueryLimitsExtension(self.clone()))
+    }
+}
+
+struct QueryLimitsExtension(QueryLimits);
+
+#[async_trait]
+impl Extension for QueryLimitsExtension {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let document = next.run(ctx, query, variables).await?;
+        self.0.check_document(&document)?;
+        Ok(document)
+    }
+
+    async fn validation(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        next: NextValidation<'_>,
+    ) -> Result<ValidationResult, Vec<ServerError>> {
+        let result = next.run(ctx).await?;
+        self.0.check_validated(&result)?;
+        Ok(result)
+    }
+}
+
+fn rejection(message: String) -> ServerError {
+    GqlError::InvalidRequest(message).into_server_error()
+}
+
+// Fragments count as often as they are spread, which is what resolving them costs
+fn count_aliases(document: &ExecutableDocument) -> usize {
+    let mut fragments = HashMap::new();
+
+    document
+        .operations
+        .iter()
+        .map(|(_, operation)| {
+            aliases_in_selection_set(document, &operation.node.selection_set.node, &mut fragments)
+        })
+        .fold(0, usize::saturating_add)
+}
+
+// Remembers the count of every fragment, so nested spreads do not have to be walked again.
+// A fragment still being counted is part of a cycle, which validation rejects later.
+fn aliases_in_selection_set<'a>(
+    document: &'a ExecutableDocument,
+    selection_set: &'a SelectionSet,
+    fragments: &mut HashMap<&'a str, Option<usize>>,
+) -> usize {
+    selection_set
+        .items
+        .iter()
+        .map(|selection| match &selection.node {
+            Selection::Field(field) => usize::from(field.node.alias.is_some()).saturating_add(
+                aliases_in_selection_set(document, &field.node.selection_set.node, fragments),
+            ),
+            Selection::InlineFragment(fragment) => {
+                aliases_in_selection_set(document, &fragment.node.selection_set.node, fragments)
+            }
+            Selection::FragmentSpread(spread) => {
+                let name = spread.node.fragment_name.node.as_str();
+
+                match (fragments.get(name), document.fragments.get(name)) {
+                    (Some(counted), _) => counted.unwrap_or(0),
+                    (None, Some(fragment)) => {
+                        fragments.insert(name, None);
+                        let count = aliases_in_selection_set(
+                            document,
+                            &fragment.node.selection_set.node,
+                            fragments,
+                        );
+                        fragments.insert(name, Some(count));
+                        count
+                    }
+                    (None, None) => 0,
+                }
+            }
+        })
+        .fold(0, usize::saturating_add)
+}
+
+fn count_root_fields(document: &ExecutableDocument) -> usize {
+    document
+        .operations
+        .iter()
+        .map(|(_, operation)| {
+            root_fields_in_selection_set(
+                document,
+                &operation.node.selection_set.node,
+                &mut HashSet::new(),
+            )
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+// Fragments are not validated yet, so cycles have to be guarded against
+fn root_fields_in_selection_set<'a>(
+    document: &'a ExecutableDocument,
+    selection_set: &'a SelectionSet,
+    visited: &mut HashSet<&'a str>,
+) -> usize {
+    selection_set
+        .items
+        .iter()
+        .map(|selection| match &selection.node {
+            Selection::Field(_) => 1,
+            Selection::InlineFragment(fragment) => {
+                root_fields_in_selection_set(document, &fragment.node.selection_set.node, visited)
+            }
+            Selection::FragmentSpread(spread) => {
+                let name = spread.node.fragment_name.node.as_str();
+
+                match document.fragments.get(&spread.node.fragment_name.node) {
+                    Some(fragment) if visited.insert(name) => root_fields_in_selection_set(
+                        document,
+                        &fragment.node.selection_set.node,
+                        visited,
+                    ),
+                    _ => 0,
+                }
+            }
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::parser::parse_query;
+
+    use super::{count_aliases, count_root_fields};
+
+    #[test]
+    fn aliases_in_operations_and_fragments() {
+        let document = parse_query(
+            r"
+                query {
+                    a: viewer { role }
+                    b: viewer { ...Drafts }
+                }
+                fragment Drafts on Viewer { c: drafts { pageInfo { hasNextPage } } }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(3, count_aliases(&document));
+    }
+
+    #[test]
+    fn aliases_of_fragments_count_per_spread() {
+        let document = parse_query(
+            r"
+                query {
+                    viewer { ...Twice ...Twice }
+                    ... on Query { viewer { ...Twice } }
+                }
+                fragment Twice on Viewer { ...Role ...Role }
+                fragment Role on Viewer { a: role }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(6, count_aliases(&document));
+    }
+
+    #[test]
+    fn aliases_with_fragment_cycle() {
+        let document = parse_query(
+            r"
+                query { ...A }
+                fragment A on Query { a: viewer { role } ...B }
+                fragment B on Query { b: viewer { role } ...A }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(2, count_aliases(&document));
+    }
+
+    #[test]
+    fn root_fields_through_fragments() {
+        let document = parse_query(
+            r"
+                query {
+                    viewer { role }
+                    ... on Query { a: viewer { role } }
+                    ...Root
+                }
+                fragment Root on Query { b: viewer { role } c: viewer { role } }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(4, count_root_fields(&document));
+    }
+
+    #[test]
+    fn root_fields_with_fragment_cycle() {
+        let document = parse_query(
+            r"
+                query { ...A }
+                fragment A on Query { viewer { role } ...B }
+                fragment B on Query { viewer { role } ...A }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(2, count_root_fields(&document));
+    }
+}
@@ -1,5 +1,7 @@
-use async_graphql_axum::GraphQLSubscription;
-use axum::{routing::get, Router};
+use axum::{
+    routing::{get, post},
+    Router,
+};
 use std::time::Duration;
 use tower::ServiceBuilder;
 use tower_http::{
@@ -10,25 +12,31 @@ use tower_http::{
 use super::{app_state::AppState, handlers, logging::CustomMakeSpan};
 
 pub fn new(app_state: AppState) -> Router {
+    let timeout = Duration::from_secs(app_state.config.http.request_timeout_secs);
+
     // Wrapped top to bottom
     let middleware = ServiceBuilder::new()
         .layer(TraceLayer::new_for_http().make_span_with(CustomMakeSpan))
         .layer(CatchPanicLayer::new())
-        .layer(TimeoutLayer::new(Duration::from_secs(30)))
+        .layer(TimeoutLayer::new(timeout))
         .layer(CompressionLayer::new())
         .layer(CorsLayer::permissive())
         .into_inner();
 
+    let graphql = if app_state.graphiql {
+        get(handlers::graphiql).post(handlers::graphql_handler)
+    } else {
+        post(handlers::graphql_handler)
+    };
+
     // Wrapped bottom to top
     Router::new()
         .route("/health-check", get(handlers::health_check))
+        .route("/graphql", graphql)
+        .route("/graphql/ws", get(handlers::graphql_ws_handler))
         .route(
-            "/graphql",
-            get(handlers::graphiql).post(handlers::graphql_handler),
-        )
-        .route_service(
-            "/graphql/ws",
-            GraphQLSubscription::new(app_state.schema.clone()),
+            "/graphql/stream",
+            get(handlers::graphql_stream_handler).post(handlers::graphql_stream_handler),
         )
         .layer(middleware)
         .with_state(app_state)
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use tokio::{spawn, time::interval};
+use tracing::{debug, error};
+
+use super::{config::SchedulerConfig, storage::Storage};
+
+#[derive(Clone)]
+pub struct PostScheduler {
+    storage: Storage,
+    period: Duration,
+    batch_size: i64,
+}
+
+impl PostScheduler {
+    pub fn new(storage: Storage, config: &SchedulerConfig) -> Self {
+        Self {
+            storage,
+            period: Duration::from_secs(config.interval_secs),
+            batch_size: i64::from(config.batch_size),
+        }
+    }
+
+    pub fn start_daemon(&self) {
+        let storage = self.storage.clone();
+        let batch_size = self.batch_size;
+        let mut interval = interval(self.period);
+
+        spawn(async move {
+            loop {
+                interval.tick().await;
+                Self::publish_due_posts(&storage, batch_size).await;
+            }
+        });
+    }
+
+    async fn publish_due_posts(storage: &Storage, batch_size: i64) {
+        loop {
+            match storage.publish_due_posts(batch_size).await {
+                Ok(published) => {
+                    if !published.is_empty() {
+                        debug!("Published {} scheduled posts", published.len());
+                    }
+
+                    if (published.len() as i64) < batch_size {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    error!("Could not publish scheduled posts: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
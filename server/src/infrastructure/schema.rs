@@ -1,18 +1,25 @@
-use std::{fs, sync::Arc};
+use std::sync::Arc;
 
 use async_graphql::{
-    extensions::{Extension, ExtensionContext, ExtensionFactory, NextValidation},
-    SchemaBuilder, ServerError, ValidationResult,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextRequest, NextSubscribe},
+    ErrorExtensions, Response, SchemaBuilder, ServerError,
 };
 use async_trait::async_trait;
-use tracing::debug;
+use futures::{stream::BoxStream, StreamExt};
+use tracing::error;
 
-use crate::domain::schema::{RootMutation, RootQuery, RootSubscription};
+use crate::domain::{
+    schema::{RootMutation, RootQuery, RootSubscription},
+    GqlError,
+};
 
 use super::{
-    db::{Loaders, Repo},
-    errors::InfrastructureError,
+    config::Config,
+    cost::CostBudgets,
+    logging,
     notification_center::NotificationCenter,
+    persisted_queries::PersistedQueries,
+    storage::{Loaders, Storage},
     urls::Urls,
 };
 
@@ -20,53 +27,448 @@ fn schema_builder() -> SchemaBuilder<RootQuery, RootMutation, RootSubscription>
     Schema::build(RootQuery, RootMutation, RootSubscription)
 }
 
-pub fn new(repo: Repo, notification_center: NotificationCenter, urls: Urls) -> Schema {
+pub fn new(
+    storage: Storage,
+    notification_center: NotificationCenter,
+    persisted_queries: PersistedQueries,
+    config: &Config,
+) -> Schema {
+    let limits = config.query_limit.clone();
+
     schema_builder()
         // Will get overriden for every request. This is a fallback for subscriptions.
-        .data(Loaders::new(repo.clone()))
+        .data(Loaders::new(storage.clone()))
         .data(notification_center)
-        .data(repo)
+        .data(storage)
         .data(reqwest::Client::new())
-        .data(urls)
-        .extension(ComplexityExtensionFactory)
-        .limit_complexity(1000)
+        .data(Urls::new(&config.service_ads))
+        .data(limits.clone())
+        .data(config.subscriptions.clone())
+        .extension(persisted_queries)
+        .extension(CostBudgets::new(limits.clone()))
+        .extension(limits)
+        .extension(ErrorCodeExtensionFactory)
         .finish()
 }
 
-pub fn save_schema(path: &str) -> Result<(), InfrastructureError> {
-    let schema = schema_builder().finish();
-    fs::write(path, schema.sdl())?;
-    Ok(())
+/// The schema in SDL, as clients compile against it.
+pub fn sdl() -> String {
+    schema_builder().finish().sdl()
 }
 
 pub type Schema = async_graphql::Schema<RootQuery, RootMutation, RootSubscription>;
 
-struct ComplexityExtensionFactory;
+struct ErrorCodeExtensionFactory;
 
-impl ExtensionFactory for ComplexityExtensionFactory {
+impl ExtensionFactory for ErrorCodeExtensionFactory {
     fn create(&self) -> Arc<dyn Extension> {
-        Arc::new(ComplexityExtension)
+        Arc::new(ErrorCodeExtension)
     }
 }
 
-struct ComplexityExtension;
+struct ErrorCodeExtension;
 
 #[async_trait]
-impl Extension for ComplexityExtension {
-    async fn validation(
+impl Extension for ErrorCodeExtension {
+    async fn request(&self, ctx: &ExtensionContext<'_>, next: NextRequest<'_>) -> Response {
+        with_error_codes(next.run(ctx).await)
+    }
+
+    fn subscribe<'s>(
         &self,
         ctx: &ExtensionContext<'_>,
-        next: NextValidation<'_>,
-    ) -> Result<ValidationResult, Vec<ServerError>> {
-        let res = next.run(ctx).await;
+        stream: BoxStream<'s, Response>,
+        next: NextSubscribe<'_>,
+    ) -> BoxStream<'s, Response> {
+        next.run(ctx, stream).map(with_error_codes).boxed()
+    }
+}
+
+fn with_error_codes(mut response: Response) -> Response {
+    if response.errors.is_empty() {
+        return response;
+    }
+
+    let trace_id = logging::current_trace_id();
+
+    for error in response.errors.iter_mut() {
+        with_error_code(error, &trace_id);
+    }
+
+    response
+}
+
+fn with_error_code(error: &mut ServerError, trace_id: &str) {
+    let extended = match error.source::<GqlError>() {
+        Some(gql_error) => {
+            if gql_error.is_internal() {
+                error!(
+                    trace_id,
+                    path = ?error.path,
+                    error = gql_error.report(),
+                    "Request failed"
+                );
+            }
+
+            gql_error.extend()
+        }
+        // Anything not raised by a resolver comes from parsing or validating the request
+        None => error
+            .message
+            .clone()
+            .extend_with(|_, e| e.set("code", "VALIDATION")),
+    };
+
+    let mut extensions = extended.extensions.unwrap_or_default();
+    extensions.set("traceId", trace_id);
+
+    error.message = extended.message;
+    error.extensions = Some(extensions);
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use serde_json::{json, Value};
+
+    use crate::{
+        domain::{app_user::Role, db_id::DbId},
+        infrastructure::{
+            config::SubscriptionConfig,
+            testing::{next_data, TestApp},
+        },
+    };
+
+    use super::sdl;
+
+    // The server no longer writes it on startup, `server export-schema --out schema.graphql` does
+    #[test]
+    fn exported_schema_is_current() {
+        assert_eq!(include_str!("../../schema.graphql"), sdl());
+    }
+
+    #[tokio::test]
+    async fn mutations_are_queryable() {
+        let app = TestApp::in_memory().await;
+        let (alice, alice_id) = app.user("Alice").await;
+        let (bob, bob_id) = app.user("Bob").await;
+
+        app.befriend(alice, &bob_id).await;
+        let post_id = app.post(alice, "Hello").await;
+
+        let query = format!(
+            r#"mutation {{ createComment(input: {{ referencedPost: "{post_id}", content: "Hi" }}) {{ node {{ id }} }} }}"#
+        );
+        app.execute(bob, &query).await;
+
+        let query = format!(
+            r#"{{
+                node(id: "{post_id}") {{
+                    ... on Post {{
+                        content
+                        author {{ firstName }}
+                        comments {{ totalCount edges {{ node {{ content author {{ firstName }} }} }} }}
+                    }}
+                }}
+                user(id: "{alice_id}") {{
+                    friends {{ firstName }}
+                    posts {{ totalCount }}
+                }}
+            }}"#
+        );
+        let data = app.execute(bob, &query).await;
+
+        assert_eq!(
+            json!({
+                "node": {
+                    "content": "Hello",
+                    "author": { "firstName": "Alice" },
+                    "comments": {
+                        "totalCount": 1,
+                        "edges": [{ "node": { "content": "Hi", "author": { "firstName": "Bob" } } }],
+                    },
+                },
+                "user": {
+                    "friends": [{ "firstName": "Bob" }],
+                    "posts": { "totalCount": 1 },
+                },
+            }),
+            data
+        );
+    }
+
+    #[tokio::test]
+    async fn moderators_hide_reported_posts() {
+        let app = TestApp::in_memory().await;
+        let (alice, _) = app.user("Alice").await;
+        let (bob, _) = app.user("Bob").await;
+        let (moderator, _) = app.user("Mod").await;
+        app.storage
+            .set_role(&moderator, Role::Moderator)
+            .await
+            .unwrap();
+
+        let post_id = app.post(alice, "Spam").await;
+
+        let query = format!(
+            r#"mutation {{ reportContent(input: {{ targetId: "{post_id}", reason: "Spam" }}) {{ id }} }}"#
+        );
+        let data = app.execute(bob, &query).await;
+        let report_id = data["reportContent"]["id"].as_str().unwrap();
+
+        let query = format!(
+            r#"mutation {{ resolveReport(input: {{ report: "{report_id}", action: HIDE_CONTENT }}) {{ resolution }} }}"#
+        );
+        let data = app.execute(moderator, &query).await;
+        assert_eq!("HIDE_CONTENT", data["resolveReport"]["resolution"]);
+
+        let data = app
+            .execute(moderator, "{ moderationQueue { totalCount } }")
+            .await;
+        assert_eq!(0, data["moderationQueue"]["totalCount"]);
+
+        let query = format!(r#"{{ node(id: "{post_id}") {{ id }} }}"#);
+        let response = app.schema.execute(app.request(bob, &query)).await;
+        assert_eq!(
+            format!("Not found: No node with id {post_id}"),
+            response.errors[0].message
+        );
+    }
+
+    #[tokio::test]
+    async fn suspended_users_can_not_change_anything() {
+        let app = TestApp::in_memory().await;
+        let (alice, _) = app.user("Alice").await;
+        let (_, bob_id) = app.user("Bob").await;
+        let post_id = app.post(alice, "Post").await;
+        let comment_id = app.comment(alice, &post_id, "Comment").await;
+        app.storage.set_suspended(&alice, true).await.unwrap();
+
+        for query in [
+            format!(
+                r#"mutation {{ addFriend(input: {{ friend: "{bob_id}" }}) {{ userErrors {{ message }} }} }}"#
+            ),
+            format!(
+                r#"mutation {{ editPost(input: {{ post: "{post_id}", content: "Edited" }}) {{ content }} }}"#
+            ),
+            format!(r#"mutation {{ publishPost(input: {{ post: "{post_id}" }}) {{ cursor }} }}"#),
+            format!(
+                r#"mutation {{ editComment(input: {{ comment: "{comment_id}", content: "Edited" }}) {{ content }} }}"#
+            ),
+        ] {
+            let response = app.try_execute(alice, &query).await;
+
+            assert_eq!("Forbidden: Viewer is suspended", response.errors[0].message);
+        }
+    }
+
+    #[tokio::test]
+    async fn home_feed_streams_posts_of_friends() {
+        let app = TestApp::in_memory().await;
+        let (alice, _) = app.user("Alice").await;
+        let (bob, bob_id) = app.user("Bob").await;
+        let (stranger, _) = app.user("Stranger").await;
+        app.befriend(alice, &bob_id).await;
+
+        let mut feed = app
+            .subscribe(
+                alice,
+                "subscription { homeFeed { node { content author { firstName } } } }",
+            )
+            .await;
+
+        app.post(stranger, "Not for Alice").await;
+        app.post(bob, "For Alice").await;
+
+        assert_eq!(
+            json!({
+                "homeFeed": [{ "node": { "content": "For Alice", "author": { "firstName": "Bob" } } }],
+            }),
+            next_data(&mut feed).await
+        );
+    }
+
+    #[tokio::test]
+    async fn home_feed_streams_posts_of_new_friends_right_away() {
+        let app = TestApp::in_memory().await;
+        let (alice, _) = app.user("Alice").await;
+        let (bob, bob_id) = app.user("Bob").await;
+
+        let mut feed = app
+            .subscribe(alice, "subscription { homeFeed { node { content } } }")
+            .await;
 
-        debug!(
-            "Complexity: {:?}",
-            res.as_ref()
-                .map(|it| it.complexity.to_string())
-                .unwrap_or("unknown".to_string())
+        // Posted before the feed had a chance to hear about the friendship
+        app.befriend(alice, &bob_id).await;
+        app.post(bob, "Right away").await;
+
+        assert_eq!(
+            json!({ "homeFeed": [{ "node": { "content": "Right away" } }] }),
+            next_data(&mut feed).await
         );
+    }
+
+    #[tokio::test]
+    async fn user_feed_streams_new_and_published_posts_only() {
+        let app = TestApp::in_memory().await;
+        let (alice, alice_id) = app.user("Alice").await;
+        let post_id = app.post(alice, "Old").await;
+        let data = app
+            .execute(
+                alice,
+                r#"mutation { createPost(input: { content: "Draft", draft: true }) { node { id } } }"#,
+            )
+            .await;
+        let draft_id = data["createPost"]["node"]["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let query = format!(
+            r#"subscription {{ userFeed(userId: "{alice_id}") {{ node {{ content }} }} }}"#
+        );
+        let mut feed = app.subscribe(alice, &query).await;
+
+        let edit = format!(
+            r#"mutation {{ editPost(input: {{ post: "{post_id}", content: "Edited" }}) {{ content }} }}"#
+        );
+        app.execute(alice, &edit).await;
+        let publish =
+            format!(r#"mutation {{ publishPost(input: {{ post: "{draft_id}" }}) {{ cursor }} }}"#);
+        app.execute(alice, &publish).await;
+
+        assert_eq!(
+            json!({ "userFeed": [{ "node": { "content": "Draft" } }] }),
+            next_data(&mut feed).await
+        );
+    }
+
+    fn contents(data: &Value, feed: &str) -> Vec<String> {
+        data[feed]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|edge| edge["node"]["content"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    /* More is posted while the replay is paused on its first page than a listener holds,
+    which all turns up on the second page and must neither come again nor end the feed. */
+    async fn replays_then_goes_live(
+        app: &TestApp,
+        viewer: DbId,
+        author: DbId,
+        feed: &str,
+        arguments: &str,
+    ) {
+        let data = app
+            .execute(
+                author,
+                r#"mutation { createPost(input: { content: "Seen" }) { edge { cursor } } }"#,
+            )
+            .await;
+        let cursor = data["createPost"]["edge"]["cursor"].as_str().unwrap();
+
+        let old: Vec<String> = (0..60).map(|i| format!("Old {i}")).collect();
+        for content in &old {
+            app.post(author, content).await;
+        }
 
-        res
+        let query = format!(
+            r#"subscription {{ {feed}({arguments}after: "{cursor}") {{ node {{ content }} }} }}"#
+        );
+        let mut stream = app
+            .schema
+            .execute_stream(app.request(viewer, &query))
+            .boxed();
+
+        assert_eq!(old[..50], contents(&next_data(&mut stream).await, feed));
+
+        let new: Vec<String> = (0..20).map(|i| format!("New {i}")).collect();
+        for content in &new {
+            app.post(author, content).await;
+        }
+
+        assert_eq!(
+            [&old[50..], &new[..]].concat(),
+            contents(&next_data(&mut stream).await, feed)
+        );
+
+        app.post(author, "Live").await;
+        assert_eq!(vec!["Live"], contents(&next_data(&mut stream).await, feed));
+    }
+
+    #[tokio::test]
+    async fn user_feed_replays_then_goes_live() {
+        let app = TestApp::in_memory().await;
+        let (alice, _) = app.user("Alice").await;
+        let (bob, bob_id) = app.user("Bob").await;
+
+        let arguments = format!(r#"userId: "{bob_id}", "#);
+        replays_then_goes_live(&app, alice, bob, "userFeed", &arguments).await;
+    }
+
+    #[tokio::test]
+    async fn home_feed_replays_then_goes_live() {
+        let app = TestApp::in_memory().await;
+        let (alice, _) = app.user("Alice").await;
+        let (bob, bob_id) = app.user("Bob").await;
+        app.befriend(alice, &bob_id).await;
+
+        replays_then_goes_live(&app, alice, bob, "homeFeed", "").await;
+    }
+
+    #[tokio::test]
+    async fn feeds_that_missed_too_much_fail() {
+        let app = TestApp::in_memory().await;
+        let (alice, alice_id) = app.user("Alice").await;
+        let data = app
+            .execute(
+                alice,
+                r#"mutation { createPost(input: { content: "Seen" }) { edge { cursor } } }"#,
+            )
+            .await;
+        let cursor = data["createPost"]["edge"]["cursor"].as_str().unwrap();
+
+        let query = format!(
+            r#"subscription {{ userFeed(userId: "{alice_id}", after: "{cursor}") {{ node {{ content }} }} }}"#
+        );
+        let config = SubscriptionConfig {
+            replay_page: 2,
+            max_replay_pages: 2,
+            ..SubscriptionConfig::default()
+        };
+        let feed = |config: SubscriptionConfig| {
+            app.schema
+                .execute_stream(app.request(alice, &query).data(config))
+                .boxed()
+        };
+
+        // What the pages hold still goes live
+        for i in 0..3 {
+            app.post(alice, &format!("Missed {i}")).await;
+        }
+
+        let mut stream = feed(config.clone());
+        next_data(&mut stream).await;
+        next_data(&mut stream).await;
+        app.post(alice, "Live").await;
+        assert_eq!(
+            vec!["Live"],
+            contents(&next_data(&mut stream).await, "userFeed")
+        );
+
+        // More than that ends the feed
+        app.post(alice, "Missed 3").await;
+        let mut stream = feed(config);
+        next_data(&mut stream).await;
+        next_data(&mut stream).await;
+
+        let response = serde_json::to_value(stream.next().await.unwrap()).unwrap();
+        assert_eq!(
+            json!("VALIDATION"),
+            response["errors"][0]["extensions"]["code"]
+        );
+        assert!(stream.next().await.is_none());
     }
 }
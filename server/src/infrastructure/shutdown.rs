@@ -1,4 +1,7 @@
-use tokio::signal;
+use std::{sync::Arc, time::Duration};
+
+use tokio::{signal, sync::watch, time::timeout};
+use tracing::warn;
 
 pub async fn signal() {
     let ctrl_c = async {
@@ -25,3 +28,39 @@ pub async fn signal() {
 
     tracing::info!("Starting shutdown");
 }
+
+/// Streaming responses and upgraded connections would keep `serve` from ever
+/// returning, they get asked to close through this once the signal arrives.
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            tx: Arc::new(watch::Sender::new(false)),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.tx.subscribe()
+    }
+
+    pub async fn signal(self) {
+        signal().await;
+        self.tx.send_replace(true);
+    }
+
+    /// Waits until every connection closed, at most for `grace`.
+    pub async fn closed(self, grace: Duration) {
+        if timeout(grace, self.tx.closed()).await.is_err() {
+            warn!(
+                "{} connections did not close in time",
+                self.tx.receiver_count()
+            );
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::{future::Future, sync::Arc};
+
+use async_graphql::dataloader::{DataLoader, HashMapCache};
+use tokio::{spawn, task::JoinHandle};
+use tracing::Instrument;
+
+use crate::domain::{
+    app_user::{AppUserLoader, FriendIdLoader, RelationStore, UserStore},
+    comment::{CommentCountOfPostLoader, CommentLoader, CommentStore, CommentsOfPostLoader},
+    post::{PostCountOfAuthorLoader, PostLoader, PostStore, PostsOfAuthorLoader},
+    report::ReportStore,
+};
+
+/// Everything the schema keeps, implemented by the repo and, for tests, in memory.
+pub trait Store: UserStore + RelationStore + PostStore + CommentStore + ReportStore {}
+
+impl<T> Store for T where T: UserStore + RelationStore + PostStore + CommentStore + ReportStore {}
+
+pub type Storage = Arc<dyn Store>;
+
+fn spawn_in_span<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn(future.in_current_span())
+}
+
+pub struct Loaders {
+    pub app_user: DataLoader<AppUserLoader, HashMapCache>,
+    pub friend_id: DataLoader<FriendIdLoader, HashMapCache>,
+    pub post: DataLoader<PostLoader, HashMapCache>,
+    pub posts_of_author: DataLoader<PostsOfAuthorLoader, HashMapCache>,
+    pub post_count_of_author: DataLoader<PostCountOfAuthorLoader, HashMapCache>,
+    pub comment: DataLoader<CommentLoader, HashMapCache>,
+    pub comments_of_post: DataLoader<CommentsOfPostLoader, HashMapCache>,
+    pub comment_count_of_post: DataLoader<CommentCountOfPostLoader, HashMapCache>,
+}
+
+impl Loaders {
+    pub fn new(storage: Storage) -> Self {
+        Self {
+            app_user: DataLoader::with_cache(
+                AppUserLoader::new(storage.clone()),
+                spawn_in_span,
+                HashMapCache::default(),
+            ),
+            friend_id: DataLoader::with_cache(
+                FriendIdLoader::new(storage.clone()),
+                spawn_in_span,
+                HashMapCache::default(),
+            ),
+            post: DataLoader::with_cache(
+                PostLoader::new(storage.clone()),
+                spawn_in_span,
+                HashMapCache::default(),
+            ),
+            posts_of_author: DataLoader::with_cache(
+                PostsOfAuthorLoader::new(storage.clone()),
+                spawn_in_span,
+                HashMapCache::default(),
+            ),
+            post_count_of_author: DataLoader::with_cache(
+                PostCountOfAuthorLoader::new(storage.clone()),
+                spawn_in_span,
+                HashMapCache::default(),
+            ),
+            comment: DataLoader::with_cache(
+                CommentLoader::new(storage.clone()),
+                spawn_in_span,
+                HashMapCache::default(),
+            ),
+            comments_of_post: DataLoader::with_cache(
+                CommentsOfPostLoader::new(storage.clone()),
+                spawn_in_span,
+                HashMapCache::default(),
+            ),
+            comment_count_of_post: DataLoader::with_cache(
+                CommentCountOfPostLoader::new(storage),
+                spawn_in_span,
+                HashMapCache::default(),
+            ),
+        }
+    }
+
+    pub fn clear_caches(&self) {
+        self.app_user.clear();
+        self.friend_id.clear();
+        self.post.clear();
+        self.posts_of_author.clear();
+        self.post_count_of_author.clear();
+        self.comment.clear();
+        self.comments_of_post.clear();
+        self.comment_count_of_post.clear();
+    }
+}
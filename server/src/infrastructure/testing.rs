@@ -0,0 +1,280 @@
+use std::{
+    env,
+    process::{self, Child, Command, Stdio},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use async_graphql::{Request, Response, ID};
+use futures::{stream::BoxStream, StreamExt};
+use serde_json::{json, Value};
+use tokio::time::{sleep, timeout};
+use tokio_postgres::{Client, Config, NoTls};
+use uuid::Uuid;
+
+use crate::domain::{
+    app_user::AppUser,
+    auth::Session,
+    db_id::{CanDecodeId, DbId},
+};
+
+use super::{
+    config::{self, NotificationConfig},
+    db::{self, Repo, TransactionRetries},
+    memory::MemoryStore,
+    notification_center::{NotificationCenter, NotificationSource},
+    persisted_queries::PersistedQueries,
+    schema::{self, Schema},
+    storage::{Loaders, Storage},
+};
+
+/// The whole schema on some storage, driven like the handlers drive it.
+pub struct TestApp {
+    pub schema: Schema,
+    pub storage: Storage,
+    pub notification_center: NotificationCenter,
+}
+
+impl TestApp {
+    pub async fn new(storage: Storage, source: Arc<dyn NotificationSource>) -> Self {
+        let mut notification_center =
+            NotificationCenter::new(source, NotificationConfig::default());
+        notification_center.start_daemon().await.unwrap();
+
+        let schema = schema::new(
+            storage.clone(),
+            notification_center.clone(),
+            PersistedQueries::default(),
+            &config::Config::default(),
+        );
+
+        Self {
+            schema,
+            storage,
+            notification_center,
+        }
+    }
+
+    pub async fn in_memory() -> Self {
+        let store = Arc::new(MemoryStore::default());
+
+        Self::new(store.clone(), store).await
+    }
+
+    pub async fn on(db: &TestDb) -> Self {
+        Self::new(Arc::new(db.repo.clone()), Arc::new(db.repo.clone())).await
+    }
+
+    // Like a handler does for every request
+    pub fn request(&self, user_id: DbId, query: &str) -> Request {
+        Request::new(query)
+            .data(Loaders::new(self.storage.clone()))
+            .data(Session::new(user_id))
+    }
+
+    pub async fn try_execute(&self, user_id: DbId, query: &str) -> Response {
+        self.schema.execute(self.request(user_id, query)).await
+    }
+
+    pub async fn execute(&self, user_id: DbId, query: &str) -> Value {
+        let response = self.try_execute(user_id, query).await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+        response.data.into_json().unwrap()
+    }
+
+    /// Starts a subscription and waits until it listens, so nothing sent afterwards is
+    /// missed.
+    pub async fn subscribe(&self, user_id: DbId, query: &str) -> BoxStream<'static, Response> {
+        let mut stream = self
+            .schema
+            .execute_stream(self.request(user_id, query))
+            .boxed();
+
+        // Nothing to deliver yet, but the listener gets registered on the way
+        assert!(timeout(Duration::from_millis(50), stream.next())
+            .await
+            .is_err());
+
+        stream
+    }
+
+    pub async fn user(&self, first_name: &str) -> (DbId, String) {
+        let query = format!(
+            r#"mutation {{ createUser(input: {{ firstName: "{first_name}", lastName: "Test" }}) {{ node {{ id }} }} }}"#
+        );
+        let data = self.execute(DbId::from(0), &query).await;
+
+        let id = data["createUser"]["node"]["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        (AppUser::decode(&ID(id.clone())).unwrap(), id)
+    }
+
+    pub async fn post(&self, author: DbId, content: &str) -> String {
+        let query = format!(
+            r#"mutation {{ createPost(input: {{ content: "{content}" }}) {{ node {{ id }} }} }}"#
+        );
+        let data = self.execute(author, &query).await;
+
+        data["createPost"]["node"]["id"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    pub async fn comment(&self, author: DbId, post_id: &str, content: &str) -> String {
+        let query = format!(
+            r#"mutation {{ createComment(input: {{ referencedPost: "{post_id}", content: "{content}" }}) {{ node {{ id }} }} }}"#
+        );
+        let data = self.execute(author, &query).await;
+
+        data["createComment"]["node"]["id"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    pub async fn befriend(&self, user: DbId, friend: &str) {
+        let query = format!(
+            r#"mutation {{ addFriend(input: {{ friend: "{friend}" }}) {{ userErrors {{ message }} }} }}"#
+        );
+        let data = self.execute(user, &query).await;
+
+        assert_eq!(json!([]), data["addFriend"]["userErrors"]);
+    }
+}
+
+/// Waits for the next response of a subscription, which must not be an error.
+pub async fn next_data(stream: &mut BoxStream<'static, Response>) -> Value {
+    let response = timeout(Duration::from_secs(5), stream.next())
+        .await
+        .expect("Subscription should have sent something")
+        .expect("Subscription should not have ended");
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+    response.data.into_json().unwrap()
+}
+
+/* Tests against Postgres get a database of their own, so they can run in parallel. A schema
+each would not do, since LISTEN/NOTIFY channels span the whole database and the listeners
+of parallel tests would hear each other. The server is TEST_DATABASE_URL, which needs to allow
+creating databases, or else a throwaway cluster started with initdb. These tests are ignored
+by default, `cargo test -- --include-ignored` runs them. */
+struct TestServer {
+    config: Config,
+    // Stops once the test process exits, which closes its stdin
+    _cluster: Option<Child>,
+}
+
+static SERVER: OnceLock<TestServer> = OnceLock::new();
+
+// Runs the cluster until stdin closes, then removes it
+const CLUSTER: &str = r#"
+postgres -D "$1" -k "$1" -c listen_addresses= -c fsync=off -c max_connections=500 &
+read _
+kill -INT $!
+wait
+rm -rf "$1"
+"#;
+
+fn server() -> &'static TestServer {
+    SERVER.get_or_init(|| match env::var("TEST_DATABASE_URL") {
+        Ok(url) => TestServer {
+            config: url
+                .parse()
+                .expect("TEST_DATABASE_URL should be a connection string"),
+            _cluster: None,
+        },
+        Err(_) => start_cluster(),
+    })
+}
+
+fn start_cluster() -> TestServer {
+    let dir = env::temp_dir().join(format!("fakebook-test-{}", process::id()));
+
+    let initdb = Command::new("initdb")
+        .args(["--auth=trust", "--username=postgres", "--no-sync", "-D"])
+        .arg(&dir)
+        .output()
+        .expect("initdb should be on the PATH, or TEST_DATABASE_URL be set");
+    assert!(
+        initdb.status.success(),
+        "initdb failed, it refuses to run as root: {}",
+        String::from_utf8_lossy(&initdb.stderr)
+    );
+
+    let cluster = Command::new("sh")
+        .args(["-c", CLUSTER, "sh"])
+        .arg(&dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Cluster should have started");
+
+    let mut config = Config::new();
+    config.host_path(&dir);
+    config.user("postgres");
+    config.dbname("postgres");
+
+    TestServer {
+        config,
+        _cluster: Some(cluster),
+    }
+}
+
+async fn connect_to_server() -> Client {
+    let mut attempts = 0;
+
+    // A throwaway cluster takes a moment to accept connections
+    loop {
+        match server().config.connect(NoTls).await {
+            Ok((client, connection)) => {
+                tokio::spawn(connection);
+                return client;
+            }
+            Err(e) if attempts == 50 => panic!("Test database server is unreachable: {e}"),
+            Err(_) => {
+                attempts += 1;
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// A migrated database of its own. Dropped by [TestDb::drop], unless the test fails before,
+/// which keeps it for a look.
+pub struct TestDb {
+    pub repo: Repo,
+    name: String,
+}
+
+impl TestDb {
+    pub async fn new() -> Self {
+        let name = format!("fakebook_test_{}", Uuid::new_v4().simple());
+
+        connect_to_server()
+            .await
+            .batch_execute(&format!("CREATE DATABASE {name}"))
+            .await
+            .expect("Test database should have been created");
+
+        let mut config = server().config.clone();
+        config.dbname(&name);
+
+        let repo = db::connect(config, TransactionRetries::default()).unwrap();
+        db::migrate(&repo).await.expect("Migrations should succeed");
+
+        Self { repo, name }
+    }
+
+    pub async fn drop(self) {
+        connect_to_server()
+            .await
+            .batch_execute(&format!("DROP DATABASE {} WITH (FORCE)", self.name))
+            .await
+            .expect("Test database should have been dropped");
+    }
+}
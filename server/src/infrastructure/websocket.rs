@@ -0,0 +1,675 @@
+use std::{
+    collections::HashMap,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_graphql::{
+    http::{
+        ClientMessage, WebSocket as GraphQLWebSocket, WebSocketProtocols as Protocols, WsMessage,
+    },
+    Data,
+};
+use axum::{
+    extract::ws::{CloseFrame, Message},
+    http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap},
+};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+    select,
+    sync::{mpsc, watch},
+    time::{interval_at, sleep_until, Instant},
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{debug, instrument};
+
+use super::{config::SubscriptionConfig, cost::ClientId, schema::Schema};
+
+/// Picks the first protocol the client asked for that we speak.
+pub fn negotiate(headers: &HeaderMap) -> Option<Protocols> {
+    headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| protocol.trim().parse().ok())
+}
+
+/// Counts subscriptions of every client over all their sockets and event streams. Clients are told apart
+/// like for their budgets, every session is the same placeholder until we have auth.
+#[derive(Clone)]
+pub struct SubscriptionLimiter {
+    per_client: Arc<Mutex<HashMap<ClientId, usize>>>,
+    max_per_client: usize,
+}
+
+impl SubscriptionLimiter {
+    pub fn new(max_per_client: usize) -> Self {
+        Self {
+            per_client: Arc::default(),
+            max_per_client,
+        }
+    }
+
+    pub(super) fn acquire(&self, client: &ClientId) -> Option<SubscriptionPermit> {
+        let mut per_client = self.per_client.lock().expect("Lock should not be poisoned");
+        let count = per_client.entry(client.clone()).or_default();
+
+        if *count >= self.max_per_client {
+            return None;
+        }
+
+        *count += 1;
+
+        Some(SubscriptionPermit {
+            limiter: self.clone(),
+            client: client.clone(),
+        })
+    }
+}
+
+/// Held while a subscription runs, dropping it frees the slot.
+pub(super) struct SubscriptionPermit {
+    limiter: SubscriptionLimiter,
+    client: ClientId,
+}
+
+impl Drop for SubscriptionPermit {
+    fn drop(&mut self) {
+        let mut per_client = self
+            .limiter
+            .per_client
+            .lock()
+            .expect("Lock should not be poisoned");
+
+        if let Some(count) = per_client.get_mut(&self.client) {
+            *count -= 1;
+
+            if *count == 0 {
+                per_client.remove(&self.client);
+            }
+        }
+    }
+}
+
+enum Verdict {
+    Forward,
+    Reject(String),
+    Close(u16, String),
+}
+
+// Only what is needed to follow the state of the connection
+#[derive(Deserialize)]
+struct ServerMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    id: Option<String>,
+}
+
+/// What runs on a socket, the GraphQL protocol itself is left to async-graphql.
+struct Subscriptions {
+    protocol: Protocols,
+    client: ClientId,
+    limiter: SubscriptionLimiter,
+    max_per_socket: usize,
+    running: HashMap<String, SubscriptionPermit>,
+    // Clients may subscribe right after their init, before it got acknowledged
+    initialised: bool,
+    acknowledged: bool,
+    idle_since: Option<Instant>,
+}
+
+impl Subscriptions {
+    fn inspect_client(&mut self, message: &ClientMessage) -> Verdict {
+        let verdict = match message {
+            ClientMessage::ConnectionInit { .. } => {
+                self.initialised = true;
+                Verdict::Forward
+            }
+            // Left to the protocol, which refuses subscriptions before the handshake
+            ClientMessage::Start { .. } if !self.initialised => Verdict::Forward,
+            ClientMessage::Start { id, .. } if self.running.contains_key(id) => {
+                Verdict::Close(4409, format!("Subscriber for {id} already exists"))
+            }
+            ClientMessage::Start { id, .. } => {
+                if self.running.len() >= self.max_per_socket {
+                    self.reject(id, "Too many subscriptions on this connection")
+                } else if let Some(permit) = self.limiter.acquire(&self.client) {
+                    self.running.insert(id.clone(), permit);
+                    Verdict::Forward
+                } else {
+                    self.reject(id, "Too many subscriptions of this client")
+                }
+            }
+            ClientMessage::Stop { id } => {
+                self.running.remove(id);
+                Verdict::Forward
+            }
+            _ => Verdict::Forward,
+        };
+
+        self.update_idle();
+        verdict
+    }
+
+    fn inspect_server(&mut self, text: &str) {
+        let Ok(message) = serde_json::from_str::<ServerMessage>(text) else {
+            return;
+        };
+
+        match (message.kind.as_str(), message.id) {
+            ("connection_ack", _) => self.acknowledged = true,
+            ("complete", Some(id)) => {
+                self.running.remove(&id);
+            }
+            _ => {}
+        }
+
+        self.update_idle();
+    }
+
+    fn update_idle(&mut self) {
+        if !self.running.is_empty() {
+            self.idle_since = None;
+        } else if self.idle_since.is_none() {
+            self.idle_since = Some(Instant::now());
+        }
+    }
+
+    fn reject(&self, id: &str, message: &str) -> Verdict {
+        let error = json!({
+            "message": message,
+            "extensions": { "code": "TOO_MANY_SUBSCRIPTIONS" },
+        });
+
+        let payload = match self.protocol {
+            Protocols::GraphQLWS => json!({ "id": id, "type": "error", "payload": [error] }),
+            Protocols::SubscriptionsTransportWS => {
+                json!({ "id": id, "type": "error", "payload": error })
+            }
+        };
+
+        Verdict::Reject(payload.to_string())
+    }
+}
+
+/// Serves GraphQL over the socket until either side closes it or the server shuts down.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(socket, schema, data, limiter, config, shutdown))]
+pub async fn serve<S>(
+    socket: S,
+    schema: Schema,
+    protocol: Protocols,
+    client: ClientId,
+    data: Data,
+    limiter: SubscriptionLimiter,
+    config: SubscriptionConfig,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: Stream<Item = Result<Message, axum::Error>> + Sink<Message>,
+{
+    let keepalive_interval = Duration::from_secs(config.keepalive_secs);
+    let dead_after = Duration::from_secs(config.dead_after_secs);
+    let init_timeout = Duration::from_secs(config.init_timeout_secs);
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+
+    let (mut sink, mut source) = socket.split();
+
+    // Drained on every poll of the protocol, so it stays as short as the socket buffer
+    let (client_tx, client_rx) = mpsc::unbounded_channel();
+    let mut graphql = pin!(GraphQLWebSocket::from_message_stream(
+        schema,
+        UnboundedReceiverStream::new(client_rx),
+        protocol,
+    )
+    .connection_data(data));
+
+    let mut subscriptions = Subscriptions {
+        protocol,
+        client,
+        limiter,
+        max_per_socket: config.max_per_socket,
+        running: HashMap::new(),
+        initialised: false,
+        acknowledged: false,
+        idle_since: Some(Instant::now()),
+    };
+
+    let connected = Instant::now();
+    let mut last_seen = Instant::now();
+    let mut keepalive = interval_at(connected + keepalive_interval, keepalive_interval);
+
+    let close = loop {
+        let idle_deadline = subscriptions.idle_since.unwrap_or(connected) + idle_timeout;
+
+        select! {
+            message = source.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    _ => break None,
+                };
+                last_seen = Instant::now();
+
+                let message = match message {
+                    Message::Text(text) => ClientMessage::from_bytes(text.as_bytes()),
+                    Message::Binary(bytes) => ClientMessage::from_bytes(bytes),
+                    Message::Close(_) => break None,
+                    Message::Ping(_) | Message::Pong(_) => continue,
+                };
+
+                let verdict = match &message {
+                    Ok(message) => subscriptions.inspect_client(message),
+                    Err(_) => Verdict::Forward, // The protocol closes with the right code
+                };
+
+                match verdict {
+                    Verdict::Forward => {
+                        if client_tx.send(message).is_err() {
+                            break None;
+                        }
+                    }
+                    Verdict::Reject(text) => {
+                        if sink.send(Message::Text(text.into())).await.is_err() {
+                            break None;
+                        }
+                    }
+                    Verdict::Close(code, reason) => break Some((code, reason)),
+                }
+            }
+            message = graphql.next() => match message {
+                Some(WsMessage::Text(text)) => {
+                    subscriptions.inspect_server(&text);
+
+                    if sink.send(Message::Text(text.into())).await.is_err() {
+                        break None;
+                    }
+                }
+                Some(WsMessage::Close(code, reason)) => break Some((code, reason)),
+                None => break None,
+            },
+            _ = keepalive.tick() => {
+                if last_seen.elapsed() > dead_after {
+                    break None;
+                }
+
+                if sink.send(Message::Ping(Default::default())).await.is_err() {
+                    break None;
+                }
+
+                // Legacy clients time out without these
+                if protocol == Protocols::SubscriptionsTransportWS && subscriptions.acknowledged {
+                    let ka = json!({ "type": "ka" }).to_string();
+
+                    if sink.send(Message::Text(ka.into())).await.is_err() {
+                        break None;
+                    }
+                }
+            }
+            _ = sleep_until(connected + init_timeout), if !subscriptions.acknowledged => {
+                break Some((4408, "Connection initialisation timeout".to_string()));
+            }
+            _ = sleep_until(idle_deadline), if subscriptions.idle_since.is_some() => {
+                break Some((1000, "Idle".to_string()));
+            }
+            // Only ever changes to closing, or errors once the server is gone
+            _ = shutdown.changed() => {
+                break Some((1001, "Server is shutting down".to_string()));
+            }
+        }
+    };
+
+    debug!("Closing websocket: {close:?}");
+
+    if let Some((code, reason)) = close {
+        let frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+
+        let _ = sink.send(Message::Close(Some(frame))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use async_graphql::{http::WebSocketProtocols as Protocols, Data};
+    use axum::{
+        extract::ws::Message,
+        http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap, HeaderValue},
+    };
+    use futures::{
+        channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        Sink, Stream, StreamExt,
+    };
+    use serde_json::{json, Value};
+    use tokio::{sync::watch, task::JoinHandle};
+
+    use crate::{
+        domain::auth::Session,
+        infrastructure::{config::SubscriptionConfig, cost::ClientId, testing::TestApp},
+    };
+
+    use super::{negotiate, serve, SubscriptionLimiter};
+
+    fn offered(protocols: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_str(protocols).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn negotiates_in_client_order() {
+        assert_eq!(
+            Some(Protocols::GraphQLWS),
+            negotiate(&offered("graphql-transport-ws, graphql-ws"))
+        );
+        assert_eq!(
+            Some(Protocols::SubscriptionsTransportWS),
+            negotiate(&offered("mqtt, graphql-ws"))
+        );
+        assert_eq!(None, negotiate(&offered("mqtt")));
+        assert_eq!(None, negotiate(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn limits_subscriptions_per_client() {
+        let limiter = SubscriptionLimiter::new(2);
+        let client = ClientId::ApiKey("key".to_string());
+
+        let first = limiter.acquire(&client).unwrap();
+        let _second = limiter.acquire(&client).unwrap();
+        assert!(limiter.acquire(&client).is_none());
+        assert!(limiter.acquire(&ClientId::Anonymous).is_some());
+
+        drop(first);
+        assert!(limiter.acquire(&client).is_some());
+    }
+
+    /// The server end of an in-memory socket.
+    struct Socket {
+        incoming: UnboundedReceiver<Result<Message, axum::Error>>,
+        outgoing: UnboundedSender<Message>,
+    }
+
+    impl Stream for Socket {
+        type Item = Result<Message, axum::Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.incoming.poll_next_unpin(cx)
+        }
+    }
+
+    impl Sink<Message> for Socket {
+        type Error = axum::Error;
+
+        fn poll_ready(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.outgoing)
+                .poll_ready(cx)
+                .map_err(axum::Error::new)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+            Pin::new(&mut self.outgoing)
+                .start_send(item)
+                .map_err(axum::Error::new)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.outgoing)
+                .poll_flush(cx)
+                .map_err(axum::Error::new)
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Pin::new(&mut self.outgoing)
+                .poll_close(cx)
+                .map_err(axum::Error::new)
+        }
+    }
+
+    /// The client end, talking to `serve` on a socket of its own.
+    struct Client {
+        tx: UnboundedSender<Result<Message, axum::Error>>,
+        rx: UnboundedReceiver<Message>,
+        shutdown: watch::Sender<bool>,
+        served: JoinHandle<()>,
+        user_id: String,
+    }
+
+    impl Client {
+        async fn connect(protocol: Protocols, config: SubscriptionConfig) -> Self {
+            let app = TestApp::in_memory().await;
+            let (user, user_id) = app.user("Alice").await;
+
+            let (tx, incoming) = unbounded();
+            let (outgoing, rx) = unbounded();
+            let (shutdown, shutdown_rx) = watch::channel(false);
+
+            let mut data = Data::default();
+            data.insert(Session::new(user));
+
+            let served = tokio::spawn(serve(
+                Socket { incoming, outgoing },
+                app.schema.clone(),
+                protocol,
+                ClientId::Anonymous,
+                data,
+                SubscriptionLimiter::new(config.max_per_user),
+                config,
+                shutdown_rx,
+            ));
+
+            Self {
+                tx,
+                rx,
+                shutdown,
+                served,
+                user_id,
+            }
+        }
+
+        fn send(&self, message: Value) {
+            self.tx
+                .unbounded_send(Ok(Message::Text(message.to_string().into())))
+                .unwrap();
+        }
+
+        async fn receive(&mut self) -> Option<Message> {
+            self.rx.next().await
+        }
+
+        async fn receive_json(&mut self) -> Value {
+            match self.receive().await {
+                Some(Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+                other => panic!("Expected a text message, got {other:?}"),
+            }
+        }
+
+        async fn closed_with(&mut self) -> (u16, String) {
+            loop {
+                match self.receive().await {
+                    Some(Message::Close(Some(frame))) => {
+                        return (frame.code, frame.reason.to_string())
+                    }
+                    Some(_) => continue,
+                    None => panic!("Socket ended without a close frame"),
+                }
+            }
+        }
+
+        async fn initialise(&mut self) {
+            self.send(json!({ "type": "connection_init" }));
+            assert_eq!("connection_ack", self.receive_json().await["type"]);
+        }
+
+        fn subscribe(&self, id: &str) {
+            let query = format!(
+                r#"subscription {{ userFeed(userId: "{}") {{ node {{ content }} }} }}"#,
+                self.user_id
+            );
+
+            self.send(json!({ "id": id, "type": "subscribe", "payload": { "query": query } }));
+        }
+    }
+
+    fn config() -> SubscriptionConfig {
+        SubscriptionConfig {
+            keepalive_secs: 10,
+            dead_after_secs: 25,
+            init_timeout_secs: 5,
+            idle_timeout_secs: 60,
+            ..SubscriptionConfig::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_without_init_in_time() {
+        let mut client = Client::connect(Protocols::GraphQLWS, config()).await;
+
+        assert_eq!(
+            (4408, "Connection initialisation timeout".to_string()),
+            client.closed_with().await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pings_and_sends_ka_to_legacy_clients() {
+        let mut client = Client::connect(Protocols::SubscriptionsTransportWS, config()).await;
+        client.send(json!({ "type": "connection_init" }));
+        assert_eq!("connection_ack", client.receive_json().await["type"]);
+
+        assert!(matches!(client.receive().await, Some(Message::Ping(_))));
+        assert_eq!(json!({ "type": "ka" }), client.receive_json().await);
+
+        // Answering keeps the socket alive past the deadline
+        client
+            .tx
+            .unbounded_send(Ok(Message::Pong(Default::default())))
+            .unwrap();
+        assert!(matches!(client.receive().await, Some(Message::Ping(_))));
+        assert_eq!(json!({ "type": "ka" }), client.receive_json().await);
+        assert!(matches!(client.receive().await, Some(Message::Ping(_))));
+        assert_eq!(json!({ "type": "ka" }), client.receive_json().await);
+
+        // Silent for longer than dead_after_secs, so it goes without a close frame
+        assert_eq!(None, client.receive().await);
+        client.served.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_pings_current_clients() {
+        let mut client = Client::connect(Protocols::GraphQLWS, config()).await;
+        client.initialise().await;
+
+        assert!(matches!(client.receive().await, Some(Message::Ping(_))));
+        assert!(matches!(client.receive().await, Some(Message::Ping(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reaps_idle_sockets() {
+        let config = SubscriptionConfig {
+            idle_timeout_secs: 15,
+            ..config()
+        };
+        let mut client = Client::connect(Protocols::GraphQLWS, config).await;
+        client.initialise().await;
+
+        client.subscribe("1");
+        client.send(json!({ "id": "1", "type": "complete" }));
+
+        assert_eq!((1000, "Idle".to_string()), client.closed_with().await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn running_subscriptions_keep_sockets_open() {
+        let config = SubscriptionConfig {
+            idle_timeout_secs: 15,
+            ..config()
+        };
+        let mut client = Client::connect(Protocols::GraphQLWS, config).await;
+        client.initialise().await;
+        client.subscribe("1");
+
+        for _ in 0..3 {
+            assert!(matches!(client.receive().await, Some(Message::Ping(_))));
+            client
+                .tx
+                .unbounded_send(Ok(Message::Pong(Default::default())))
+                .unwrap();
+        }
+
+        client.shutdown.send(true).unwrap();
+        assert_eq!(
+            (1001, "Server is shutting down".to_string()),
+            client.closed_with().await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_on_duplicate_subscription_ids() {
+        let mut client = Client::connect(Protocols::GraphQLWS, config()).await;
+        client.initialise().await;
+
+        client.subscribe("1");
+        client.subscribe("1");
+
+        assert_eq!(
+            (4409, "Subscriber for 1 already exists".to_string()),
+            client.closed_with().await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejects_subscriptions_over_the_client_limit() {
+        let config = SubscriptionConfig {
+            max_per_user: 1,
+            ..config()
+        };
+        let mut client = Client::connect(Protocols::GraphQLWS, config).await;
+        client.initialise().await;
+
+        client.subscribe("1");
+        client.subscribe("2");
+
+        let rejected = client.receive_json().await;
+        assert_eq!(json!("2"), rejected["id"]);
+        assert_eq!(
+            json!("TOO_MANY_SUBSCRIPTIONS"),
+            rejected["payload"][0]["extensions"]["code"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_a_close_frame_on_shutdown() {
+        let mut client = Client::connect(Protocols::GraphQLWS, config()).await;
+        client.initialise().await;
+
+        client.shutdown.send(true).unwrap();
+
+        assert_eq!(
+            (1001, "Server is shutting down".to_string()),
+            client.closed_with().await
+        );
+        client.served.await.unwrap();
+    }
+}
@@ -2,43 +2,263 @@ mod domain;
 mod infrastructure;
 
 use axum::serve;
-use infrastructure::{notification_center::NotificationCenter, urls::Urls};
+use domain::{app_user::UserStore, db_id::HasDbId};
+use infrastructure::{
+    app_env::AppEnv,
+    cli::{Cli, Command, USAGE},
+    config::Config,
+    db::{Repo, SeedSizes},
+    notification_center::NotificationCenter,
+    persisted_queries::PersistedQueries,
+    scheduler::PostScheduler,
+    storage::Storage,
+};
+use std::{env, fs, net::SocketAddr, process, sync::Arc, time::Duration};
 use tokio::net::TcpListener;
+use tracing::info;
+use uuid::Uuid;
 
-use crate::infrastructure::{app_state::AppState, db, logging, router, schema, shutdown};
+use crate::infrastructure::{app_state::AppState, db, logging, router, schema, shutdown::Shutdown};
 
 #[tokio::main]
 async fn main() {
-    schema::save_schema("./schema.graphql").expect("Should have written schema to file");
+    let cli = Cli::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}\n\n{USAGE}");
+        process::exit(2);
+    });
+
+    // Needs neither config nor db, and stdout has to stay clean for redirecting
+    if let Command::ExportSchema { out } = &cli.command {
+        match out {
+            Some(path) => fs::write(path, schema::sdl()).expect("Should have written schema"),
+            None => print!("{}", schema::sdl()),
+        }
+        return;
+    }
+
     let _ = dotenvy::dotenv(); // If .env is not found, ENV might be configured already
-    let addr = dotenvy::var("HOSTING_ADDRESS").expect("Need to know where to bind app");
-    let urls = Urls::new().expect("Env should contain all necessary urls");
+
+    let config = Config::load(
+        cli.config_file.as_deref(),
+        |name| env::var(name).ok(),
+        &cli.overrides,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(1);
+    });
+
+    if cli.command == Command::CheckConfig {
+        check_config(&config).await;
+        return;
+    }
 
     let _guard = logging::init().expect("Logging should build"); // Guard flushes when main/server stops
 
-    let repo = db::initiate_repo()
+    let app_env = config.app_env;
+
+    let repo = db::initiate_repo(&config.pg)
         .await
         .expect("Repo should have been created");
-    db::migrate(&repo).await.expect("Migrations should succeed");
 
-    let mut notification_center = NotificationCenter::new(repo.clone());
+    match cli.command {
+        Command::Serve => {
+            prepare(&repo, app_env).await;
+
+            if app_env.allows_seeding() {
+                seed(&repo, SeedSizes::default()).await;
+            }
+
+            serve_app(repo, config).await;
+        }
+        Command::Migrate { target } => {
+            let applied = db::migrate_to(&repo, target)
+                .await
+                .expect("Migrations should succeed");
+
+            if applied.is_empty() {
+                println!("Nothing to apply");
+            }
+            for migration in applied {
+                println!("Applied {migration}");
+            }
+        }
+        Command::MigrationStatus => {
+            let status = db::migration_status(&repo)
+                .await
+                .expect("Migration status should have been read");
+
+            for migration in status {
+                let state = match (migration.applied_on, migration.known) {
+                    (Some(applied_on), true) => format!("applied on {applied_on}"),
+                    (Some(applied_on), false) => {
+                        format!("applied on {applied_on}, not in this build")
+                    }
+                    (None, _) => "pending".to_string(),
+                };
+                println!("V{} {}: {state}", migration.version, migration.name);
+            }
+        }
+        Command::Seed(sizes) => {
+            if !app_env.allows_seeding() {
+                eprintln!("Production is never seeded, APP_ENV needs to be development");
+                process::exit(1);
+            }
+
+            prepare(&repo, app_env).await;
+            let seeded = db::seed(&repo, sizes.unwrap_or_default())
+                .await
+                .expect("Seeding should succeed");
+
+            match (seeded, sizes) {
+                (true, _) => println!("Seeded the db"),
+                (false, None) => println!("The db was seeded before"),
+                // Seeds only ever fill an empty db, so the sizes would go unnoticed
+                (false, Some(_)) => {
+                    eprintln!("The db was seeded before, sizes only apply to a fresh db");
+                    process::exit(1);
+                }
+            }
+        }
+        Command::CreateUser {
+            first_name,
+            last_name,
+            role,
+        } => {
+            prepare(&repo, app_env).await;
+
+            let user = repo
+                .save_user(&first_name, &last_name, role)
+                .await
+                .expect("User should have been saved");
+
+            println!("Created user {} as {role:?}", *user.db_id());
+        }
+        Command::ResetPassword { user_id } => {
+            prepare(&repo, app_env).await;
+
+            let password = Uuid::new_v4().simple().to_string();
+            let reset = repo
+                .set_password(&user_id, &password)
+                .await
+                .expect("Password should have been set");
+
+            if !reset {
+                eprintln!("There is no user {}", *user_id);
+                process::exit(1);
+            }
+
+            println!("New password of user {}: {password}", *user_id);
+        }
+        Command::ExportSchema { .. } | Command::CheckConfig => unreachable!(),
+    }
+}
+
+/// Development migrates on the fly. Production migrates as a step of its own before
+/// deploying, so a server never changes the schema under another one still running.
+async fn prepare(repo: &Repo, app_env: AppEnv) {
+    if app_env == AppEnv::Development {
+        db::migrate(repo).await.expect("Migrations should succeed");
+        return;
+    }
+
+    let pending = db::migration_status(repo)
+        .await
+        .expect("Migration status should have been read")
+        .into_iter()
+        .filter(|migration| migration.applied_on.is_none())
+        .count();
+
+    if pending > 0 {
+        eprintln!("{pending} migrations are pending, run `server migrate` first");
+        process::exit(1);
+    }
+}
+
+async fn seed(repo: &Repo, sizes: SeedSizes) {
+    let seeded = db::seed(repo, sizes).await.expect("Seeding should succeed");
+
+    if seeded {
+        info!(?sizes, "Seeded the db");
+    } else {
+        info!("The db was seeded before");
+    }
+}
+
+/// Prints the config, then checks what only shows once it gets used. Exits with 1 on problems.
+async fn check_config(config: &Config) {
+    println!("{config}");
+
+    let mut errors = Vec::new();
+
+    match db::initiate_repo(&config.pg).await {
+        Ok(repo) => {
+            if let Err(e) = PersistedQueries::new(&config.persisted_queries, repo.clone()) {
+                errors.push(e.to_string());
+            }
+            if let Err(e) = db::migration_status(&repo).await {
+                errors.push(format!("Db is unreachable: {e}"));
+            }
+        }
+        Err(e) => errors.push(e.to_string()),
+    }
+
+    if errors.is_empty() {
+        println!("Config is valid");
+        return;
+    }
+
+    for error in errors {
+        eprintln!("{error}");
+    }
+    process::exit(1);
+}
+
+async fn serve_app(repo: Repo, config: Config) {
+    let config = Arc::new(config);
+    let addr = config.hosting_address;
+
+    let storage: Storage = Arc::new(repo.clone());
+
+    let mut notification_center =
+        NotificationCenter::new(Arc::new(repo.clone()), config.notifications.clone());
     notification_center
         .start_daemon()
         .await
         .expect("NotificationCenter should have started");
 
-    let app_state = AppState::new(notification_center, repo, urls);
+    PostScheduler::new(storage.clone(), &config.scheduler).start_daemon();
+
+    let persisted_queries = PersistedQueries::new(&config.persisted_queries, repo.clone())
+        .expect("Persisted queries should have been configured");
+
+    let shutdown = Shutdown::default();
+    let grace = Duration::from_secs(config.http.shutdown_grace_secs);
+
+    let app_state = AppState::new(
+        config,
+        notification_center,
+        repo,
+        storage,
+        persisted_queries,
+        shutdown.subscribe(),
+    );
     let router = router::new(app_state);
 
-    let listener = TcpListener::bind(&addr)
+    let listener = TcpListener::bind(addr)
         .await
         .expect("Should have bound to port");
 
-    tracing::info!("Listening on {}", &addr);
-    tracing::info!("Visit GraphiQL: http://{}/graphql", &addr);
+    info!("Listening on {}", &addr);
+    info!("Visit GraphiQL: http://{}/graphql", &addr);
 
-    serve(listener, router)
-        .with_graceful_shutdown(shutdown::signal())
-        .await
-        .expect("Server should start");
+    serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown.clone().signal())
+    .await
+    .expect("Server should start");
+
+    shutdown.closed(grace).await;
 }